@@ -0,0 +1,223 @@
+use std::env;
+
+use anyhow::Context;
+
+use common::env::missing_env_vars;
+
+use lambda_runtime::Error;
+
+use serde::Deserialize;
+
+use crate::discovery::designated_resolver_record;
+
+pub const MOBILE_CONFIG_FILENAME: &str = "dns.mobileconfig";
+const MOBILE_CONFIG_CONTENT_TYPE: &str = "application/x-apple-aspen-config";
+
+// Browser policies turning on DoH with the resolver, for Firefox's
+// `policies.json` and Chrome and Edge's managed policy directories. Android
+// and systemd-resolved only speak DNS over TLS, so they aren't covered.
+const BROWSER_POLICY_FILENAMES: &[&str] = &[
+    "firefox-policies.json",
+    "chromium-policy.json"
+];
+const BROWSER_POLICY_CONTENT_TYPE: &str = "application/json";
+
+// The resolver's discovery record, for operators to copy into their zone
+const DESIGNATED_RESOLVER_FILENAME: &str = "designated-resolver.zone";
+const DESIGNATED_RESOLVER_CONTENT_TYPE: &str = "text/dns";
+
+const REQUIRED_ENV_VARS: &[&str] = &[
+    "APPLE_DEVICE_PROFILE_BUCKET_NAME",
+    "RESOLVER_URL"
+];
+
+// A file published to the profile bucket, rendered from a template that is
+// either a path in the package or an `s3://<bucket>/<key>` URI. Keys may
+// contain a `{version}` placeholder to publish versions side by side, with
+// the optional latest key always holding the most recent one.
+#[derive(Deserialize, Debug)]
+pub struct ProfileArtifact {
+    pub template: String,
+    pub key: String,
+    #[serde(default)]
+    pub latest_key: Option<String>,
+    pub content_type: String
+}
+
+impl ProfileArtifact {
+    pub fn keys(&self, version: &str) -> Vec<String> {
+        let mut keys = vec![self.key.replace("{version}", version)];
+
+        if let Some(latest_key) = &self.latest_key {
+            keys.push(latest_key.replace("{version}", version));
+        }
+
+        keys
+    }
+}
+
+// Every publisher setting, read and validated once at startup
+#[derive(Debug)]
+pub struct Config {
+    pub bucket_name: String,
+    pub resolver_url: String,
+    pub designated_resolver_record: String,
+    pub artifacts: Vec<ProfileArtifact>
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Error> {
+        let missing_env_vars = missing_env_vars(REQUIRED_ENV_VARS);
+        if !missing_env_vars.is_empty() {
+            return Err(format!("Missing required env vars: {}", missing_env_vars.join(", ")).into());
+        }
+
+        let resolver_url = env::var("RESOLVER_URL")?;
+
+        Ok(Self {
+            bucket_name: env::var("APPLE_DEVICE_PROFILE_BUCKET_NAME")?,
+            designated_resolver_record: designated_resolver_record(&resolver_url)?,
+            resolver_url,
+            artifacts: profile_artifacts()?
+        })
+    }
+}
+
+// The artifacts to publish, configured as a JSON array in PROFILE_ARTIFACTS:
+//
+//     [{"template": "dns.mobileconfig", "key": "dns.mobileconfig", "content_type": "application/x-apple-aspen-config"}, ...]
+//
+// Defaults to the DoH mobileconfig, whose template may be overridden with
+// TEMPLATE_S3_URI and keys with PROFILE_S3_KEY and PROFILE_S3_LATEST_KEY,
+// followed by the bundled browser policies and `designated-resolver.zone`.
+fn profile_artifacts() -> Result<Vec<ProfileArtifact>, Error> {
+    if let Ok(artifacts) = env::var("PROFILE_ARTIFACTS") {
+        return Ok(serde_json::from_str(&artifacts).with_context(|| "Invalid PROFILE_ARTIFACTS")?);
+    }
+
+    let mobile_config = ProfileArtifact {
+        template: env::var("TEMPLATE_S3_URI").unwrap_or(MOBILE_CONFIG_FILENAME.to_string()),
+        key: env::var("PROFILE_S3_KEY").unwrap_or(MOBILE_CONFIG_FILENAME.to_string()),
+        latest_key: env::var("PROFILE_S3_LATEST_KEY").ok(),
+        content_type: MOBILE_CONFIG_CONTENT_TYPE.to_string()
+    };
+
+    let browser_policies = BROWSER_POLICY_FILENAMES.iter().map(|filename| ProfileArtifact {
+        template: filename.to_string(),
+        key: filename.to_string(),
+        latest_key: None,
+        content_type: BROWSER_POLICY_CONTENT_TYPE.to_string()
+    });
+
+    let designated_resolver = ProfileArtifact {
+        template: DESIGNATED_RESOLVER_FILENAME.to_string(),
+        key: DESIGNATED_RESOLVER_FILENAME.to_string(),
+        latest_key: None,
+        content_type: DESIGNATED_RESOLVER_CONTENT_TYPE.to_string()
+    };
+
+    Ok(std::iter::once(mobile_config).chain(browser_policies).chain(std::iter::once(designated_resolver)).collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const ENV_VARS: &[&str] = &[
+        "APPLE_DEVICE_PROFILE_BUCKET_NAME",
+        "RESOLVER_URL",
+        "PROFILE_ARTIFACTS",
+        "TEMPLATE_S3_URI",
+        "PROFILE_S3_KEY",
+        "PROFILE_S3_LATEST_KEY"
+    ];
+
+    const REQUIRED: &[(&str, &str)] = &[
+        ("APPLE_DEVICE_PROFILE_BUCKET_NAME", "profiles"),
+        ("RESOLVER_URL", "https://dns.example/dns-query")
+    ];
+
+    // The environment is shared by every test thread
+    pub(crate) static ENV: Mutex<()> = Mutex::new(());
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+
+        for name in ENV_VARS {
+            env::remove_var(name);
+        }
+
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+
+        Config::from_env()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(REQUIRED).unwrap();
+
+        assert_eq!(config.bucket_name, "profiles");
+        assert_eq!(config.resolver_url, "https://dns.example/dns-query");
+        assert!(config.designated_resolver_record.contains("dns.example."));
+
+        let keys: Vec<&str> = config.artifacts.iter().map(|artifact| artifact.key.as_str()).collect();
+
+        assert_eq!(keys, vec![MOBILE_CONFIG_FILENAME, "firefox-policies.json", "chromium-policy.json", DESIGNATED_RESOLVER_FILENAME]);
+        assert_eq!(config.artifacts[0].template, MOBILE_CONFIG_FILENAME);
+        assert_eq!(config.artifacts[0].content_type, MOBILE_CONFIG_CONTENT_TYPE);
+        assert!(config.artifacts[0].latest_key.is_none());
+        assert_eq!(config.artifacts[3].template, DESIGNATED_RESOLVER_FILENAME);
+        assert_eq!(config.artifacts[3].content_type, DESIGNATED_RESOLVER_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn overrides_the_mobile_config() {
+        let config = config(&[
+            REQUIRED[0],
+            REQUIRED[1],
+            ("TEMPLATE_S3_URI", "s3://templates/dns.mobileconfig"),
+            ("PROFILE_S3_KEY", "profiles/{version}/dns.mobileconfig"),
+            ("PROFILE_S3_LATEST_KEY", "profiles/latest/dns.mobileconfig")
+        ]).unwrap();
+
+        let mobile_config = &config.artifacts[0];
+
+        assert_eq!(mobile_config.template, "s3://templates/dns.mobileconfig");
+        assert_eq!(mobile_config.keys("2"), vec!["profiles/2/dns.mobileconfig", "profiles/latest/dns.mobileconfig"]);
+        assert_eq!(config.artifacts.len(), 2 + BROWSER_POLICY_FILENAMES.len());
+    }
+
+    #[test]
+    fn parses_artifacts() {
+        let config = config(&[
+            REQUIRED[0],
+            REQUIRED[1],
+            ("PROFILE_ARTIFACTS", r#"[{"template": "designated-resolver.zone", "key": "dns.zone", "content_type": "text/dns"}]"#)
+        ]).unwrap();
+
+        assert_eq!(config.artifacts.len(), 1);
+        assert_eq!(config.artifacts[0].key, "dns.zone");
+        assert!(config.artifacts[0].latest_key.is_none());
+    }
+
+    #[test]
+    fn requires_the_bucket_and_resolver() {
+        let err = config(&[]).unwrap_err().to_string();
+
+        assert!(err.contains("APPLE_DEVICE_PROFILE_BUCKET_NAME"));
+        assert!(err.contains("RESOLVER_URL"));
+
+        assert!(config(&REQUIRED[..1]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(config(&[REQUIRED[0], ("RESOLVER_URL", "http://dns.example/dns-query")]).is_err());
+        assert!(config(&[REQUIRED[0], ("RESOLVER_URL", "dns.example")]).is_err());
+        assert!(config(&[REQUIRED[0], REQUIRED[1], ("PROFILE_ARTIFACTS", r#"[{"key": "dns.zone"}]"#)]).is_err());
+    }
+}
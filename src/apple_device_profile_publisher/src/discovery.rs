@@ -0,0 +1,66 @@
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use reqwest::Url;
+
+const DESIGNATED_RESOLVER_TTL: u32 = 300;
+
+// The SVCB record advertising the resolver for Discovery of Designated
+// Resolvers (RFC 9462), in zone file presentation format, for operators to
+// publish at `_dns.resolver.arpa` or under their own resolver's name. The
+// URI template's `{?dns}` variable (RFC 9461) is appended to the path.
+pub fn designated_resolver_record(resolver_url: &str) -> Result<String> {
+    let url = Url::parse(resolver_url).with_context(|| format!("Invalid resolver URL '{}'", resolver_url))?;
+
+    if url.scheme() != "https" {
+        return Err(anyhow!("Resolver URL '{}' must use https", resolver_url));
+    }
+
+    let host = url.host_str().with_context(|| format!("Resolver URL '{}' has no host", resolver_url))?;
+
+    // Parameters must be in ascending key order
+    let mut svc_params = vec!["alpn=h2".to_string()];
+
+    if let Some(port) = url.port() {
+        svc_params.push(format!("port={}", port));
+    }
+
+    svc_params.push(format!("dohpath=\"{}{{?dns}}\"", url.path()));
+
+    Ok(format!(
+        "_dns.resolver.arpa. {} IN SVCB 1 {}. {}",
+        DESIGNATED_RESOLVER_TTL,
+        host.trim_end_matches('.'),
+        svc_params.join(" ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_records() {
+        assert_eq!(
+            designated_resolver_record("https://dns.example/dns-query").unwrap(),
+            "_dns.resolver.arpa. 300 IN SVCB 1 dns.example. alpn=h2 dohpath=\"/dns-query{?dns}\""
+        );
+    }
+
+    #[test]
+    fn includes_non_default_ports() {
+        assert_eq!(
+            designated_resolver_record("https://dns.example:8443/resolve").unwrap(),
+            "_dns.resolver.arpa. 300 IN SVCB 1 dns.example. alpn=h2 port=8443 dohpath=\"/resolve{?dns}\""
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        assert!(designated_resolver_record("http://dns.example/dns-query").is_err());
+        assert!(designated_resolver_record("dns.example/dns-query").is_err());
+    }
+}
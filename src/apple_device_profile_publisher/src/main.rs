@@ -1,6 +1,9 @@
+mod config;
+mod discovery;
+mod store;
+
 use std::{
     collections::HashMap,
-    env,
     fs
 };
 
@@ -9,11 +12,6 @@ use anyhow::{
     Result
 };
 
-use aws_sdk_s3::{
-    self,
-    types::ByteStream
-};
-
 use lambda_runtime::{
     LambdaEvent,
     Error,
@@ -22,6 +20,18 @@ use lambda_runtime::{
 
 use serde::{Deserialize, Serialize};
 
+use config::{
+    Config,
+    ProfileArtifact,
+    MOBILE_CONFIG_FILENAME
+};
+
+use store::ObjectStore;
+
+// Every key published by the last Create or Update, kept in the bucket so an
+// Update can delete old versions and removed artifacts, and Delete them all
+const PUBLISHED_KEYS_KEY: &str = "published-keys.json";
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 struct DeviceProfilePublisherParameters {
@@ -48,7 +58,10 @@ struct CloudFormationRequest {
     stack_id: String,
     #[allow(dead_code)]
     physical_resource_id: Option<String>,
-    resource_properties: DeviceProfilePublisherParameters
+    resource_properties: DeviceProfilePublisherParameters,
+    // Only sent with Update requests
+    #[serde(default)]
+    old_resource_properties: Option<DeviceProfilePublisherParameters>
 }
 
 #[derive(Serialize, Debug)]
@@ -74,10 +87,15 @@ struct CloudFormationResponse {
     data: Option<HashMap<String, String>>,
 }
 
-const MOBILE_CONFIG_FILENAME: &str = "dns.mobileconfig";
-
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    // Failing the init phase would leave CloudFormation waiting on a response
+    // until it times out, so invalid settings are only logged here and fail
+    // each request instead
+    if let Err(err) = Config::from_env() {
+        println!("Invalid configuration, every request will fail: {}", err);
+    }
+
     lambda_runtime::run(service_fn(handler)).await?;
 
     Ok(())
@@ -100,57 +118,126 @@ async fn handler(event: LambdaEvent<CloudFormationRequest>) -> Result<(), Error>
 async fn handle_request(request: &CloudFormationRequest) -> Result<(), Error> {
     println!("Input event: {:#?}", request);
 
+    let config = Config::from_env().map_err(|err| format!("Invalid configuration: {}", err))?;
+
     let aws_config = aws_config::load_from_env().await;
     let s3_client = aws_sdk_s3::Client::new(&aws_config);
+
+    publish(&s3_client, &config, request).await
+}
+
+async fn publish(store: &dyn ObjectStore, config: &Config, request: &CloudFormationRequest) -> Result<(), Error> {
     let version = &request.resource_properties.version;
+    let published = published_keys(store, config).await;
+
+    if let RequestType::Delete = request.request_type {
+        let mut keys = published.unwrap_or_default();
+        keys.extend(config.artifacts.iter().flat_map(|artifact| artifact.keys(version)));
+        keys.push(PUBLISHED_KEYS_KEY.to_string());
 
-    match request.request_type {
-        RequestType::Create => put_mobile_config(&s3_client, &version).await,
-        RequestType::Update => put_mobile_config(&s3_client, &version).await,
-        RequestType::Delete => delete_mobile_config(&s3_client).await
+        return delete_keys(store, config, keys).await;
     }
+
+    // Every artifact must be published, so one with a missing template fails
+    // the request rather than being skipped
+    for artifact in &config.artifacts {
+        put_artifact(store, config, artifact, version)
+            .await
+            .map_err(|err| format!("Failed to handle {}: {}", artifact.key, err))?;
+    }
+
+    let keys: Vec<String> = config.artifacts.iter().flat_map(|artifact| artifact.keys(version)).collect();
+
+    // Stacks published before the list of keys was kept only know the old
+    // version, so removed artifacts are left behind
+    let old_keys = published.unwrap_or_else(|| match &request.old_resource_properties {
+        Some(old) => config.artifacts.iter().flat_map(|artifact| artifact.keys(&old.version)).collect(),
+        None => Vec::new()
+    });
+
+    store
+        .put(&config.bucket_name, PUBLISHED_KEYS_KEY, "application/json", serde_json::to_vec(&keys)?)
+        .await
+        .map_err(|err| format!("Failed to record the published keys: {}", err))?;
+
+    delete_keys(store, config, old_keys.into_iter().filter(|key| !keys.contains(key)).collect()).await
 }
 
-async fn put_mobile_config(s3_client: &aws_sdk_s3::Client, version: &str) -> Result<(), Error> {
-    println!("Uploading {} file...", MOBILE_CONFIG_FILENAME);
+// The keys listed by the last Create or Update, if any
+async fn published_keys(store: &dyn ObjectStore, config: &Config) -> Option<Vec<String>> {
+    let contents = match store.get(&config.bucket_name, PUBLISHED_KEYS_KEY).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            println!("No published keys found, only the current artifacts' keys will be cleaned up: {}", err);
+            return None;
+        }
+    };
+
+    match serde_json::from_slice(&contents) {
+        Ok(keys) => Some(keys),
+        Err(err) => {
+            println!("Ignoring invalid published keys: {}", err);
+            None
+        }
+    }
+}
 
-    let bucket_name = env::var("APPLE_DEVICE_PROFILE_BUCKET_NAME").with_context(|| "APPLE_DEVICE_PROFILE_BUCKET_NAME env var not set")?;
-    let resolver_url = env::var("RESOLVER_URL").with_context(|| "RESOLVER_URL env var not set")?;
+async fn put_artifact(store: &dyn ObjectStore, config: &Config, artifact: &ProfileArtifact, version: &str) -> Result<(), Error> {
+    let contents = load_template(store, &artifact.template).await?
+        .replace("##RESOLVER_URL##", &config.resolver_url)
+        .replace("##DESIGNATED_RESOLVER_RECORD##", &config.designated_resolver_record)
+        .replace("##VERSION##", version);
 
-    let device_profile_contents = fs::read_to_string(MOBILE_CONFIG_FILENAME)
-        .with_context(|| format!("Missing Apple device profile template file '{}'", MOBILE_CONFIG_FILENAME))?
-        .replace("##RESOLVER_URL##", &resolver_url)
-        .replace("##VERSION##", &version.to_string());
+    for key in artifact.keys(version) {
+        println!("Uploading {} file...", key);
 
-    s3_client
-        .put_object()
-        .bucket(bucket_name)
-        .key(MOBILE_CONFIG_FILENAME)
-        .content_type("application/x-apple-aspen-config")
-        .cache_control("public, must-revalidate, proxy-revalidate, max-age=0")
-        .body(ByteStream::from(device_profile_contents.as_bytes().to_vec()))
-        .send()
-        .await?;
+        store.put(&config.bucket_name, &key, &artifact.content_type, contents.as_bytes().to_vec()).await?;
 
-    println!("Uploaded {} file", MOBILE_CONFIG_FILENAME);
+        println!("Uploaded {} file", key);
+    }
 
     Ok(())
 }
 
-async fn delete_mobile_config(s3_client: &aws_sdk_s3::Client) -> Result<(), Error> {
-    println!("Deleting {} file...", MOBILE_CONFIG_FILENAME);
+// Templates given as `s3://<bucket>/<key>` are downloaded, so the layout can
+// change without a code deploy. Anything else is a file bundled in the package.
+async fn load_template(store: &dyn ObjectStore, template: &str) -> Result<String, Error> {
+    let location = match template.strip_prefix("s3://") {
+        Some(location) => location,
+        None => return Ok(fs::read_to_string(template)
+            .with_context(|| format!("Missing profile template file '{}'", template))?)
+    };
 
-    let bucket_name = env::var("APPLE_DEVICE_PROFILE_BUCKET_NAME")?;
-    
-    s3_client
-        .delete_object()
-        .bucket(bucket_name)
-        .key("dns.mobileconfig")
-        .send()
-        .await?;
+    let (bucket, key) = location
+        .split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .with_context(|| format!("Invalid template URI '{}', expected 's3://<bucket>/<key>'", template))?;
+
+    println!("Downloading template from {}...", template);
+
+    let contents = store
+        .get(bucket, key)
+        .await
+        .map_err(|err| format!("Failed to download template from {}: {}", template, err))?;
+
+    Ok(String::from_utf8(contents).with_context(|| format!("Template {} is not valid UTF-8", template))?)
+}
+
+async fn delete_keys(store: &dyn ObjectStore, config: &Config, mut keys: Vec<String>) -> Result<(), Error> {
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        println!("Deleting {} file...", key);
+
+        store
+            .delete(&config.bucket_name, &key)
+            .await
+            .map_err(|err| format!("Failed to delete {}: {}", key, err))?;
+
+        println!("Deleted {} file", key);
+    }
 
-    println!("Deleted {} file", MOBILE_CONFIG_FILENAME);
-    
     Ok(())
 }
 
@@ -185,15 +272,308 @@ async fn send_cloudformation_failure(request: &CloudFormationRequest, physical_r
 }
 
 async fn send_cloudformation_response(response_url: &str, response: &CloudFormationResponse) {
+    // A response that never arrives leaves the stack hanging until it times
+    // out, so nothing here may panic
+    let body = match serde_json::to_string(response) {
+        Ok(body) => body,
+        Err(err) => {
+            println!("Failed to serialize CloudFormation response, reporting failure instead: {}", err);
+
+            serde_json::json!({
+                "Status": "FAILED",
+                "Reason": format!("Failed to serialize response: {}", err),
+                "PhysicalResourceId": response.physical_resource_id,
+                "StackId": response.stack_id,
+                "RequestId": response.request_id,
+                "LogicalResourceId": response.logical_resource_id
+            }).to_string()
+        }
+    };
+
+    println!("Sending CloudFormation response: {}", body);
+
     let client = reqwest::Client::new();
 
-    println!("Sending CloudFormation response: {}", serde_json::to_string(response).unwrap());
-    
-    client.put(response_url)
-        .json(&response)
+    let result = client.put(response_url)
+        .header("Content-Type", "application/json")
+        .body(body)
         .send()
         .await
-        .expect("Failed to send CloudFormation response")
-        .error_for_status()
-        .expect("Failed to send CloudFormation response");
-}
\ No newline at end of file
+        .and_then(|response| response.error_for_status());
+
+    if let Err(err) = result {
+        println!("Failed to send CloudFormation response: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::Mutex
+    };
+
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt
+        },
+        net::TcpListener
+    };
+
+    use store::StoreFuture;
+
+    use super::*;
+
+    // Objects by `<bucket>/<key>`, with their content type
+    #[derive(Default)]
+    struct FakeStore {
+        objects: Mutex<HashMap<String, (String, Vec<u8>)>>
+    }
+
+    fn path(bucket: &str, key: &str) -> String {
+        format!("{}/{}", bucket, key)
+    }
+
+    impl FakeStore {
+        fn with_templates(templates: &[(&str, &str)]) -> Self {
+            let store = Self::default();
+
+            for (key, contents) in templates {
+                store.objects.lock().unwrap().insert(path("templates", key), (String::new(), contents.as_bytes().to_vec()));
+            }
+
+            store
+        }
+
+        fn object(&self, key: &str) -> Option<(String, String)> {
+            self.objects
+                .lock()
+                .unwrap()
+                .get(&path("profiles", key))
+                .map(|(content_type, body)| (content_type.clone(), String::from_utf8(body.clone()).unwrap()))
+        }
+
+        fn keys(&self) -> Vec<String> {
+            let mut keys: Vec<String> = self.objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|path| path.strip_prefix("profiles/"))
+                .map(str::to_string)
+                .collect();
+
+            keys.sort();
+            keys
+        }
+    }
+
+    impl ObjectStore for FakeStore {
+        fn get(&self, bucket: &str, key: &str) -> StoreFuture<'_, Vec<u8>> {
+            let object = self.objects.lock().unwrap().get(&path(bucket, key)).map(|(_, body)| body.clone());
+
+            Box::pin(async move { object.ok_or_else(|| "NoSuchKey".into()) })
+        }
+
+        fn put(&self, bucket: &str, key: &str, content_type: &str, body: Vec<u8>) -> StoreFuture<'_, ()> {
+            self.objects.lock().unwrap().insert(path(bucket, key), (content_type.to_string(), body));
+
+            Box::pin(async { Ok(()) })
+        }
+
+        fn delete(&self, bucket: &str, key: &str) -> StoreFuture<'_, ()> {
+            self.objects.lock().unwrap().remove(&path(bucket, key));
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    const TEMPLATES: &[(&str, &str)] = &[
+        ("dns.mobileconfig", "<string>##RESOLVER_URL##</string><string>##VERSION##</string>"),
+        ("firefox-policies.json", r###"{"url": "##RESOLVER_URL##"}"###)
+    ];
+
+    fn artifact(template: &str, key: &str, latest_key: Option<&str>, content_type: &str) -> ProfileArtifact {
+        ProfileArtifact {
+            template: format!("s3://templates/{}", template),
+            key: key.to_string(),
+            latest_key: latest_key.map(str::to_string),
+            content_type: content_type.to_string()
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            bucket_name: "profiles".to_string(),
+            resolver_url: "https://dns.example/dns-query".to_string(),
+            designated_resolver_record: "_dns.dns.example. 300 IN SVCB 1 dns.example. alpn=h2".to_string(),
+            artifacts: vec![
+                artifact("dns.mobileconfig", "{version}/dns.mobileconfig", Some("dns.mobileconfig"), "application/x-apple-aspen-config"),
+                artifact("firefox-policies.json", "firefox-policies.json", None, "application/json")
+            ]
+        }
+    }
+
+    fn request(request_type: RequestType, version: &str) -> CloudFormationRequest {
+        CloudFormationRequest {
+            request_id: "request".to_string(),
+            request_type,
+            response_url: "https://cloudformation.example/response".to_string(),
+            resource_type: "Custom::FunctionDeployTrigger".to_string(),
+            logical_resource_id: "AppleDeviceProfile".to_string(),
+            stack_id: "stack".to_string(),
+            physical_resource_id: None,
+            resource_properties: DeviceProfilePublisherParameters { version: version.to_string() },
+            old_resource_properties: None
+        }
+    }
+
+    fn update(version: &str, old_version: &str) -> CloudFormationRequest {
+        CloudFormationRequest {
+            old_resource_properties: Some(DeviceProfilePublisherParameters { version: old_version.to_string() }),
+            ..request(RequestType::Update, version)
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_and_puts_every_artifact() {
+        let store = FakeStore::with_templates(TEMPLATES);
+
+        publish(&store, &config(), &request(RequestType::Create, "2")).await.unwrap();
+
+        assert_eq!(store.keys(), vec!["2/dns.mobileconfig", "dns.mobileconfig", "firefox-policies.json", PUBLISHED_KEYS_KEY]);
+
+        let mobile_config = (
+            "application/x-apple-aspen-config".to_string(),
+            "<string>https://dns.example/dns-query</string><string>2</string>".to_string()
+        );
+
+        // Under both the versioned and the latest key
+        assert_eq!(store.object("2/dns.mobileconfig"), Some(mobile_config.clone()));
+        assert_eq!(store.object("dns.mobileconfig"), Some(mobile_config));
+        assert_eq!(store.object("firefox-policies.json"), Some((
+            "application/json".to_string(),
+            r#"{"url": "https://dns.example/dns-query"}"#.to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_missing_template() {
+        let store = FakeStore::with_templates(&TEMPLATES[..1]);
+
+        let err = publish(&store, &config(), &request(RequestType::Create, "2")).await.unwrap_err().to_string();
+
+        assert!(err.contains("firefox-policies.json"));
+    }
+
+    #[tokio::test]
+    async fn deletes_every_published_key() {
+        let store = FakeStore::with_templates(TEMPLATES);
+
+        publish(&store, &config(), &request(RequestType::Create, "2")).await.unwrap();
+        publish(&store, &config(), &request(RequestType::Delete, "2")).await.unwrap();
+
+        assert!(store.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_old_versions_and_removed_artifacts_on_update() {
+        let store = FakeStore::with_templates(TEMPLATES);
+
+        publish(&store, &config(), &request(RequestType::Create, "1")).await.unwrap();
+
+        let mut config = config();
+        config.artifacts.truncate(1);
+
+        publish(&store, &config, &update("2", "1")).await.unwrap();
+
+        assert_eq!(store.keys(), vec!["2/dns.mobileconfig", "dns.mobileconfig", PUBLISHED_KEYS_KEY]);
+        assert!(store.object("dns.mobileconfig").unwrap().1.contains("<string>2</string>"));
+
+        publish(&store, &config, &request(RequestType::Delete, "2")).await.unwrap();
+
+        assert!(store.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_the_old_version_without_published_keys() {
+        let store = FakeStore::with_templates(TEMPLATES);
+
+        publish(&store, &config(), &request(RequestType::Create, "1")).await.unwrap();
+        store.delete("profiles", PUBLISHED_KEYS_KEY).await.unwrap();
+
+        publish(&store, &config(), &update("2", "1")).await.unwrap();
+
+        assert_eq!(store.keys(), vec!["2/dns.mobileconfig", "dns.mobileconfig", "firefox-policies.json", PUBLISHED_KEYS_KEY]);
+    }
+
+    // Answers one request with a 200, returning its body
+    async fn cloudformation_endpoint() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/response", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+
+            loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+
+                let request = String::from_utf8_lossy(&request);
+                let (headers, body) = match request.split_once("\r\n\r\n") {
+                    Some(parts) => parts,
+                    None => continue
+                };
+
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse().unwrap()))
+                    .unwrap_or(0);
+
+                if read == 0 || body.len() >= length {
+                    socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+                    return body.to_string();
+                }
+            }
+        });
+
+        (url, server)
+    }
+
+    #[test]
+    fn reports_invalid_config_as_a_failure() {
+        let _env = config::tests::ENV.lock().unwrap_or_else(|err| err.into_inner());
+        env::remove_var("APPLE_DEVICE_PROFILE_BUCKET_NAME");
+        env::remove_var("RESOLVER_URL");
+
+        let response = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (url, server) = cloudformation_endpoint().await;
+            let request = CloudFormationRequest { response_url: url, ..request(RequestType::Create, "2") };
+
+            handler(LambdaEvent::new(request, lambda_runtime::Context::default())).await.unwrap();
+
+            server.await.unwrap()
+        });
+
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+
+        assert_eq!(response["Status"], "FAILED");
+        assert_eq!(response["RequestId"], "request");
+        assert!(response["Reason"].as_str().unwrap().contains("RESOLVER_URL"));
+    }
+
+    #[test]
+    fn completes_when_the_response_cannot_be_delivered() {
+        let _env = config::tests::ENV.lock().unwrap_or_else(|err| err.into_inner());
+        env::remove_var("APPLE_DEVICE_PROFILE_BUCKET_NAME");
+
+        // Nothing listens on port 1
+        let request = CloudFormationRequest { response_url: "http://127.0.0.1:1/response".to_string(), ..request(RequestType::Create, "2") };
+
+        let result = tokio::runtime::Runtime::new().unwrap().block_on(handler(LambdaEvent::new(request, lambda_runtime::Context::default())));
+
+        assert!(result.is_ok());
+    }
+}
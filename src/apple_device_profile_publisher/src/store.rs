@@ -0,0 +1,56 @@
+use std::{
+    future::Future,
+    pin::Pin
+};
+
+use aws_sdk_s3::types::ByteStream;
+
+use lambda_runtime::Error;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+// The S3 operations the publisher makes. Production uses the AWS SDK client,
+// tests an in-memory store.
+pub trait ObjectStore: Send + Sync {
+    fn get(&self, bucket: &str, key: &str) -> StoreFuture<'_, Vec<u8>>;
+    fn put(&self, bucket: &str, key: &str, content_type: &str, body: Vec<u8>) -> StoreFuture<'_, ()>;
+    fn delete(&self, bucket: &str, key: &str) -> StoreFuture<'_, ()>;
+}
+
+// Objects are always served fresh, so devices pick up a new profile as soon
+// as it's published
+const CACHE_CONTROL: &str = "public, must-revalidate, proxy-revalidate, max-age=0";
+
+impl ObjectStore for aws_sdk_s3::Client {
+    fn get(&self, bucket: &str, key: &str) -> StoreFuture<'_, Vec<u8>> {
+        let request = self.get_object().bucket(bucket).key(key);
+
+        Box::pin(async move {
+            Ok(request.send().await?.body.collect().await?.into_bytes().to_vec())
+        })
+    }
+
+    fn put(&self, bucket: &str, key: &str, content_type: &str, body: Vec<u8>) -> StoreFuture<'_, ()> {
+        let request = self
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .cache_control(CACHE_CONTROL)
+            .body(ByteStream::from(body));
+
+        Box::pin(async move {
+            request.send().await?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, bucket: &str, key: &str) -> StoreFuture<'_, ()> {
+        let request = self.delete_object().bucket(bucket).key(key);
+
+        Box::pin(async move {
+            request.send().await?;
+            Ok(())
+        })
+    }
+}
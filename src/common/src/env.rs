@@ -0,0 +1,30 @@
+use std::env;
+
+// The names that aren't set, or are set to an empty string, so every missing
+// required env var can be reported at once
+pub fn missing_env_vars<'a>(names: &[&'a str]) -> Vec<&'a str> {
+    names
+        .iter()
+        .filter(|name| env::var(name).map(|value| value.is_empty()).unwrap_or(true))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_unset_and_empty_vars() {
+        env::set_var("COMMON_TEST_SET", "value");
+        env::set_var("COMMON_TEST_EMPTY", "");
+        env::remove_var("COMMON_TEST_UNSET");
+
+        assert_eq!(
+            missing_env_vars(&["COMMON_TEST_SET", "COMMON_TEST_EMPTY", "COMMON_TEST_UNSET"]),
+            ["COMMON_TEST_EMPTY", "COMMON_TEST_UNSET"]
+        );
+        assert!(missing_env_vars(&["COMMON_TEST_SET"]).is_empty());
+        assert!(missing_env_vars(&[]).is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+// Code shared by the Lambda functions, so formats the updater writes and the
+// responder reads can't drift apart
+
+pub mod env;
+pub mod list_entry;
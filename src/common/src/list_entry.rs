@@ -0,0 +1,70 @@
+use chrono::{
+    DateTime,
+    NaiveDate,
+    Utc
+};
+
+// Deny list entries may carry an expiry after which they no longer apply,
+// either a date (expiring at the start of that day, UTC) or an RFC 3339
+// timestamp:
+//
+//     evil.com #expires=2025-01-01
+//
+// The updater writes these and the responder reads them, so both parse them
+// here. Other comments are ignored, as are expiries that don't parse.
+pub fn parse_entry(line: &str) -> (&str, Option<DateTime<Utc>>) {
+    let (domain, comment) = match line.split_once('#') {
+        Some((domain, comment)) => (domain.trim(), Some(comment.trim())),
+        None => (line.trim(), None)
+    };
+
+    let expiry = comment
+        .and_then(|comment| comment.strip_prefix("expires="))
+        .and_then(parse_expiry);
+
+    (domain, expiry)
+}
+
+pub fn parse_expiry(expiry: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(expiry, "%Y-%m-%d") {
+        return date.and_hms_opt(0, 0, 0).map(|time| time.and_utc());
+    }
+
+    DateTime::parse_from_rfc3339(expiry).ok().map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc))
+    }
+
+    #[test]
+    fn parses_plain_entries() {
+        assert_eq!(parse_entry("evil.com"), ("evil.com", None));
+        assert_eq!(parse_entry("  evil.com  "), ("evil.com", None));
+        assert_eq!(parse_entry(""), ("", None));
+    }
+
+    #[test]
+    fn parses_date_expiries() {
+        assert_eq!(parse_entry("evil.com #expires=2025-01-01"), ("evil.com", time("2025-01-01T00:00:00Z")));
+        assert_eq!(parse_entry("evil.com#expires=2025-01-01"), ("evil.com", time("2025-01-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn parses_timestamp_expiries() {
+        assert_eq!(parse_entry("evil.com #expires=2025-01-01T12:30:00Z"), ("evil.com", time("2025-01-01T12:30:00Z")));
+        assert_eq!(parse_entry("evil.com #expires=2025-01-01T12:30:00+02:00"), ("evil.com", time("2025-01-01T10:30:00Z")));
+    }
+
+    #[test]
+    fn ignores_other_comments_and_invalid_expiries() {
+        assert_eq!(parse_entry("evil.com # from some list"), ("evil.com", None));
+        assert_eq!(parse_entry("evil.com #expires=soon"), ("evil.com", None));
+        assert_eq!(parse_entry("evil.com #expires=2025-13-01"), ("evil.com", None));
+        assert_eq!(parse_entry("# just a comment"), ("", None));
+    }
+}
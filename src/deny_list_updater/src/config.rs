@@ -0,0 +1,342 @@
+use std::{
+    env,
+    str::FromStr,
+    time::Duration
+};
+
+use common::env::missing_env_vars;
+
+use lambda_runtime::Error;
+
+const DENY_LIST_URL: &str = "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts";
+const ALLOW_LIST_URL: &str = "https://raw.githubusercontent.com/NChaves/pi-hole/main/adBlockListGetAdmiral_ABP.txt";
+
+// How long a source download may take unless it sets its own `timeout`
+const DEFAULT_SOURCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const REQUIRED_ENV_VARS: &[&str] = &[
+    "RESPONDER_FUNCTION_NAME"
+];
+
+// What a run does, configured with ACTION
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // Rebuild the lists and deploy them with the responder
+    Update,
+    // Only check the deployed lists against their sources
+    Verify
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(action: &str) -> Result<Self, Error> {
+        match action {
+            "update" => Ok(Action::Update),
+            "verify" => Ok(Action::Verify),
+            _ => Err(format!("Unknown ACTION '{}', expected 'update' or 'verify'", action).into())
+        }
+    }
+}
+
+// How the lists are written into the responder package, configured with
+// DENY_LIST_FORMAT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenyListFormat {
+    // Plain `hosts` and `allow` files
+    Hosts,
+    // A single SQLite database
+    Sqlite,
+    // The deny list prebuilt into a sorted, compressed `hosts.fst` set, which
+    // the responder loads without parsing a line, alongside a `hosts` file
+    // holding only the entries that expire, and a plain `allow` file
+    Fst
+}
+
+impl FromStr for DenyListFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Error> {
+        match format {
+            "hosts" => Ok(DenyListFormat::Hosts),
+            "sqlite" => Ok(DenyListFormat::Sqlite),
+            "fst" => Ok(DenyListFormat::Fst),
+            _ => Err(format!("Unknown DENY_LIST_FORMAT '{}', expected 'hosts', 'sqlite', or 'fst'", format).into())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ListFormat {
+    Abp,
+    Domains,
+    Hosts,
+    // Detected line by line, for lists mixing formats and sources given
+    // without one
+    Auto
+}
+
+impl FromStr for ListFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Error> {
+        match format {
+            "abp" => Ok(ListFormat::Abp),
+            "domains" => Ok(ListFormat::Domains),
+            "hosts" => Ok(ListFormat::Hosts),
+            "auto" => Ok(ListFormat::Auto),
+            _ => Err(format!("Unknown list format '{}', expected 'abp', 'domains', 'hosts', or 'auto'", format).into())
+        }
+    }
+}
+
+// What to do with names that are on both the deny list and an allow list,
+// configured with CONFLICT_POLICY
+#[derive(Debug, Clone, Copy)]
+pub enum ConflictPolicy {
+    // Drop the name from the deny list
+    AllowWins,
+    // Drop the name from the allow lists
+    DenyWins,
+    // Ship both lists unchanged. The responder lets an allow list entry
+    // override a deny list entry for the same name, so at query time this
+    // settles like AllowWins, only with both entries deployed.
+    LogOnly
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self, Error> {
+        match policy {
+            "allow_wins" => Ok(ConflictPolicy::AllowWins),
+            "deny_wins" => Ok(ConflictPolicy::DenyWins),
+            "log_only" => Ok(ConflictPolicy::LogOnly),
+            _ => Err(format!("Unknown CONFLICT_POLICY '{}', expected 'allow_wins', 'deny_wins', or 'log_only'", policy).into())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListSource {
+    pub format: ListFormat,
+    pub url: String,
+    pub timeout: Duration,
+    // Whether failing to download the source fails the run, rather than the
+    // run carrying on without it
+    pub required: bool
+}
+
+// Every updater setting, read and validated once at startup
+#[derive(Debug)]
+pub struct Config {
+    pub responder_function_name: String,
+    pub action: Action,
+    pub deny_list_format: DenyListFormat,
+    pub conflict_policy: ConflictPolicy,
+    // Configured in DENY_LIST_SOURCES and ALLOW_LIST_SOURCES as
+    // comma-separated lists of `<format>:<url>`, or bare URLs to detect the
+    // format of each line, where the format is one of:
+    //
+    //     abp      Adblock Plus rules, e.g. `||example.com^`
+    //     domains  one domain per line
+    //     hosts    hosts file lines, e.g. `0.0.0.0 example.com`
+    //     auto     any of the above
+    //
+    // Sources may be followed by `;`-separated options:
+    //
+    //     timeout=<seconds>  give up on the download after this long
+    //     optional           skip the source with a warning if it fails,
+    //                        rather than failing the run
+    pub deny_list_sources: Vec<ListSource>,
+    pub allow_list_sources: Vec<ListSource>,
+    // A feed of newly registered domains, configured in NRD_LIST_SOURCE as
+    // `<format>:<url>`. Its domains are shipped in the deny list under their
+    // own category, for the responder to handle more softly than hard blocks.
+    pub nrd_list_source: Option<ListSource>
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Error> {
+        let missing_env_vars = missing_env_vars(REQUIRED_ENV_VARS);
+        if !missing_env_vars.is_empty() {
+            return Err(format!("Missing required env vars: {}", missing_env_vars.join(", ")).into());
+        }
+
+        let deny_list_sources = parse_list_sources(&env::var("DENY_LIST_SOURCES").unwrap_or(format!("hosts:{}", DENY_LIST_URL)))?;
+        let allow_list_sources = parse_list_sources(&env::var("ALLOW_LIST_SOURCES").unwrap_or(format!("abp:{}", ALLOW_LIST_URL)))?;
+
+        if deny_list_sources.is_empty() {
+            return Err("DENY_LIST_SOURCES must list at least one source".into());
+        }
+
+        let deny_list_format = env::var("DENY_LIST_FORMAT").unwrap_or("hosts".to_string()).parse()?;
+
+        let nrd_list_source = env::var("NRD_LIST_SOURCE")
+            .ok()
+            .filter(|source| !source.trim().is_empty())
+            .map(|source| parse_list_source(source.trim()))
+            .transpose()?;
+
+        // Only the database records categories
+        if nrd_list_source.is_some() && deny_list_format != DenyListFormat::Sqlite {
+            return Err("NRD_LIST_SOURCE requires DENY_LIST_FORMAT=sqlite".into());
+        }
+
+        Ok(Self {
+            responder_function_name: env::var("RESPONDER_FUNCTION_NAME")?,
+            action: env::var("ACTION").unwrap_or("update".to_string()).parse()?,
+            deny_list_format,
+            conflict_policy: env::var("CONFLICT_POLICY").unwrap_or("allow_wins".to_string()).parse()?,
+            deny_list_sources,
+            allow_list_sources,
+            nrd_list_source
+        })
+    }
+}
+
+fn parse_list_sources(sources: &str) -> Result<Vec<ListSource>, Error> {
+    sources
+        .split(',')
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .map(parse_list_source)
+        .collect()
+}
+
+fn parse_list_source(source: &str) -> Result<ListSource, Error> {
+    let mut parts = source.split(';').map(str::trim);
+    let location = parts.next().unwrap_or_default();
+
+    let (format, url) = match location.starts_with("http://") || location.starts_with("https://") {
+        true => (ListFormat::Auto, location),
+        false => {
+            let (format, url) = location
+                .split_once(':')
+                .ok_or_else(|| format!("List source '{}' is missing a format, e.g. 'abp:'", source))?;
+
+            (format.parse()?, url)
+        }
+    };
+
+    let mut list_source = ListSource { format, url: url.to_string(), timeout: DEFAULT_SOURCE_TIMEOUT, required: true };
+
+    for option in parts.filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            Some(("timeout", seconds)) => {
+                let seconds: u64 = seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid timeout '{}' for list source '{}', expected seconds", seconds, url))?;
+
+                list_source.timeout = Duration::from_secs(seconds);
+            },
+            None if option == "optional" => list_source.required = false,
+            _ => return Err(format!("Unknown option '{}' for list source '{}', expected 'timeout=<seconds>' or 'optional'", option, url).into())
+        };
+    }
+
+    Ok(list_source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const ENV_VARS: &[&str] = &[
+        "RESPONDER_FUNCTION_NAME",
+        "ACTION",
+        "DENY_LIST_FORMAT",
+        "CONFLICT_POLICY",
+        "DENY_LIST_SOURCES",
+        "ALLOW_LIST_SOURCES",
+        "NRD_LIST_SOURCE"
+    ];
+
+    // The environment is shared by every test thread
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+
+        for name in ENV_VARS {
+            env::remove_var(name);
+        }
+
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+
+        Config::from_env()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[("RESPONDER_FUNCTION_NAME", "responder")]).unwrap();
+
+        assert_eq!(config.responder_function_name, "responder");
+        assert_eq!(config.action, Action::Update);
+        assert_eq!(config.deny_list_format, DenyListFormat::Hosts);
+        assert!(matches!(config.conflict_policy, ConflictPolicy::AllowWins));
+        assert_eq!(config.deny_list_sources.len(), 1);
+        assert_eq!(config.deny_list_sources[0].url, DENY_LIST_URL);
+        assert!(matches!(config.deny_list_sources[0].format, ListFormat::Hosts));
+        assert_eq!(config.allow_list_sources[0].url, ALLOW_LIST_URL);
+        assert!(matches!(config.allow_list_sources[0].format, ListFormat::Abp));
+        assert!(config.nrd_list_source.is_none());
+    }
+
+    #[test]
+    fn parses_values() {
+        let config = config(&[
+            ("RESPONDER_FUNCTION_NAME", "responder"),
+            ("ACTION", "verify"),
+            ("DENY_LIST_FORMAT", "sqlite"),
+            ("CONFLICT_POLICY", "log_only"),
+            ("DENY_LIST_SOURCES", "domains:https://lists.example/a, https://lists.example/b; timeout=5; optional"),
+            ("NRD_LIST_SOURCE", "domains:https://lists.example/nrd")
+        ]).unwrap();
+
+        assert_eq!(config.action, Action::Verify);
+        assert_eq!(config.deny_list_format, DenyListFormat::Sqlite);
+        assert!(matches!(config.conflict_policy, ConflictPolicy::LogOnly));
+
+        let sources = &config.deny_list_sources;
+
+        assert_eq!(sources.len(), 2);
+        assert!(matches!(sources[0].format, ListFormat::Domains));
+        assert_eq!(sources[0].url, "https://lists.example/a");
+        assert_eq!(sources[0].timeout, DEFAULT_SOURCE_TIMEOUT);
+        assert!(sources[0].required);
+        assert!(matches!(sources[1].format, ListFormat::Auto));
+        assert_eq!(sources[1].url, "https://lists.example/b");
+        assert_eq!(sources[1].timeout, Duration::from_secs(5));
+        assert!(!sources[1].required);
+
+        assert_eq!(config.nrd_list_source.unwrap().url, "https://lists.example/nrd");
+    }
+
+    #[test]
+    fn requires_the_responder_function_name() {
+        let err = config(&[]).unwrap_err();
+
+        assert!(err.to_string().contains("RESPONDER_FUNCTION_NAME"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let function = ("RESPONDER_FUNCTION_NAME", "responder");
+
+        assert!(config(&[function, ("ACTION", "delete")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_FORMAT", "csv")]).is_err());
+        assert!(config(&[function, ("CONFLICT_POLICY", "coin_flip")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_SOURCES", "")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_SOURCES", "lists.example/a")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_SOURCES", "xml:https://lists.example/a")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_SOURCES", "https://lists.example/a;timeout=soon")]).is_err());
+        assert!(config(&[function, ("DENY_LIST_SOURCES", "https://lists.example/a;retries=3")]).is_err());
+        // Categories are only recorded in the database
+        assert!(config(&[function, ("NRD_LIST_SOURCE", "domains:https://lists.example/nrd")]).is_err());
+    }
+}
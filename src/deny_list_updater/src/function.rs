@@ -0,0 +1,108 @@
+use std::{
+    future::Future,
+    pin::Pin
+};
+
+use aws_sdk_lambda::{
+    model::{
+        Architecture,
+        LastUpdateStatus,
+        State
+    },
+    types::{
+        Blob,
+        SdkError
+    }
+};
+
+use lambda_runtime::Error;
+
+pub type FunctionFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+pub enum CodeUpdate {
+    // With the ARN of the updated function
+    Updated(String),
+    // Lambda rejects updates while a previous one is still in progress
+    Conflict
+}
+
+// The Lambda operations the updater makes on the responder function.
+// Production uses the AWS SDK client, tests an in-memory function.
+pub trait ResponderFunction: Send + Sync {
+    fn code_package(&self) -> FunctionFuture<'_, Vec<u8>>;
+    fn update_code(&self, package: Vec<u8>) -> FunctionFuture<'_, CodeUpdate>;
+    // The function's state and the status of its last update
+    fn update_status(&self) -> FunctionFuture<'_, (Option<State>, Option<LastUpdateStatus>)>;
+    fn tag(&self, function_arn: &str, tags: Vec<(&'static str, String)>) -> FunctionFuture<'_, ()>;
+}
+
+pub struct LambdaFunction {
+    client: aws_sdk_lambda::Client,
+    name: String
+}
+
+impl LambdaFunction {
+    pub fn new(client: aws_sdk_lambda::Client, name: &str) -> Self {
+        Self { client, name: name.to_string() }
+    }
+}
+
+impl ResponderFunction for LambdaFunction {
+    fn code_package(&self) -> FunctionFuture<'_, Vec<u8>> {
+        let request = self.client.get_function().function_name(&self.name);
+
+        Box::pin(async move {
+            let responder_code_location = request.send().await?.code
+                .ok_or("Missing responder function code config")?
+                .location
+                .ok_or("Missing responder function code location")?;
+
+            println!("Got code location");
+
+            Ok(reqwest::get(responder_code_location).await?
+                .bytes().await?
+                .to_vec()
+            )
+        })
+    }
+
+    fn update_code(&self, package: Vec<u8>) -> FunctionFuture<'_, CodeUpdate> {
+        let request = self.client
+            .update_function_code()
+            .function_name(&self.name)
+            .zip_file(Blob::new(package))
+            .architectures(Architecture::Arm64);
+
+        Box::pin(async move {
+            match request.send().await {
+                Ok(output) => Ok(CodeUpdate::Updated(output
+                    .function_arn()
+                    .ok_or("Lambda did not return the responder function ARN")?
+                    .to_string())),
+                Err(SdkError::ServiceError { err, .. }) if err.is_resource_conflict_exception() => Ok(CodeUpdate::Conflict),
+                Err(err) => Err(err.into())
+            }
+        })
+    }
+
+    fn update_status(&self) -> FunctionFuture<'_, (Option<State>, Option<LastUpdateStatus>)> {
+        let request = self.client.get_function_configuration().function_name(&self.name);
+
+        Box::pin(async move {
+            let config = request.send().await?;
+
+            Ok((config.state().cloned(), config.last_update_status().cloned()))
+        })
+    }
+
+    fn tag(&self, function_arn: &str, tags: Vec<(&'static str, String)>) -> FunctionFuture<'_, ()> {
+        let request = tags
+            .into_iter()
+            .fold(self.client.tag_resource().resource(function_arn), |request, (key, value)| request.tags(key, value));
+
+        Box::pin(async move {
+            request.send().await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    fs,
+    time::{
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+use chrono::{
+    DateTime,
+    Utc
+};
+
+use lambda_runtime::Error;
+
+use rusqlite::{
+    params,
+    Connection
+};
+
+// The responder looks for this file in its package to decide whether to use
+// the database instead of the plain hosts file
+pub const LIST_DATABASE_FILENAME: &str = "hosts.db";
+
+// Lambda only allows writes under /tmp
+const LIST_DATABASE_BUILD_PATH: &str = "/tmp/hosts.db";
+
+const SCHEMA: &str = "
+    CREATE TABLE deny (
+        domain TEXT PRIMARY KEY NOT NULL,
+        category TEXT,
+        source TEXT NOT NULL,
+        added INTEGER NOT NULL,
+        expires INTEGER
+    ) WITHOUT ROWID;
+
+    CREATE TABLE allow (
+        domain TEXT PRIMARY KEY NOT NULL,
+        category TEXT,
+        source TEXT NOT NULL,
+        added INTEGER NOT NULL,
+        expires INTEGER
+    ) WITHOUT ROWID;
+";
+
+pub struct ListEntries<'a> {
+    pub domains: &'a HashSet<String>,
+    pub expiries: Option<&'a HashMap<String, DateTime<Utc>>>,
+    pub category: Option<&'a str>,
+    pub source: &'a str
+}
+
+// Builds the database file and returns its contents
+pub fn build_list_database(deny_lists: &[ListEntries], allow_lists: &[ListEntries]) -> Result<Vec<u8>, Error> {
+    if fs::metadata(LIST_DATABASE_BUILD_PATH).is_ok() {
+        fs::remove_file(LIST_DATABASE_BUILD_PATH)?;
+    }
+
+    let added = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut connection = Connection::open(LIST_DATABASE_BUILD_PATH)?;
+
+    connection.execute_batch(SCHEMA)?;
+
+    let transaction = connection.transaction()?;
+
+    for (table, lists) in [("deny", deny_lists), ("allow", allow_lists)] {
+        let mut statement = transaction.prepare(&format!(
+            "INSERT OR IGNORE INTO {} (domain, category, source, added, expires) VALUES (?1, ?2, ?3, ?4, ?5)",
+            table
+        ))?;
+
+        for list in lists {
+            for domain in list.domains {
+                let expires = list.expiries
+                    .and_then(|expiries| expiries.get(domain))
+                    .map(|expiry| expiry.timestamp());
+
+                statement.execute(params![domain, list.category, list.source, added, expires])?;
+            }
+        }
+    }
+
+    transaction.commit()?;
+
+    // Compact the file before it is bundled into the package
+    connection.execute_batch("VACUUM")?;
+    drop(connection);
+
+    let database = fs::read(LIST_DATABASE_BUILD_PATH)?;
+
+    fs::remove_file(LIST_DATABASE_BUILD_PATH)?;
+
+    Ok(database)
+}
+
+// Reads the unexpired deny and allow domains back out of a database
+pub fn read_list_database(database: &[u8]) -> Result<(HashSet<String>, HashSet<String>), Error> {
+    fs::write(LIST_DATABASE_BUILD_PATH, database)?;
+
+    let connection = Connection::open(LIST_DATABASE_BUILD_PATH)?;
+
+    let deny = read_domains(&connection, "deny")?;
+    let allow = read_domains(&connection, "allow")?;
+
+    drop(connection);
+    fs::remove_file(LIST_DATABASE_BUILD_PATH)?;
+
+    Ok((deny, allow))
+}
+
+fn read_domains(connection: &Connection, table: &str) -> Result<HashSet<String>, Error> {
+    let mut statement = connection.prepare(&format!(
+        "SELECT domain FROM {} WHERE expires IS NULL OR expires > unixepoch()",
+        table
+    ))?;
+
+    let domains = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<HashSet<String>, rusqlite::Error>>()?;
+
+    Ok(domains)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn domains(domains: &[&str]) -> HashSet<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_back_unexpired_entries() {
+        let deny = domains(&["ads.example", "expired.example", "fresh.example"]);
+        let allow = domains(&["cdn.example"]);
+
+        let expiries = HashMap::from([
+            ("expired.example".to_string(), Utc::now() - Duration::days(1)),
+            ("fresh.example".to_string(), Utc::now() + Duration::days(1))
+        ]);
+
+        let database = build_list_database(
+            &[ListEntries { domains: &deny, expiries: Some(&expiries), category: None, source: "test" }],
+            &[ListEntries { domains: &allow, expiries: None, category: None, source: "test" }]
+        ).unwrap();
+
+        let (deny, allow) = read_list_database(&database).unwrap();
+
+        assert_eq!(deny, domains(&["ads.example", "fresh.example"]));
+        assert_eq!(allow, domains(&["cdn.example"]));
+    }
+}
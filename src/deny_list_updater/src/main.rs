@@ -1,13 +1,33 @@
+mod config;
+mod function;
+mod list_db;
+
 use std::{
-    collections::HashSet, env, io::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    io::{
         Cursor,
+        Read,
         Write
+    },
+    net::IpAddr,
+    time::{
+        Duration,
+        Instant
     }
 };
 
-use aws_sdk_lambda::{
-    model::Architecture,
-    types::Blob
+use aws_sdk_lambda::model::{
+    LastUpdateStatus,
+    State
+};
+
+use chrono::{
+    DateTime,
+    SecondsFormat,
+    Utc
 };
 
 use lambda_runtime::{
@@ -16,117 +36,686 @@ use lambda_runtime::{
     service_fn
 };
 
-use regex::Regex;
+use bytes::Bytes;
+
+use common::list_entry::parse_entry;
+
+use futures_util::future::join_all;
 
 use serde_json::Value;
 
+use sha2::{
+    Digest,
+    Sha256
+};
+
+use config::{
+    Action,
+    Config,
+    ConflictPolicy,
+    DenyListFormat,
+    ListFormat,
+    ListSource
+};
+
+use function::{
+    CodeUpdate,
+    LambdaFunction,
+    ResponderFunction
+};
+
+use list_db::{
+    build_list_database,
+    read_list_database,
+    ListEntries,
+    LIST_DATABASE_FILENAME
+};
+
+#[derive(Default)]
+struct DenyList {
+    domains: HashSet<String>,
+    expiries: HashMap<String, DateTime<Utc>>,
+    // Each source URL, with when it was last changed from its Last-Modified
+    // header
+    sources: Vec<(String, Option<DateTime<Utc>>)>
+}
+
+impl DenyList {
+    // The merged list doesn't track which source each domain came from
+    fn source_label(&self) -> String {
+        self.sources
+            .iter()
+            .map(|(source, _)| source.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+}
+
+// How the deny list changed from the deployed one, in sorted order
+struct DenyListDelta {
+    added: Vec<String>,
+    removed: Vec<String>
+}
+
+// A list downloaded from a single source
+struct SourceList {
+    source: String,
+    domains: HashSet<String>,
+    last_modified: Option<DateTime<Utc>>
+}
+
+enum ListContents<'a> {
+    Bytes(Vec<u8>),
+    // Plain list files are written into the package a chunk of lines at a
+    // time rather than built up front, keeping peak memory low on small
+    // function sizes
+    Domains {
+        domains: Vec<&'a String>,
+        expiries: Option<&'a HashMap<String, DateTime<Utc>>>
+    }
+}
+
+// Manually allow-listed domains
+const MANUAL_ALLOW_LIST: &[&str] = &[
+    // adsafeprotected.com is used on eater.com
+    "static.adsafeprotected.com"
+];
+const MANUAL_ALLOW_LIST_SOURCE: &str = "manual";
+
+const HOSTS_FILE_OWN_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts"
+];
+
+// Deny list category of domains from the NRD_LIST_SOURCE feed, which the
+// responder answers per its NRD_HANDLING
+const NRD_CATEGORY: &str = "newly-registered";
+
+const LIST_CHUNK_LINES: usize = 10_000;
+
+// Written with DENY_LIST_FORMAT=fst, and preferred by the responder over the
+// plain `hosts` file when present
+const COMPILED_DENY_LIST_FILENAME: &str = "hosts.fst";
+
+// When the lists were built and how fresh their sources were, for the
+// responder to report
+const BUILD_INFO_FILENAME: &str = "build-info.json";
+
+// The domains added to and removed from the deny list by the last update
+const DENY_LIST_DELTA_FILENAME: &str = "deny-list-delta.json";
+
+// Every file the updater writes into the package, in any DENY_LIST_FORMAT.
+// Anything else in the package, e.g. the responder's overrides, is carried
+// over unchanged.
+const GENERATED_FILENAMES: &[&str] = &[
+    "hosts",
+    "allow",
+    COMPILED_DENY_LIST_FILENAME,
+    LIST_DATABASE_FILENAME,
+    BUILD_INFO_FILENAME,
+    DENY_LIST_DELTA_FILENAME
+];
+
+// Uploads rejected because the function is mid-update are retried once it
+// settles, polling its state with exponential backoff
+const UPLOAD_ATTEMPTS: u32 = 5;
+const FUNCTION_SETTLE_TIMEOUT: Duration = Duration::from_secs(60);
+const FUNCTION_POLL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const FUNCTION_POLL_MAX_DELAY: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    lambda_runtime::run(service_fn(handler)).await?;
+    // Missing or invalid settings fail the init phase rather than every
+    // scheduled run
+    let config = Config::from_env()?;
+
+    let aws_config = aws_config::load_from_env().await;
+    let function = LambdaFunction::new(aws_sdk_lambda::Client::new(&aws_config), &config.responder_function_name);
+
+    lambda_runtime::run(service_fn(|event| handler(&config, &function, event))).await?;
 
     Ok(())
 }
 
-async fn handler(_: LambdaEvent<Value>) -> Result<(), Error> {
-    let responder_function_name = env::var("RESPONDER_FUNCTION_NAME")
-        .expect("RESPONDER_FUNCTION_NAME env var not available");
-
-    let aws_config = aws_config::load_from_env().await;
-    let lambda_client = aws_sdk_lambda::Client::new(&aws_config);
+async fn handler(config: &Config, function: &dyn ResponderFunction, _: LambdaEvent<Value>) -> Result<(), Error> {
+    let deny_list_format = config.deny_list_format;
 
-    let package_future = get_code_package(&responder_function_name, &lambda_client);
-    let deny_list_future = get_deny_list();
-    let allow_list_future = get_allow_list();
+    let package_future = function.code_package();
+    let deny_list_future = get_deny_list(&config.deny_list_sources);
+    let allow_lists_future = get_allow_lists(&config.allow_list_sources);
+    let nrd_list_future = get_nrd_list(config.nrd_list_source.as_ref());
 
     let package = package_future.await?;
-    let deny_list = deny_list_future.await?;
-    let allow_list = allow_list_future.await?;
+    let mut deny_list = deny_list_future.await?;
+    let mut allow_lists = allow_lists_future.await?;
+    let nrd_list = nrd_list_future.await?;
+
+    resolve_conflicts(config.conflict_policy, &mut deny_list, &mut allow_lists);
+
+    let allow_list: HashSet<String> = allow_lists
+        .iter()
+        .flat_map(|allow_list| allow_list.domains.iter().cloned())
+        .collect();
 
     println!("Downloaded code and allow/deny lists");
 
-    let mut deny_list_string = "".to_string();
-    for domain in deny_list.difference(&allow_list) {
-        deny_list_string.push_str(domain);
-        deny_list_string.push('\n');
+    if config.action == Action::Verify {
+        return verify_code_package(&package, deny_list_format, &deny_list, nrd_list.as_ref(), &allow_list);
     }
 
-    println!("Simplified deny list");
+    let built_at = Utc::now();
+
+    let mut list_files = build_list_files(deny_list_format, &deny_list, nrd_list.as_ref(), &allow_list, &allow_lists)?;
+    list_files.push((BUILD_INFO_FILENAME, ListContents::Bytes(build_info(built_at, &deny_list, nrd_list.as_ref(), &allow_lists)?)));
+
+    let delta = deny_list_delta(&package, deny_list_format, &deployable_deny_list(&deny_list, nrd_list.as_ref()));
+
+    println!("Deny list changes from the deployed package: {} added, {} removed", delta.added.len(), delta.removed.len());
 
-    let package = update_code_package(package, deny_list_string)?;
+    list_files.push((DENY_LIST_DELTA_FILENAME, ListContents::Bytes(delta_record(built_at, &delta)?)));
+
+    let deployed_digest = list_files_digest(&package)?;
+
+    let package = update_code_package(package, list_files)?;
 
     println!("Finished writing zip to buffer");
 
-    upload_new_code_package(&responder_function_name, &lambda_client, package).await?;
+    if list_files_digest(&package)? == deployed_digest {
+        println!("Deny/allow lists are unchanged from the deployed package, skipping upload");
+        return Ok(());
+    }
+
+    let function_arn = upload_new_code_package(function, package).await?;
 
     println!("Finished uploading new code package");
 
+    function.tag(&function_arn, deny_list_tags(built_at, &deny_list, &allow_lists, &delta)).await?;
+
+    println!("Finished tagging responder function with deny list metadata");
+
     Ok(())
 }
 
-async fn get_code_package(responder_function_name: &str, lambda_client: &aws_sdk_lambda::client::Client) -> Result<Vec<u8>, Error> {
-    let responder_function_config = lambda_client
-        .get_function()
-        .function_name(responder_function_name)
-        .send()
-        .await?;
+fn build_list_files<'a>(deny_list_format: DenyListFormat, deny_list: &'a DenyList, nrd_list: Option<&SourceList>, allow_list: &'a HashSet<String>, allow_lists: &[SourceList]) -> Result<Vec<(&'static str, ListContents<'a>)>, Error> {
+    let list_files = match deny_list_format {
+        DenyListFormat::Hosts => {
+            // Sort so identical lists produce byte-identical packages
+            let mut deny_list_domains: Vec<&String> = deny_list.domains.iter().collect();
+            deny_list_domains.sort();
+
+            // The responder still needs the allow list to override its own
+            // blocking rules (e.g. blocked TLDs)
+            let mut allow_list_domains: Vec<&String> = allow_list.iter().collect();
+            allow_list_domains.sort();
+
+            vec![
+                ("hosts", ListContents::Domains { domains: deny_list_domains, expiries: Some(&deny_list.expiries) }),
+                ("allow", ListContents::Domains { domains: allow_list_domains, expiries: None })
+            ]
+        },
+        DenyListFormat::Fst => {
+            let mut deny_list_domains: Vec<&String> = deny_list.domains.iter().collect();
+            deny_list_domains.sort();
+
+            let compiled = fst::Set::from_iter(&deny_list_domains)?.into_fst().into_inner();
+
+            // Expiries can't be stored in the set itself
+            let expiring_domains: Vec<&String> = deny_list_domains
+                .iter()
+                .filter(|domain| deny_list.expiries.contains_key(**domain))
+                .copied()
+                .collect();
+
+            let mut allow_list_domains: Vec<&String> = allow_list.iter().collect();
+            allow_list_domains.sort();
+
+            println!("Built compiled deny list of {} bytes", compiled.len());
+
+            vec![
+                (COMPILED_DENY_LIST_FILENAME, ListContents::Bytes(compiled)),
+                ("hosts", ListContents::Domains { domains: expiring_domains, expiries: Some(&deny_list.expiries) }),
+                ("allow", ListContents::Domains { domains: allow_list_domains, expiries: None })
+            ]
+        },
+        DenyListFormat::Sqlite => {
+            // The responder applies the allow list itself when using the database
+            let allow_list_entries: Vec<ListEntries> = allow_lists
+                .iter()
+                .map(|allow_list| ListEntries { domains: &allow_list.domains, expiries: None, category: None, source: &allow_list.source })
+                .collect();
+
+            // Entries already on the deny list keep their uncategorized hard
+            // block, as the first insert of a domain wins
+            let deny_list_source = deny_list.source_label();
+
+            let deny_list_entries: Vec<ListEntries> = [ListEntries { domains: &deny_list.domains, expiries: Some(&deny_list.expiries), category: None, source: &deny_list_source }]
+                .into_iter()
+                .chain(nrd_list.map(|nrd_list| ListEntries { domains: &nrd_list.domains, expiries: None, category: Some(NRD_CATEGORY), source: &nrd_list.source }))
+                .collect();
 
-    let responder_code_location = responder_function_config.code
-        .expect("Missing responder function code config")
-        .location
-        .expect("Missing responder function code location");
+            let database = build_list_database(&deny_list_entries, &allow_list_entries)?;
 
-    println!("Got code location");
+            println!("Built deny/allow list database");
 
-    Ok(reqwest::get(responder_code_location).await?
-        .bytes().await?
-        .as_ref()
-        .to_vec()
-    )
+            vec![(LIST_DATABASE_FILENAME, ListContents::Bytes(database))]
+        },
+    };
+
+    Ok(list_files)
 }
 
-async fn get_deny_list() -> Result<HashSet<String>, Error> {
-    const DENY_LIST_URL: &'static str = "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts";
+// Compares the lists in the deployed package against the ones the sources
+// would produce now, to catch failed deploys or manual modifications. Drift
+// fails the invocation so it shows up in the function's error metrics.
+fn verify_code_package(package: &[u8], deny_list_format: DenyListFormat, deny_list: &DenyList, nrd_list: Option<&SourceList>, allow_list: &HashSet<String>) -> Result<(), Error> {
+    let (deployed_deny_list, deployed_allow_list) = read_deployed_lists(package, deny_list_format)?;
+    let expected_deny_list = deployable_deny_list(deny_list, nrd_list);
 
-    let bytes = reqwest::get(DENY_LIST_URL).await?
-        .bytes().await?;
+    let mut drifted = false;
 
-    let hosts = std::str::from_utf8(&bytes)?;
+    for (name, expected, deployed) in [("deny", &expected_deny_list, &deployed_deny_list), ("allow", allow_list, &deployed_allow_list)] {
+        let missing = expected.difference(deployed).count();
+        let unexpected = deployed.difference(expected).count();
 
-    let simplify_re = Regex::new(r"(?m)^0.0.0.0 (.*)$").unwrap();
+        println!("Verified {} list: {} expected, {} deployed, {} missing, {} unexpected", name, expected.len(), deployed.len(), missing, unexpected);
 
-    let mut deny_list = HashSet::new();
+        drifted |= missing > 0 || unexpected > 0;
+    }
 
-    for (_, [domain]) in simplify_re.captures_iter(hosts).map(|captures| captures.extract()) {
-        deny_list.insert(domain.to_string());
+    if drifted {
+        return Err("Deployed deny/allow lists do not match their sources".into());
+    }
+
+    println!("Deployed deny/allow lists match their sources");
+
+    Ok(())
+}
+
+// Every domain shipped on the deny list, whatever its category
+fn deployable_deny_list(deny_list: &DenyList, nrd_list: Option<&SourceList>) -> HashSet<String> {
+    deny_list.domains
+        .iter()
+        .chain(nrd_list.iter().flat_map(|nrd_list| nrd_list.domains.iter()))
+        .cloned()
+        .collect()
+}
+
+// Reads the unexpired deny and allow domains back out of a package built with
+// `deny_list_format`
+fn read_deployed_lists(package: &[u8], deny_list_format: DenyListFormat) -> Result<(HashSet<String>, HashSet<String>), Error> {
+    let mut reader = zip::ZipArchive::new(Cursor::new(package))?;
+
+    match deny_list_format {
+        DenyListFormat::Hosts => Ok((read_deployed_hosts(&mut reader, "hosts")?, read_deployed_hosts(&mut reader, "allow")?)),
+        DenyListFormat::Fst => {
+            let mut compiled = Vec::new();
+            reader.by_name(COMPILED_DENY_LIST_FILENAME)?.read_to_end(&mut compiled)?;
+
+            // The set keeps entries past their expiry, which only the
+            // `hosts` file records
+            let mut expiring = String::new();
+            reader.by_name("hosts")?.read_to_string(&mut expiring)?;
+
+            let now = Utc::now();
+            let expired: HashSet<&str> = expiring
+                .lines()
+                .map(parse_entry)
+                .filter(|(_, expiry)| expiry.map(|expiry| expiry <= now).unwrap_or(false))
+                .map(|(domain, _)| domain)
+                .collect();
+
+            let deployed_deny_list = fst::Set::new(compiled)?
+                .stream()
+                .into_strs()?
+                .into_iter()
+                .filter(|domain| !expired.contains(domain.as_str()))
+                .collect();
+
+            Ok((deployed_deny_list, read_deployed_hosts(&mut reader, "allow")?))
+        },
+        DenyListFormat::Sqlite => {
+            let mut database = Vec::new();
+            reader.by_name(LIST_DATABASE_FILENAME)?.read_to_end(&mut database)?;
+
+            read_list_database(&database)
+        }
+    }
+}
+
+// Compares the new deny list against the deployed package's. A package
+// without lists, as on the first run or after a format change, counts every
+// entry as added.
+fn deny_list_delta(package: &[u8], deny_list_format: DenyListFormat, deny_list: &HashSet<String>) -> DenyListDelta {
+    let deployed = match read_deployed_lists(package, deny_list_format) {
+        Ok((deployed, _)) => deployed,
+        Err(err) => {
+            println!("No deployed deny list to compare against ({}), counting every entry as added", err);
+            HashSet::new()
+        }
+    };
+
+    list_delta(&deployed, deny_list)
+}
+
+fn list_delta(previous: &HashSet<String>, current: &HashSet<String>) -> DenyListDelta {
+    let mut added: Vec<String> = current.difference(previous).cloned().collect();
+    let mut removed: Vec<String> = previous.difference(current).cloned().collect();
+
+    added.sort_unstable();
+    removed.sort_unstable();
+
+    DenyListDelta { added, removed }
+}
+
+fn delta_record(built_at: DateTime<Utc>, delta: &DenyListDelta) -> Result<Vec<u8>, Error> {
+    Ok(serde_json::to_vec_pretty(&serde_json::json!({
+        "built_at": built_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "added_count": delta.added.len(),
+        "removed_count": delta.removed.len(),
+        "added": delta.added,
+        "removed": delta.removed
+    }))?)
+}
+
+// Reads the unexpired domains of a plain list file from the package
+fn read_deployed_hosts(reader: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<HashSet<String>, Error> {
+    let mut contents = String::new();
+    reader.by_name(name)?.read_to_string(&mut contents)?;
+
+    let now = Utc::now();
+
+    Ok(contents
+        .lines()
+        .map(parse_entry)
+        .filter(|(domain, expiry)| !domain.is_empty() && expiry.map(|expiry| expiry > now).unwrap_or(true))
+        .map(|(domain, _)| domain.to_string())
+        .collect())
+}
+
+// Merges every source into one list, fetched concurrently
+async fn get_deny_list(sources: &[ListSource]) -> Result<DenyList, Error> {
+    let downloads = join_all(sources.iter().map(|source| async move {
+        (source, download_source(source).await)
+    })).await;
+
+    let mut deny_list = DenyList::default();
+    let now = Utc::now();
+    let mut expired = 0;
+
+    for (source, download) in downloads {
+        let (last_modified, bytes) = match skip_optional_failure(source, download)? {
+            Some(download) => download,
+            None => continue
+        };
+        let text = std::str::from_utf8(&bytes)?;
+        let entries_before = deny_list.domains.len();
+
+        for line in text.lines() {
+            let (entry, expiry) = parse_entry(line);
+
+            let domain = match list_entry(entry, source.format) {
+                Some(domain) => domain,
+                None => continue
+            };
+
+            // A bad upstream entry like `co.uk` must never take out every
+            // name registered under a public suffix
+            if is_public_suffix(domain) {
+                println!("Rejected deny list entry '{}', which is a public suffix", domain);
+                continue;
+            }
+
+            match expiry {
+                // Prune expired entries rather than shipping them to the
+                // responder
+                Some(expiry) if expiry <= now => expired += 1,
+                Some(expiry) => {
+                    deny_list.expiries.insert(domain.to_string(), expiry);
+                    deny_list.domains.insert(domain.to_string());
+                },
+                None => {
+                    deny_list.domains.insert(domain.to_string());
+                }
+            };
+        }
+
+        println!("Downloaded deny list from {}, adding {} new entries", source.url, deny_list.domains.len() - entries_before);
+
+        deny_list.sources.push((source.url.clone(), last_modified));
+    }
+
+    if expired > 0 {
+        println!("Pruned {} expired deny list entries", expired);
+    }
+
+    // Never deploy an empty list because every source happened to be down
+    if deny_list.sources.is_empty() {
+        return Err("Failed to download any deny list source".into());
     }
 
     Ok(deny_list)
 }
 
-async fn get_allow_list() -> Result<HashSet<String>, Error> {
-    const ALLOW_LIST_URL: &'static str = "https://raw.githubusercontent.com/NChaves/pi-hole/main/adBlockListGetAdmiral_ABP.txt";
+// Logs every name on both the deny list and an allow list, with the sources
+// it came from, and settles it per the policy. Returns the names, sorted.
+fn resolve_conflicts(policy: ConflictPolicy, deny_list: &mut DenyList, allow_lists: &mut [SourceList]) -> Vec<String> {
+    let mut conflicts: Vec<String> = allow_lists
+        .iter()
+        .flat_map(|allow_list| allow_list.domains.intersection(&deny_list.domains).cloned())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+    conflicts.sort();
 
-    let bytes = reqwest::get(ALLOW_LIST_URL).await?
-        .bytes().await?;
+    let resolution = match policy {
+        ConflictPolicy::AllowWins => "dropping it from the deny list",
+        ConflictPolicy::DenyWins => "dropping it from the allow lists",
+        ConflictPolicy::LogOnly => "keeping it on both"
+    };
 
-    let hosts = std::str::from_utf8(&bytes)?;
+    for domain in &conflicts {
+        let sources: Vec<&str> = allow_lists
+            .iter()
+            .filter(|allow_list| allow_list.domains.contains(domain))
+            .map(|allow_list| allow_list.source.as_str())
+            .collect();
 
-    let simplify_re = Regex::new(r"(?m)^\|\|(.*)\^$").unwrap();
+        println!("Domain '{}' is on the deny list ({}) and allow lists ({}), {}", domain, deny_list.source_label(), sources.join(", "), resolution);
 
-    let mut allow_list = HashSet::new();
+        match policy {
+            ConflictPolicy::AllowWins => {
+                deny_list.domains.remove(domain);
+                deny_list.expiries.remove(domain);
+            },
+            ConflictPolicy::DenyWins => {
+                for allow_list in allow_lists.iter_mut() {
+                    allow_list.domains.remove(domain);
+                }
+            },
+            ConflictPolicy::LogOnly => {}
+        };
+    }
 
-    // Manually add allow-listed domains
-    // adsafeprotected.com is used on eater.com
-    allow_list.insert("static.adsafeprotected.com".to_string());
+    if !conflicts.is_empty() {
+        println!("Found {} deny/allow list conflicts ({:?})", conflicts.len(), policy);
+    }
+
+    conflicts
+}
+
+// Only suffixes on the Public Suffix List count, not unknown single labels
+// like `localhost`
+fn is_public_suffix(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    match psl::suffix(domain.as_bytes()) {
+        Some(suffix) => suffix.is_known() && suffix.as_bytes() == domain.as_bytes(),
+        None => false
+    }
+}
+
+// The manual allow list is always included
+async fn get_allow_lists(sources: &[ListSource]) -> Result<Vec<SourceList>, Error> {
+    let mut allow_lists = vec![SourceList {
+        source: MANUAL_ALLOW_LIST_SOURCE.to_string(),
+        domains: MANUAL_ALLOW_LIST.iter().map(|domain| domain.to_string()).collect(),
+        last_modified: None
+    }];
+
+    for (source, allow_list) in sources.iter().zip(join_all(sources.iter().map(get_source_list)).await) {
+        let allow_list = match skip_optional_failure(source, allow_list)? {
+            Some(allow_list) => allow_list,
+            None => continue
+        };
+
+        println!("Downloaded {} allow list entries from {}", allow_list.domains.len(), allow_list.source);
+
+        allow_lists.push(allow_list);
+    }
 
-    for (_, [domain]) in simplify_re.captures_iter(hosts).map(|captures| captures.extract()) {
-        allow_list.insert(domain.to_string());
+    Ok(allow_lists)
+}
+
+async fn get_nrd_list(source: Option<&ListSource>) -> Result<Option<SourceList>, Error> {
+    let source = match source {
+        Some(source) => source,
+        None => return Ok(None)
+    };
+
+    let mut nrd_list = match skip_optional_failure(source, get_source_list(source).await)? {
+        Some(nrd_list) => nrd_list,
+        None => return Ok(None)
+    };
+
+    // Like deny list entries, suffixes must never be caught
+    nrd_list.domains.retain(|domain| !is_public_suffix(domain));
+
+    println!("Downloaded {} newly registered domains from {}", nrd_list.domains.len(), source.url);
+
+    Ok(Some(nrd_list))
+}
+
+async fn get_source_list(source: &ListSource) -> Result<SourceList, Error> {
+    let (last_modified, bytes) = download_source(source).await?;
+
+    Ok(SourceList {
+        source: source.url.clone(),
+        domains: parse_list(std::str::from_utf8(&bytes)?, source.format),
+        last_modified
+    })
+}
+
+// Returns the source's contents and when it was last modified
+async fn download_source(source: &ListSource) -> Result<(Option<DateTime<Utc>>, Bytes), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(source.timeout)
+        .build()?;
+
+    let response = client.get(&source.url).send().await?.error_for_status()?;
+    let last_modified = last_modified(&response);
+
+    Ok((last_modified, response.bytes().await?))
+}
+
+// Passes on a failed download of a required source, and drops one of an
+// optional source with a warning
+fn skip_optional_failure<T>(source: &ListSource, result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if !source.required => {
+            println!("Warning: skipping optional list source {}: {}", source.url, err);
+            Ok(None)
+        },
+        Err(err) => Err(format!("Failed to download required list source {}: {}", source.url, err).into())
     }
+}
+
+// None when the server doesn't say, as for raw GitHub content
+fn last_modified(response: &reqwest::Response) -> Option<DateTime<Utc>> {
+    let last_modified = response.headers().get(reqwest::header::LAST_MODIFIED)?.to_str().ok()?;
 
-    Ok(allow_list)
+    DateTime::parse_from_rfc2822(last_modified).ok().map(|time| time.with_timezone(&Utc))
 }
 
-fn update_code_package(package: Vec<u8>, deny_list: String) -> Result<Vec<u8>, Error> {
+fn parse_list(text: &str, format: ListFormat) -> HashSet<String> {
+    text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| list_entry(line, format))
+        .map(str::to_string)
+        .collect()
+}
+
+// The domain on a list line with any comment already removed, or None for
+// blank lines and lines that aren't entries
+fn list_entry(line: &str, format: ListFormat) -> Option<&str> {
+    let line = line.trim();
+
+    if line.is_empty() {
+        return None;
+    }
+
+    match format {
+        ListFormat::Abp => abp_rule_domain(line),
+        ListFormat::Domains => Some(line),
+        ListFormat::Hosts => hosts_entry_domain(line),
+        ListFormat::Auto => {
+            if line.starts_with("||") {
+                abp_rule_domain(line)
+            } else if line.contains(char::is_whitespace) {
+                hosts_entry_domain(line)
+            } else {
+                // Skips ABP comments and headers like `! Title` or
+                // `[Adblock Plus 2.0]`
+                Some(line).filter(|line| line.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '.' | '_')))
+            }
+        }
+    }
+}
+
+// Hosts files map their own names too, e.g. `127.0.0.1 localhost`, which must
+// not be blocked
+fn hosts_entry_domain(line: &str) -> Option<&str> {
+    let mut fields = line.split_whitespace();
+
+    fields.next()?.parse::<IpAddr>().ok()?;
+
+    let domain = fields.next()?;
+
+    if HOSTS_FILE_OWN_NAMES.contains(&domain) || domain.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+
+    Some(domain)
+}
+
+// The domain of an ABP `||domain^` rule, ignoring any `$...` options after
+// the separator, such as `||example.com^$third-party`
+fn abp_rule_domain(line: &str) -> Option<&str> {
+    let (domain, rest) = line.strip_prefix("||")?.split_once('^')?;
+
+    if domain.is_empty() || !(rest.is_empty() || rest.starts_with('$')) {
+        return None;
+    }
+
+    Some(domain)
+}
+
+// Builds the new package deterministically, so identical inputs yield
+// byte-identical zips: the files the updater doesn't generate are copied from
+// the deployed package as they are, then the generated ones are written with
+// fixed timestamps and permissions, each in name order. Generated files from
+// a previous DENY_LIST_FORMAT are dropped.
+fn update_code_package(package: Vec<u8>, mut list_files: Vec<(&str, ListContents)>) -> Result<Vec<u8>, Error> {
     let buffer = Cursor::new(package);
 
     let mut reader = zip::ZipArchive::new(buffer)?;
@@ -135,23 +724,583 @@ fn update_code_package(package: Vec<u8>, deny_list: String) -> Result<Vec<u8>, E
 
     let mut writer = zip::ZipWriter::new(buffer);
 
-    writer.raw_copy_file(reader.by_name("bootstrap")?)?;
+    // Fails early on a package that isn't the responder's
+    reader.by_name("bootstrap")?;
+
+    let mut kept_names: Vec<String> = reader
+        .file_names()
+        .filter(|name| !GENERATED_FILENAMES.contains(name))
+        .map(str::to_string)
+        .collect();
+    kept_names.sort();
+
+    for name in kept_names {
+        writer.raw_copy_file(reader.by_name(&name)?)?;
+    }
+
+    let options = zip::write::FileOptions::default()
+        .last_modified_time(zip::DateTime::default())
+        .unix_permissions(0o644);
 
-    writer.start_file("hosts", zip::write::FileOptions::default())?;
+    list_files.sort_by_key(|(name, _)| *name);
 
-    writer.write_all(deny_list.as_bytes())?;
+    for (list_file_name, list_file_contents) in list_files {
+        writer.start_file(list_file_name, options)?;
+
+        write_list_contents(&mut writer, list_file_contents)?;
+    }
 
     Ok(writer.finish()?.into_inner())
 }
 
-async fn upload_new_code_package(responder_function_name: &str, lambda_client: &aws_sdk_lambda::client::Client, package: Vec<u8>) -> Result<(), Error> {
-    lambda_client
-        .update_function_code()
-        .function_name(responder_function_name)
-        .zip_file(Blob::new(package))
-        .architectures(Architecture::Arm64)
-        .send()
-        .await?;
+// A SHA-256 digest over the names and contents of the package's list files,
+// read back out of the zip so it reflects what's actually deployed. The build
+// info and delta are left out, as they differ on every run. The database
+// records when entries were added, so only plain list files ever match.
+fn list_files_digest(package: &[u8]) -> Result<String, Error> {
+    let mut reader = zip::ZipArchive::new(Cursor::new(package))?;
+
+    let mut names: Vec<String> = reader
+        .file_names()
+        .filter(|name| !["bootstrap", BUILD_INFO_FILENAME, DENY_LIST_DELTA_FILENAME].contains(name))
+        .map(str::to_string)
+        .collect();
+    names.sort();
+
+    let mut hasher = Sha256::new();
+
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        std::io::copy(&mut reader.by_name(&name)?, &mut hasher)?;
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn write_list_contents(writer: &mut impl Write, contents: ListContents) -> Result<(), Error> {
+    let (domains, expiries) = match contents {
+        ListContents::Bytes(bytes) => return Ok(writer.write_all(&bytes)?),
+        ListContents::Domains { domains, expiries } => (domains, expiries)
+    };
+
+    let mut chunk = String::new();
+
+    for lines in domains.chunks(LIST_CHUNK_LINES) {
+        chunk.clear();
+
+        for domain in lines {
+            chunk.push_str(domain);
+            if let Some(expiry) = expiries.and_then(|expiries| expiries.get(*domain)) {
+                chunk.push_str(&format!(" #expires={}", expiry.to_rfc3339()));
+            }
+            chunk.push('\n');
+        }
+
+        writer.write_all(chunk.as_bytes())?;
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Returns the ARN of the updated function
+async fn upload_new_code_package(function: &dyn ResponderFunction, package: Vec<u8>) -> Result<String, Error> {
+    let mut attempt = 1;
+
+    loop {
+        match function.update_code(package.clone()).await? {
+            CodeUpdate::Updated(function_arn) => return Ok(function_arn),
+            CodeUpdate::Conflict if attempt < UPLOAD_ATTEMPTS => {
+                println!("Responder function is being updated (attempt {} of {}), waiting for it to settle", attempt, UPLOAD_ATTEMPTS);
+                wait_for_function_update(function).await?;
+                attempt += 1;
+            },
+            CodeUpdate::Conflict => return Err(format!("Responder function was still being updated after {} attempts", UPLOAD_ATTEMPTS).into())
+        };
+    }
+}
+
+// Waits until the function is active and its last update has finished
+async fn wait_for_function_update(function: &dyn ResponderFunction) -> Result<(), Error> {
+    let started = Instant::now();
+    let mut delay = FUNCTION_POLL_INITIAL_DELAY;
+
+    loop {
+        match function.update_status().await? {
+            (Some(State::Active), Some(LastUpdateStatus::Successful | LastUpdateStatus::Failed)) => return Ok(()),
+            (state, status) if started.elapsed() >= FUNCTION_SETTLE_TIMEOUT => {
+                return Err(format!("Responder function did not settle within {:?} (state {:?}, last update {:?})", FUNCTION_SETTLE_TIMEOUT, state, status).into());
+            },
+            _ => {}
+        };
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(FUNCTION_POLL_MAX_DELAY);
+    }
+}
+
+// Metadata about the deployed lists, shown on the responder function in the
+// console without having to download and parse the package
+fn deny_list_tags(built_at: DateTime<Utc>, deny_list: &DenyList, allow_lists: &[SourceList], delta: &DenyListDelta) -> Vec<(&'static str, String)> {
+    let mut sources: Vec<&str> = allow_lists
+        .iter()
+        .map(|allow_list| allow_list.source.as_str())
+        .chain(deny_list.sources.iter().map(|(source, _)| source.as_str()))
+        .collect();
+    sources.sort_unstable();
+
+    let sources_hash: String = Sha256::digest(sources.join("\n").as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    vec![
+        ("DenyListEntries", deny_list.domains.len().to_string()),
+        ("DenyListAdded", delta.added.len().to_string()),
+        ("DenyListRemoved", delta.removed.len().to_string()),
+        ("DenyListSourcesHash", sources_hash),
+        ("DenyListBuiltAt", built_at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ("DenyListUpdaterVersion", env!("CARGO_PKG_VERSION").to_string())
+    ]
+}
+
+// The build time makes every package unique, unlike the list files
+fn build_info(built_at: DateTime<Utc>, deny_list: &DenyList, nrd_list: Option<&SourceList>, allow_lists: &[SourceList]) -> Result<Vec<u8>, Error> {
+    let rfc3339 = |time: DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let sources: Vec<Value> = deny_list.sources
+        .iter()
+        .map(|(source, last_modified)| (source.as_str(), *last_modified))
+        .chain(nrd_list.into_iter().chain(allow_lists).map(|list| (list.source.as_str(), list.last_modified)))
+        .map(|(source, last_modified)| serde_json::json!({
+            "source": source,
+            "last_modified": last_modified.map(rfc3339)
+        }))
+        .collect();
+
+    Ok(serde_json::to_vec_pretty(&serde_json::json!({
+        "built_at": rfc3339(built_at),
+        "sources": sources
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt
+        },
+        net::TcpListener
+    };
+
+    use super::*;
+
+    fn domains(domains: &[&str]) -> HashSet<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    fn overlapping_lists() -> (DenyList, Vec<SourceList>) {
+        let deny_list = DenyList {
+            domains: domains(&["ads.example", "cdn.example", "shared.example"]),
+            expiries: HashMap::new(),
+            sources: vec![("https://deny.example/hosts".to_string(), None)]
+        };
+
+        let allow_lists = vec![
+            SourceList { source: "https://allow.example/a".to_string(), domains: domains(&["cdn.example", "ok.example"]), last_modified: None },
+            SourceList { source: "https://allow.example/b".to_string(), domains: domains(&["shared.example", "cdn.example"]), last_modified: None }
+        ];
+
+        (deny_list, allow_lists)
+    }
+
+    #[test]
+    fn allow_wins_drops_conflicts_from_the_deny_list() {
+        let (mut deny_list, mut allow_lists) = overlapping_lists();
+
+        let conflicts = resolve_conflicts(ConflictPolicy::AllowWins, &mut deny_list, &mut allow_lists);
+
+        assert_eq!(conflicts, ["cdn.example", "shared.example"]);
+        assert_eq!(deny_list.domains, domains(&["ads.example"]));
+        assert_eq!(allow_lists[0].domains, domains(&["cdn.example", "ok.example"]));
+        assert_eq!(allow_lists[1].domains, domains(&["shared.example", "cdn.example"]));
+    }
+
+    #[test]
+    fn deny_wins_drops_conflicts_from_the_allow_lists() {
+        let (mut deny_list, mut allow_lists) = overlapping_lists();
+
+        let conflicts = resolve_conflicts(ConflictPolicy::DenyWins, &mut deny_list, &mut allow_lists);
+
+        assert_eq!(conflicts, ["cdn.example", "shared.example"]);
+        assert_eq!(deny_list.domains, domains(&["ads.example", "cdn.example", "shared.example"]));
+        assert_eq!(allow_lists[0].domains, domains(&["ok.example"]));
+        assert!(allow_lists[1].domains.is_empty());
+    }
+
+    #[test]
+    fn log_only_keeps_both_lists() {
+        let (mut deny_list, mut allow_lists) = overlapping_lists();
+
+        let conflicts = resolve_conflicts(ConflictPolicy::LogOnly, &mut deny_list, &mut allow_lists);
+
+        assert_eq!(conflicts, ["cdn.example", "shared.example"]);
+        assert_eq!(deny_list.domains, domains(&["ads.example", "cdn.example", "shared.example"]));
+        assert_eq!(allow_lists[1].domains, domains(&["shared.example", "cdn.example"]));
+    }
+
+    #[test]
+    fn reads_entries_in_each_format() {
+        assert_eq!(list_entry("||ads.example^", ListFormat::Abp), Some("ads.example"));
+        assert_eq!(list_entry("ads.example", ListFormat::Abp), None);
+        assert_eq!(list_entry("  ads.example ", ListFormat::Domains), Some("ads.example"));
+        assert_eq!(list_entry("0.0.0.0 ads.example", ListFormat::Hosts), Some("ads.example"));
+        assert_eq!(list_entry("ads.example", ListFormat::Hosts), None);
+        assert_eq!(list_entry("   ", ListFormat::Domains), None);
+    }
+
+    #[test]
+    fn detects_the_format_of_each_line() {
+        assert_eq!(list_entry("||ads.example^", ListFormat::Auto), Some("ads.example"));
+        assert_eq!(list_entry("127.0.0.1\ttracker.example", ListFormat::Auto), Some("tracker.example"));
+        assert_eq!(list_entry("cdn.example", ListFormat::Auto), Some("cdn.example"));
+        assert_eq!(list_entry("! Title: Some list", ListFormat::Auto), None);
+        assert_eq!(list_entry("[Adblock Plus 2.0]", ListFormat::Auto), None);
+    }
+
+    #[test]
+    fn skips_hosts_file_own_names() {
+        assert_eq!(hosts_entry_domain("127.0.0.1 localhost"), None);
+        assert_eq!(hosts_entry_domain("::1 ip6-localhost"), None);
+        assert_eq!(hosts_entry_domain("0.0.0.0 0.0.0.0"), None);
+        assert_eq!(hosts_entry_domain("not-an-ip ads.example"), None);
+        assert_eq!(hosts_entry_domain("0.0.0.0"), None);
+    }
+
+    #[test]
+    fn parses_lists_without_comments() {
+        let list = parse_list("# A hosts file\n0.0.0.0 ads.example # ads\n0.0.0.0 tracker.example\n\n127.0.0.1 localhost\n", ListFormat::Hosts);
+
+        assert_eq!(list, domains(&["ads.example", "tracker.example"]));
+    }
+
+    #[test]
+    fn reads_abp_rules_with_options() {
+        assert_eq!(abp_rule_domain("||example.com^"), Some("example.com"));
+        assert_eq!(abp_rule_domain("||example.com^$third-party"), Some("example.com"));
+        assert_eq!(abp_rule_domain("||example.com^$script,domain=site.example"), Some("example.com"));
+        assert_eq!(abp_rule_domain("||example.com^/path"), None);
+        assert_eq!(abp_rule_domain("||example.com"), None);
+        assert_eq!(abp_rule_domain("||^"), None);
+        assert_eq!(abp_rule_domain("@@||example.com^"), None);
+    }
+
+    fn source(required: bool) -> ListSource {
+        ListSource { format: ListFormat::Auto, url: "https://lists.example/hosts".to_string(), timeout: Duration::from_secs(5), required }
+    }
+
+    #[test]
+    fn skips_failed_optional_sources() {
+        assert_eq!(skip_optional_failure(&source(false), Ok(1)).unwrap(), Some(1));
+        assert_eq!(skip_optional_failure::<u32>(&source(false), Err("timed out".into())).unwrap(), None);
+    }
+
+    #[test]
+    fn fails_on_failed_required_sources() {
+        let err = skip_optional_failure::<u32>(&source(true), Err("timed out".into())).unwrap_err();
+
+        assert!(err.to_string().contains("https://lists.example/hosts"));
+        assert_eq!(skip_optional_failure(&source(true), Ok(1)).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn computes_sorted_deltas() {
+        let delta = list_delta(&domains(&["b.example", "old.example", "a.example"]), &domains(&["new2.example", "a.example", "b.example", "new1.example"]));
+
+        assert_eq!(delta.added, vec!["new1.example", "new2.example"]);
+        assert_eq!(delta.removed, vec!["old.example"]);
+    }
+
+    #[test]
+    fn counts_every_entry_as_added_without_a_deployed_list() {
+        let delta = deny_list_delta(b"not a zip", DenyListFormat::Hosts, &domains(&["ads.example"]));
+
+        assert_eq!(delta.added, vec!["ads.example"]);
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn recognizes_public_suffixes() {
+        assert!(is_public_suffix("com"));
+        assert!(is_public_suffix("co.uk"));
+        assert!(is_public_suffix("CO.UK."));
+        assert!(is_public_suffix("github.io"));
+    }
+
+    #[test]
+    fn does_not_treat_registrable_or_unknown_names_as_suffixes() {
+        assert!(!is_public_suffix("example.com"));
+        assert!(!is_public_suffix("example.co.uk"));
+        assert!(!is_public_suffix("localhost"));
+        assert!(!is_public_suffix("lan"));
+    }
+
+    fn package() -> Vec<u8> {
+        package_with(&[])
+    }
+
+    // A deployed package holding `files` besides the bootstrap and a deny list
+    fn package_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+
+        writer.start_file("bootstrap", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(b"binary").unwrap();
+        writer.start_file("hosts", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(b"old.example\n").unwrap();
+
+        for (name, contents) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn read_entry(package: &[u8], name: &str) -> Option<String> {
+        let mut reader = zip::ZipArchive::new(Cursor::new(package)).unwrap();
+        let mut contents = String::new();
+
+        reader.by_name(name).ok()?.read_to_string(&mut contents).unwrap();
+
+        Some(contents)
+    }
+
+    #[test]
+    fn builds_identical_packages_from_identical_lists() {
+        let hosts = domains(&["ads.example", "tracker.example"]);
+        let mut sorted: Vec<&String> = hosts.iter().collect();
+        sorted.sort();
+
+        let build = |reversed: bool| {
+            let mut list_files = vec![
+                ("allow", ListContents::Bytes(b"ok.example\n".to_vec())),
+                ("hosts", ListContents::Domains { domains: sorted.clone(), expiries: None })
+            ];
+
+            if reversed {
+                list_files.reverse();
+            }
+
+            update_code_package(package(), list_files).unwrap()
+        };
+
+        let first = build(false);
+
+        assert_eq!(first, build(true));
+
+        let mut reader = zip::ZipArchive::new(Cursor::new(first.as_slice())).unwrap();
+        let mut contents = String::new();
+        reader.by_name("hosts").unwrap().read_to_string(&mut contents).unwrap();
+
+        assert_eq!(contents, "ads.example\ntracker.example\n");
+        assert!(reader.by_name("bootstrap").is_ok());
+    }
+
+    #[test]
+    fn digests_only_the_list_files() {
+        let build = |build_info: &[u8]| {
+            update_code_package(package(), vec![
+                ("hosts", ListContents::Bytes(b"ads.example\n".to_vec())),
+                (BUILD_INFO_FILENAME, ListContents::Bytes(build_info.to_vec()))
+            ]).unwrap()
+        };
+
+        let digest = list_files_digest(&build(b"{\"built_at\": \"2025-01-01T00:00:00Z\"}")).unwrap();
+
+        assert_eq!(digest.len(), 64);
+        assert_eq!(digest, list_files_digest(&build(b"{\"built_at\": \"2025-01-02T00:00:00Z\"}")).unwrap());
+
+        let changed = update_code_package(package(), vec![("hosts", ListContents::Bytes(b"tracker.example\n".to_vec()))]).unwrap();
+
+        assert_ne!(digest, list_files_digest(&changed).unwrap());
+    }
+
+    #[test]
+    fn keeps_files_it_does_not_generate() {
+        let deployed = package_with(&[
+            ("overrides", b"api.partner.example A 10.1.2.3\n"),
+            // From an earlier DENY_LIST_FORMAT
+            (LIST_DATABASE_FILENAME, b"stale")
+        ]);
+
+        let package = update_code_package(deployed, vec![("hosts", ListContents::Bytes(b"ads.example\n".to_vec()))]).unwrap();
+
+        assert_eq!(read_entry(&package, "overrides").as_deref(), Some("api.partner.example A 10.1.2.3\n"));
+        assert_eq!(read_entry(&package, "hosts").as_deref(), Some("ads.example\n"));
+        assert_eq!(read_entry(&package, "bootstrap").as_deref(), Some("binary"));
+        assert!(read_entry(&package, LIST_DATABASE_FILENAME).is_none());
+    }
+
+    #[test]
+    fn keeps_views_and_their_profiles() {
+        let deployed = package_with(&[
+            ("views", b"192.168.1.64/26 kids\n"),
+            ("view-profiles/kids/deny", b"games.example\n")
+        ]);
+
+        let package = update_code_package(deployed, vec![("hosts", ListContents::Bytes(b"ads.example\n".to_vec()))]).unwrap();
+
+        assert_eq!(read_entry(&package, "views").as_deref(), Some("192.168.1.64/26 kids\n"));
+        assert_eq!(read_entry(&package, "view-profiles/kids/deny").as_deref(), Some("games.example\n"));
+    }
+
+    #[test]
+    fn keeps_the_config_file() {
+        let deployed = package_with(&[("config.json", br#"{"stealth_blocking": true}"#)]);
+
+        let package = update_code_package(deployed, vec![("hosts", ListContents::Bytes(b"ads.example\n".to_vec()))]).unwrap();
+
+        assert_eq!(read_entry(&package, "config.json").as_deref(), Some(r#"{"stealth_blocking": true}"#));
+    }
+
+    // Serves `body` to every request, like a list source
+    async fn list_source(body: &'static str) -> ListSource {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        ListSource { format: ListFormat::Domains, url, timeout: Duration::from_secs(5), required: true }
+    }
+
+    fn config(action: Action, deny_list_source: ListSource) -> Config {
+        Config {
+            responder_function_name: "responder".to_string(),
+            action,
+            deny_list_format: DenyListFormat::Hosts,
+            conflict_policy: ConflictPolicy::AllowWins,
+            deny_list_sources: vec![deny_list_source],
+            allow_list_sources: Vec::new(),
+            nrd_list_source: None
+        }
+    }
+
+    // A responder function deployed with `package`, rejecting its first
+    // `conflicts` uploads as mid-update
+    struct FakeFunction {
+        package: Vec<u8>,
+        conflicts: Mutex<u32>,
+        uploads: Mutex<Vec<Vec<u8>>>,
+        tags: Mutex<Vec<(&'static str, String)>>
+    }
+
+    impl FakeFunction {
+        fn new(package: Vec<u8>, conflicts: u32) -> Self {
+            Self { package, conflicts: Mutex::new(conflicts), uploads: Mutex::new(Vec::new()), tags: Mutex::new(Vec::new()) }
+        }
+    }
+
+    impl ResponderFunction for FakeFunction {
+        fn code_package(&self) -> function::FunctionFuture<'_, Vec<u8>> {
+            Box::pin(async move { Ok(self.package.clone()) })
+        }
+
+        fn update_code(&self, package: Vec<u8>) -> function::FunctionFuture<'_, CodeUpdate> {
+            Box::pin(async move {
+                self.uploads.lock().unwrap().push(package);
+
+                let mut conflicts = self.conflicts.lock().unwrap();
+                match *conflicts {
+                    0 => Ok(CodeUpdate::Updated("arn:aws:lambda:us-east-1:123456789012:function:responder".to_string())),
+                    _ => {
+                        *conflicts -= 1;
+                        Ok(CodeUpdate::Conflict)
+                    }
+                }
+            })
+        }
+
+        fn update_status(&self) -> function::FunctionFuture<'_, (Option<State>, Option<LastUpdateStatus>)> {
+            Box::pin(async move { Ok((Some(State::Active), Some(LastUpdateStatus::Successful))) })
+        }
+
+        fn tag(&self, _: &str, tags: Vec<(&'static str, String)>) -> function::FunctionFuture<'_, ()> {
+            Box::pin(async move {
+                self.tags.lock().unwrap().extend(tags);
+                Ok(())
+            })
+        }
+    }
+
+    fn event() -> LambdaEvent<Value> {
+        LambdaEvent::new(Value::Null, lambda_runtime::Context::default())
+    }
+
+    #[tokio::test]
+    async fn verify_reports_drift_without_uploading() {
+        let deployed = package_with(&[("allow", b"static.adsafeprotected.com\n")]);
+
+        // The package's deny list only holds old.example
+        let function = FakeFunction::new(deployed, 0);
+
+        assert!(handler(&config(Action::Verify, list_source("old.example\nads.example\n").await), &function, event()).await.is_err());
+        handler(&config(Action::Verify, list_source("old.example\n").await), &function, event()).await.unwrap();
+
+        assert!(function.uploads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tags_the_function_with_deny_list_metadata() {
+        let function = FakeFunction::new(package_with(&[("allow", b"static.adsafeprotected.com\n")]), 0);
+
+        handler(&config(Action::Update, list_source("ads.example\ntracker.example\nold.example\n").await), &function, event()).await.unwrap();
+
+        let uploads = function.uploads.lock().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(read_entry(&uploads[0], "hosts").as_deref(), Some("ads.example\nold.example\ntracker.example\n"));
+
+        let tags: HashMap<&str, String> = function.tags.lock().unwrap().iter().cloned().collect();
+        assert_eq!(tags["DenyListEntries"], "3");
+        assert_eq!(tags["DenyListAdded"], "2");
+        assert_eq!(tags["DenyListRemoved"], "0");
+        assert_eq!(tags["DenyListSourcesHash"].len(), 64);
+        assert!(DateTime::parse_from_rfc3339(&tags["DenyListBuiltAt"]).is_ok());
+        assert_eq!(tags["DenyListUpdaterVersion"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn retries_uploads_while_the_function_is_updating() {
+        let function = FakeFunction::new(package(), 1);
+
+        handler(&config(Action::Update, list_source("ads.example\n").await), &function, event()).await.unwrap();
+
+        // Rejected once, then uploaded after the function settled
+        assert_eq!(function.uploads.lock().unwrap().len(), 2);
+        assert!(!function.tags.lock().unwrap().is_empty());
+    }
+}
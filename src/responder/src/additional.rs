@@ -0,0 +1,104 @@
+use trust_dns_proto::{
+    op::message::Message,
+    rr::{
+        Name,
+        RData,
+        Record,
+        RecordType
+    }
+};
+
+// Caps the additional section at `limit` records. Glue, addresses for the
+// names the answer and authority records point at (NS, MX, SRV targets), is
+// always kept, as clients would otherwise have to look them up separately.
+// The EDNS OPT record is kept by the message separately and is unaffected.
+// Records in `exempt`, e.g. the responder's own block explanations, are kept
+// like glue.
+pub fn trim_additionals(response: &mut Message, limit: usize, exempt: &[Record]) {
+    let targets: Vec<Name> = response
+        .answers()
+        .iter()
+        .chain(response.name_servers())
+        .filter_map(|record| match record.data() {
+            Some(RData::NS(name)) => Some(name.clone()),
+            Some(RData::MX(mx)) => Some(mx.exchange().clone()),
+            Some(RData::SRV(srv)) => Some(srv.target().clone()),
+            _ => None
+        })
+        .collect();
+
+    let mut kept = 0;
+    let additionals = response.take_additionals();
+    let total = additionals.len();
+
+    for record in additionals {
+        let glue = (matches!(record.record_type(), RecordType::A | RecordType::AAAA) && targets.contains(record.name()))
+            || exempt.contains(&record);
+
+        if glue || kept < limit {
+            kept += usize::from(!glue);
+            response.add_additional(record);
+        }
+    }
+
+    if response.additionals().len() < total {
+        println!("Trimmed additional section from {} to {} records", total, response.additionals().len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr
+    };
+
+    use trust_dns_proto::rr::rdata::TXT;
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn address(owner: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+    }
+
+    fn text(owner: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::TXT(TXT::new(vec!["text".to_string()])))
+    }
+
+    fn response() -> Message {
+        let mut response = Message::new();
+        response.add_answer(Record::from_rdata(name("example.com."), 300, RData::NS(name("ns1.example.com."))));
+        response.add_additional(address("ns1.example.com."));
+        response.add_additional(address("other.example."));
+        response.add_additional(text("example.com."));
+        response
+    }
+
+    #[test]
+    fn keeps_glue_beyond_the_limit() {
+        let mut response = response();
+        trim_additionals(&mut response, 0, &[]);
+
+        assert_eq!(response.additionals(), [address("ns1.example.com.")]);
+    }
+
+    #[test]
+    fn keeps_other_records_up_to_the_limit() {
+        let mut response = response();
+        trim_additionals(&mut response, 1, &[]);
+
+        assert_eq!(response.additionals(), [address("ns1.example.com."), address("other.example.")]);
+    }
+
+    #[test]
+    fn keeps_exempt_records_beyond_the_limit() {
+        let mut response = response();
+        trim_additionals(&mut response, 0, &[text("example.com.")]);
+
+        assert_eq!(response.additionals(), [address("ns1.example.com."), text("example.com.")]);
+    }
+}
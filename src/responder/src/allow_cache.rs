@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+// Names recently found not blocked thanks to an allow list, so hot
+// exceptions skip the full policy walk. Keyed by view as well, as views have
+// their own allow lists. Holds at most `capacity` names, evicting an
+// arbitrary one when full, and forgets names after `max_age` in case their
+// allow list entry has since expired.
+pub struct AllowCache {
+    entries: Mutex<HashMap<(Option<String>, String), Instant>>,
+    capacity: usize,
+    max_age: Duration
+}
+
+impl AllowCache {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self { entries: Mutex::new(HashMap::new()), capacity, max_age }
+    }
+
+    pub fn contains(&self, view: Option<&str>, name: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+
+        match entries.get(&(view.map(str::to_string), name.to_lowercase())) {
+            Some(inserted) => inserted.elapsed() < self.max_age,
+            None => false
+        }
+    }
+
+    pub fn insert(&self, view: Option<&str>, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (view.map(str::to_string), name.to_lowercase());
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let evicted = match entries.keys().next() {
+                Some(evicted) => evicted.clone(),
+                None => return
+            };
+            entries.remove(&evicted);
+        }
+
+        entries.insert(key, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn remembers_names_per_view() {
+        let cache = AllowCache::new(10, Duration::from_secs(60));
+
+        cache.insert(None, "Ok.Example");
+
+        assert!(cache.contains(None, "ok.example"));
+        assert!(!cache.contains(Some("kids"), "ok.example"));
+        assert!(!cache.contains(None, "other.example"));
+
+        cache.insert(Some("kids"), "ok.example");
+        assert!(cache.contains(Some("kids"), "ok.example"));
+    }
+
+    #[test]
+    fn forgets_names_after_max_age() {
+        let cache = AllowCache::new(10, Duration::from_millis(20));
+
+        cache.insert(None, "ok.example");
+        sleep(Duration::from_millis(30));
+
+        assert!(!cache.contains(None, "ok.example"));
+    }
+
+    #[test]
+    fn holds_at_most_capacity_names() {
+        let cache = AllowCache::new(2, Duration::from_secs(60));
+
+        cache.insert(None, "a.example");
+        cache.insert(None, "b.example");
+        // Reinserting a cached name doesn't evict another
+        cache.insert(None, "a.example");
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+
+        cache.insert(None, "c.example");
+
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(cache.contains(None, "c.example"));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+// Clients tracked at once. When full, clients whose window has passed are
+// forgotten, and new clients go untracked until there's room.
+const MAX_CLIENTS: usize = 10_000;
+
+struct Window {
+    started: Instant,
+    blocks: u32
+}
+
+// Counts blocked responses per client in fixed windows, flagging clients that
+// get more than `limit` in one, e.g. an app stuck retrying a blocked name
+pub struct BlockRateTracker {
+    windows: Mutex<HashMap<String, Window>>,
+    limit: u32,
+    window: Duration
+}
+
+impl BlockRateTracker {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { windows: Mutex::new(HashMap::new()), limit, window }
+    }
+
+    // Returns whether the client is now over the limit for its window, and
+    // logs when it first goes over
+    pub fn record_block(&self, client: &str) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() >= MAX_CLIENTS && !windows.contains_key(client) {
+            windows.retain(|_, window| window.started.elapsed() < self.window);
+
+            if windows.len() >= MAX_CLIENTS {
+                return false;
+            }
+        }
+
+        let window = windows.entry(client.to_string()).or_insert(Window { started: Instant::now(), blocks: 0 });
+
+        if window.started.elapsed() >= self.window {
+            window.started = Instant::now();
+            window.blocks = 0;
+        }
+
+        window.blocks += 1;
+
+        if window.blocks == self.limit + 1 {
+            println!("Client IP {} exceeded {} blocked responses in {:?}", client, self.limit, self.window);
+        }
+
+        window.blocks > self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn flags_clients_over_the_limit() {
+        let tracker = BlockRateTracker::new(2, Duration::from_secs(60));
+
+        assert!(!tracker.record_block("192.0.2.1"));
+        assert!(!tracker.record_block("192.0.2.1"));
+        assert!(tracker.record_block("192.0.2.1"));
+        assert!(tracker.record_block("192.0.2.1"));
+
+        assert!(!tracker.record_block("192.0.2.2"));
+    }
+
+    #[test]
+    fn starts_a_new_window_once_it_passes() {
+        let tracker = BlockRateTracker::new(1, Duration::from_millis(20));
+
+        tracker.record_block("192.0.2.1");
+        assert!(tracker.record_block("192.0.2.1"));
+
+        sleep(Duration::from_millis(30));
+        assert!(!tracker.record_block("192.0.2.1"));
+    }
+
+    #[test]
+    fn tracks_at_most_max_clients() {
+        let tracker = BlockRateTracker::new(0, Duration::from_secs(60));
+
+        for index in 0..MAX_CLIENTS {
+            tracker.record_block(&index.to_string());
+        }
+
+        // New clients go untracked until windows pass
+        assert!(!tracker.record_block("192.0.2.1"));
+        assert!(tracker.record_block("0"));
+        assert_eq!(tracker.windows.lock().unwrap().len(), MAX_CLIENTS);
+    }
+}
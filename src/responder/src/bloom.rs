@@ -0,0 +1,98 @@
+use std::hash::Hasher;
+
+use siphasher::sip128::{
+    Hasher128,
+    SipHasher13
+};
+
+// Bits per entry and hashes per entry for a false positive rate of about 1%
+const BITS_PER_ENTRY: f64 = 9.6;
+const HASHES_PER_ENTRY: u64 = 7;
+
+// A Bloom filter over domains. `may_contain` never returns false for an
+// inserted domain, but returns true for about 1% of others, so hits must be
+// confirmed against the exact set.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64
+}
+
+impl BloomFilter {
+    pub fn with_capacity(entries: usize) -> Self {
+        let num_bits = ((entries.max(1) as f64 * BITS_PER_ENTRY).ceil() as u64).max(64);
+
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits
+        }
+    }
+
+    // Takes the output of `hash`, so entries can be hashed before the filter
+    // is sized
+    pub fn insert_hash(&mut self, hash: (u64, u64)) {
+        for bit in bit_indexes(hash, self.num_bits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn may_contain(&self, domain: &str) -> bool {
+        bit_indexes(hash(domain), self.num_bits).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+// Double hashing (Kirsch and Mitzenmacher), deriving every index from one
+// 128 bit hash
+fn bit_indexes((h1, h2): (u64, u64), num_bits: u64) -> impl Iterator<Item = u64> {
+    (0..HASHES_PER_ENTRY).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+pub fn hash(domain: &str) -> (u64, u64) {
+    let mut hasher = SipHasher13::new();
+    hasher.write(domain.as_bytes());
+
+    let hash = hasher.finish128();
+    (hash.h1, hash.h2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(domains: &[String]) -> BloomFilter {
+        let mut filter = BloomFilter::with_capacity(domains.len());
+
+        for domain in domains {
+            filter.insert_hash(hash(domain));
+        }
+
+        filter
+    }
+
+    #[test]
+    fn contains_inserted_domains() {
+        let domains: Vec<String> = (0..10_000).map(|index| format!("ads{}.example", index)).collect();
+        let filter = filter(&domains);
+
+        assert!(domains.iter().all(|domain| filter.may_contain(domain)));
+    }
+
+    #[test]
+    fn has_about_the_expected_false_positive_rate() {
+        let domains: Vec<String> = (0..10_000).map(|index| format!("ads{}.example", index)).collect();
+        let filter = filter(&domains);
+
+        let false_positives = (0..10_000)
+            .filter(|index| filter.may_contain(&format!("cdn{}.example", index)))
+            .count();
+
+        // About 1%, with room for variance
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn handles_empty_filters() {
+        let filter = BloomFilter::with_capacity(0);
+
+        assert!(!filter.may_contain("example.com"));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::Path
+};
+
+use anyhow::{
+    Context,
+    Result
+};
+
+use chrono::{
+    DateTime,
+    Utc
+};
+
+use serde::Deserialize;
+
+// Written into the package by the deny list updater alongside the lists
+pub const BUILD_INFO_FILENAME: &str = "build-info.json";
+
+#[derive(Deserialize)]
+struct BuildInfoFile {
+    built_at: String,
+    sources: Vec<SourceInfoFile>
+}
+
+#[derive(Deserialize)]
+struct SourceInfoFile {
+    source: String,
+    last_modified: Option<String>
+}
+
+// When the deployed lists were built, and when each of their sources last
+// changed where known, so a stalled update pipeline can be spotted
+pub struct BuildInfo {
+    pub built_at: DateTime<Utc>,
+    pub sources: Vec<(String, Option<DateTime<Utc>>)>
+}
+
+impl BuildInfo {
+    // None when the package has no build info, as before the first update
+    pub fn load(path: &str) -> Result<Option<Self>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path))?;
+        let file: BuildInfoFile = serde_json::from_str(&contents).with_context(|| format!("Invalid build info in '{}'", path))?;
+
+        let sources = file.sources
+            .into_iter()
+            .map(|source| Ok((source.source, source.last_modified.as_deref().map(parse_time).transpose()?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self { built_at: parse_time(&file.built_at)?, sources }))
+    }
+
+    pub fn describe(&self) -> String {
+        let now = Utc::now();
+        let age = |time: DateTime<Utc>| (now - time).num_seconds().max(0);
+
+        let mut description = format!("Lists built at {} ({}s ago)\n", self.built_at.to_rfc3339(), age(self.built_at));
+
+        for (source, last_modified) in &self.sources {
+            match last_modified {
+                Some(last_modified) => description.push_str(&format!("Source {} last modified at {} ({}s ago)\n", source, last_modified.to_rfc3339(), age(*last_modified))),
+                None => description.push_str(&format!("Source {} last modified at an unknown time\n", source))
+            };
+        }
+
+        description
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)
+        .with_context(|| format!("Invalid timestamp '{}'", time))?
+        .with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn write(name: &str, contents: &str) -> String {
+        let dir = env::temp_dir().join(format!("build-info-tests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn loads_build_info() {
+        let path = write("valid", r#"{
+            "built_at": "2025-01-01T00:00:00Z",
+            "sources": [
+                {"source": "https://lists.example/hosts", "last_modified": "2024-12-31T12:00:00+01:00"},
+                {"source": "https://lists.example/allow", "last_modified": null}
+            ]
+        }"#);
+
+        let info = BuildInfo::load(&path).unwrap().unwrap();
+
+        assert_eq!(info.built_at.to_rfc3339(), "2025-01-01T00:00:00+00:00");
+        assert_eq!(info.sources.len(), 2);
+        assert_eq!(info.sources[0].1.map(|time| time.to_rfc3339()), Some("2024-12-31T11:00:00+00:00".to_string()));
+        assert!(info.sources[1].1.is_none());
+
+        let description = info.describe();
+
+        assert!(description.starts_with("Lists built at 2025-01-01T00:00:00+00:00 ("));
+        assert!(description.contains("Source https://lists.example/allow last modified at an unknown time"));
+    }
+
+    #[test]
+    fn treats_a_missing_file_as_no_build_info() {
+        assert!(BuildInfo::load("/nonexistent/build-info.json").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_build_info() {
+        assert!(BuildInfo::load(&write("json", "{")).is_err());
+        assert!(BuildInfo::load(&write("time", r#"{"built_at": "yesterday", "sources": []}"#)).is_err());
+        assert!(BuildInfo::load(&write("source_time", r#"{"built_at": "2025-01-01T00:00:00Z", "sources": [{"source": "a", "last_modified": "soon"}]}"#)).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+use futures_util::future::BoxFuture;
+
+use trust_dns_proto::rr::RecordType;
+
+use trust_dns_resolver::{
+    error::{
+        ResolveError,
+        ResolveErrorKind::NoRecordsFound
+    },
+    lookup::Lookup
+};
+
+use crate::upstream::UpstreamResolver;
+
+// Tracks whether an upstream failed recently, so it can be skipped in favour
+// of a healthy one until `cooldown` has passed, after which it's tried again
+pub struct CircuitBreaker {
+    failed_at: Mutex<Option<Instant>>,
+    cooldown: Duration
+}
+
+impl CircuitBreaker {
+    pub fn new(cooldown: Duration) -> Self {
+        Self { failed_at: Mutex::new(None), cooldown }
+    }
+
+    pub fn is_open(&self) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            Some(failed_at) => failed_at.elapsed() < self.cooldown,
+            None => false
+        }
+    }
+
+    pub fn record_failure(&self) {
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn record_success(&self) {
+        *self.failed_at.lock().unwrap() = None;
+    }
+}
+
+// Sends each query to the first upstream that hasn't failed within the
+// cooldown, moving on to the next when one fails. Upstreams cooling down are
+// only tried once the healthy ones have failed, so a partial outage costs one
+// failed query per upstream per cooldown rather than one per query. "No
+// records" answers count as successes.
+pub struct FailoverResolver {
+    upstreams: Vec<(Box<dyn UpstreamResolver>, CircuitBreaker)>
+}
+
+impl FailoverResolver {
+    pub fn new(upstreams: Vec<Box<dyn UpstreamResolver>>, cooldown: Duration) -> Self {
+        Self {
+            upstreams: upstreams
+                .into_iter()
+                .map(|upstream| (upstream, CircuitBreaker::new(cooldown)))
+                .collect()
+        }
+    }
+}
+
+impl UpstreamResolver for FailoverResolver {
+    fn lookup(&self, name: &str, query_type: RecordType) -> BoxFuture<'_, Result<Lookup, ResolveError>> {
+        let name = name.to_string();
+
+        Box::pin(async move {
+            let (healthy, cooling_down): (Vec<_>, Vec<_>) = self.upstreams
+                .iter()
+                .partition(|(_, breaker)| !breaker.is_open());
+
+            let mut last_error: ResolveError = "No upstream resolvers configured".into();
+
+            for (upstream, breaker) in healthy.into_iter().chain(cooling_down) {
+                match upstream.lookup(&name, query_type).await {
+                    Err(err) if !matches!(err.kind(), NoRecordsFound { .. }) => {
+                        breaker.record_failure();
+                        last_error = err;
+                    },
+                    results => {
+                        breaker.record_success();
+                        return results;
+                    }
+                }
+            }
+
+            Err(last_error)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{
+                AtomicBool,
+                AtomicUsize,
+                Ordering
+            },
+            Arc
+        },
+        thread::sleep
+    };
+
+    use trust_dns_proto::{
+        op::Query,
+        rr::{
+            Name,
+            RData,
+            Record
+        }
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Upstream {
+        queries: AtomicUsize,
+        failing: AtomicBool
+    }
+
+    struct FakeUpstream(Arc<Upstream>);
+
+    impl UpstreamResolver for FakeUpstream {
+        fn lookup(&self, name: &str, query_type: RecordType) -> BoxFuture<'_, Result<Lookup, ResolveError>> {
+            let name = Name::from_ascii(name).unwrap();
+
+            Box::pin(async move {
+                self.0.queries.fetch_add(1, Ordering::SeqCst);
+
+                match self.0.failing.load(Ordering::SeqCst) {
+                    true => Err("upstream unreachable".into()),
+                    false => {
+                        let record = Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+                        Ok(Lookup::new_with_max_ttl(Query::query(name, query_type), Arc::from(vec![record])))
+                    }
+                }
+            })
+        }
+    }
+
+    fn failover(cooldown: Duration) -> (FailoverResolver, Arc<Upstream>, Arc<Upstream>) {
+        let (first, second) = (Arc::new(Upstream::default()), Arc::new(Upstream::default()));
+        let resolver = FailoverResolver::new(vec![Box::new(FakeUpstream(first.clone())), Box::new(FakeUpstream(second.clone()))], cooldown);
+
+        (resolver, first, second)
+    }
+
+    fn queries(upstream: &Upstream) -> usize {
+        upstream.queries.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn starts_closed() {
+        assert!(!CircuitBreaker::new(Duration::from_secs(30)).is_open());
+    }
+
+    #[test]
+    fn opens_on_failure_until_success() {
+        let breaker = CircuitBreaker::new(Duration::from_secs(30));
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn closes_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(Duration::from_millis(20));
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        sleep(Duration::from_millis(30));
+        assert!(!breaker.is_open());
+    }
+
+    #[tokio::test]
+    async fn queries_the_first_healthy_upstream() {
+        let (resolver, first, second) = failover(Duration::from_secs(30));
+
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+
+        assert_eq!((queries(&first), queries(&second)), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn skips_a_failed_upstream_during_its_cooldown() {
+        let (resolver, first, second) = failover(Duration::from_millis(50));
+        first.failing.store(true, Ordering::SeqCst);
+
+        // The failure is answered by the next upstream
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+        assert_eq!((queries(&first), queries(&second)), (1, 1));
+
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+        assert_eq!((queries(&first), queries(&second)), (1, 2));
+
+        // Retried once its cooldown has passed
+        first.failing.store(false, Ordering::SeqCst);
+        sleep(Duration::from_millis(60));
+
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+        assert_eq!((queries(&first), queries(&second)), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn tries_upstreams_cooling_down_when_the_rest_fail() {
+        let (resolver, first, second) = failover(Duration::from_secs(30));
+        first.failing.store(true, Ordering::SeqCst);
+        second.failing.store(true, Ordering::SeqCst);
+
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_err());
+
+        second.failing.store(false, Ordering::SeqCst);
+
+        assert!(resolver.lookup("example.com", RecordType::A).await.is_ok());
+        assert_eq!((queries(&first), queries(&second)), (2, 2));
+    }
+}
@@ -0,0 +1,107 @@
+use trust_dns_proto::rr::{
+    Name,
+    RData,
+    Record
+};
+
+// Follows the CNAME chain starting at `name` through the answer records,
+// returning the successive targets in order. Stops at the first name without a
+// CNAME, or when the chain loops back on itself.
+pub fn cname_chain(name: &Name, records: &[Record]) -> Vec<Name> {
+    let mut chain: Vec<Name> = Vec::new();
+    let mut current = name;
+
+    while let Some(target) = records.iter().find_map(|record| match record.data() {
+        Some(RData::CNAME(target)) if record.name() == current => Some(target),
+        _ => None
+    }) {
+        if target == name || chain.contains(target) {
+            break;
+        }
+
+        chain.push(target.clone());
+        current = target;
+    }
+
+    chain
+}
+
+// Whether every answer record is owned by `name` or a name on its CNAME chain.
+// Records that don't connect to the question indicate a malformed or
+// poisoned upstream response.
+pub fn answers_chain_from(name: &Name, records: &[Record]) -> bool {
+    let chain = cname_chain(name, records);
+
+    records
+        .iter()
+        .all(|record| record.name() == name || chain.contains(record.name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr
+    };
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn cname(owner: &str, target: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::CNAME(name(target)))
+    }
+
+    fn a(owner: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+    }
+
+    #[test]
+    fn follows_the_chain() {
+        let records = vec![
+            cname("www.example.com.", "cdn.example.net."),
+            cname("cdn.example.net.", "edge.tracker.example."),
+            a("edge.tracker.example.")
+        ];
+
+        assert_eq!(cname_chain(&name("www.example.com."), &records), vec![name("cdn.example.net."), name("edge.tracker.example.")]);
+        assert!(answers_chain_from(&name("www.example.com."), &records));
+    }
+
+    #[test]
+    fn finds_no_chain_without_cnames() {
+        assert!(cname_chain(&name("example.com."), &[a("example.com.")]).is_empty());
+        assert!(answers_chain_from(&name("example.com."), &[a("example.com.")]));
+    }
+
+    #[test]
+    fn stops_at_loops() {
+        let records = vec![
+            cname("a.example.", "b.example."),
+            cname("b.example.", "c.example."),
+            cname("c.example.", "a.example.")
+        ];
+
+        assert_eq!(cname_chain(&name("a.example."), &records), vec![name("b.example."), name("c.example.")]);
+
+        let records = vec![
+            cname("a.example.", "b.example."),
+            cname("b.example.", "b.example.")
+        ];
+
+        assert_eq!(cname_chain(&name("a.example."), &records), vec![name("b.example.")]);
+    }
+
+    #[test]
+    fn detects_unconnected_answers() {
+        let records = vec![
+            cname("www.example.com.", "cdn.example.net."),
+            a("cdn.example.net."),
+            a("bank.example.")
+        ];
+
+        assert!(!answers_chain_from(&name("www.example.com."), &records));
+    }
+}
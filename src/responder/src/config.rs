@@ -0,0 +1,952 @@
+use std::{
+    collections::HashMap,
+    env::{
+        self,
+        VarError
+    },
+    fs,
+    str::FromStr,
+    time::Duration
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use ipnet::{
+    IpNet,
+    Ipv6Net
+};
+
+use serde::Deserialize;
+
+use tokio::sync::Semaphore;
+
+use trust_dns_proto::rr::{
+    Name,
+    RecordType
+};
+
+use crate::{
+    cookies::CookieSecret,
+    dns64,
+    domain_set::{
+        DomainSet,
+        Matcher
+    },
+    overrides,
+    query_log::QueryLogSettings,
+    upstream::{
+        parse_search_domains,
+        IpVersion
+    }
+};
+
+// How to answer queries whose name is an IP address (e.g. "8.8.8.8"),
+// configured with IP_LITERAL_QUERIES
+#[derive(Debug, Clone, Copy)]
+pub enum IpLiteralHandling {
+    NXDomain,
+    Refused,
+    Forward
+}
+
+impl FromStr for IpLiteralHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "nxdomain" => Ok(IpLiteralHandling::NXDomain),
+            "refused" => Ok(IpLiteralHandling::Refused),
+            "forward" => Ok(IpLiteralHandling::Forward),
+            _ => Err(anyhow!("Unknown IP literal handling '{}', expected 'nxdomain', 'refused', or 'forward'", value))
+        }
+    }
+}
+
+// How to interpret POST bodies, configured with POST_BODY_ENCODING
+#[derive(Debug, Clone, Copy)]
+pub enum BodyEncoding {
+    // Accept both raw wire bytes and base64-encoded wire bytes
+    Auto,
+    // Only accept the body as already decoded by API Gateway
+    Raw,
+    // Always expect the body to carry base64-encoded wire bytes
+    Base64
+}
+
+impl FromStr for BodyEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(BodyEncoding::Auto),
+            "raw" => Ok(BodyEncoding::Raw),
+            "base64" => Ok(BodyEncoding::Base64),
+            _ => Err(anyhow!("Unknown body encoding '{}', expected 'auto', 'raw', or 'base64'", value))
+        }
+    }
+}
+
+// What to do with upstream answers containing records that don't chain from
+// the question name, configured with ANSWER_NAME_MISMATCH
+#[derive(Debug, Clone, Copy)]
+pub enum AnswerNameMismatchHandling {
+    Accept,
+    Log,
+    ServFail
+}
+
+impl FromStr for AnswerNameMismatchHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "accept" => Ok(AnswerNameMismatchHandling::Accept),
+            "log" => Ok(AnswerNameMismatchHandling::Log),
+            "servfail" => Ok(AnswerNameMismatchHandling::ServFail),
+            _ => Err(anyhow!("Unknown answer name mismatch handling '{}', expected 'accept', 'log', or 'servfail'", value))
+        }
+    }
+}
+
+// What to do with responses too large for a DNS message or to return over
+// HTTP, configured with OVERSIZED_RESPONSES
+#[derive(Debug, Clone, Copy)]
+pub enum OversizedResponseHandling {
+    // Drop the records and set TC, so the client retries over another transport
+    Truncate,
+    // Return SERVFAIL with an Extended DNS Error explaining why
+    ServFail
+}
+
+impl FromStr for OversizedResponseHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "truncate" => Ok(OversizedResponseHandling::Truncate),
+            "servfail" => Ok(OversizedResponseHandling::ServFail),
+            _ => Err(anyhow!("Unknown oversized response handling '{}', expected 'truncate' or 'servfail'", value))
+        }
+    }
+}
+
+// How to answer GET requests for the endpoint root without a DNS message,
+// configured with ROOT_RESPONSE
+#[derive(Debug, Clone, Copy)]
+pub enum RootResponse {
+    Landing,
+    NotFound
+}
+
+impl FromStr for RootResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "landing" => Ok(RootResponse::Landing),
+            "not-found" => Ok(RootResponse::NotFound),
+            _ => Err(anyhow!("Unknown root response '{}', expected 'landing' or 'not-found'", value))
+        }
+    }
+}
+
+// How to answer when no upstream resolver can be reached, configured with
+// UPSTREAM_UNREACHABLE
+#[derive(Debug, Clone, Copy)]
+pub enum UpstreamUnreachableHandling {
+    ServFail,
+    // Answer from the last known good answer when there is one, otherwise
+    // SERVFAIL
+    ServeStale,
+    Refused
+}
+
+impl FromStr for UpstreamUnreachableHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "servfail" => Ok(UpstreamUnreachableHandling::ServFail),
+            "serve-stale" => Ok(UpstreamUnreachableHandling::ServeStale),
+            "refused" => Ok(UpstreamUnreachableHandling::Refused),
+            _ => Err(anyhow!("Unknown upstream unreachable handling '{}', expected 'servfail', 'serve-stale', or 'refused'", value))
+        }
+    }
+}
+
+// What to do with clients getting more than BLOCK_RATE_LIMIT blocked
+// responses per window, configured with BLOCK_RATE_ACTION
+#[derive(Debug, Clone, Copy)]
+pub enum BlockRateAction {
+    // Only log the client and count it in the metrics
+    Log,
+    // Also hold their blocked responses for BLOCK_RATE_DELAY
+    Delay,
+    // Also answer them with HTTP 429 until the window passes
+    Reject
+}
+
+impl FromStr for BlockRateAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "log" => Ok(BlockRateAction::Log),
+            "delay" => Ok(BlockRateAction::Delay),
+            "reject" => Ok(BlockRateAction::Reject),
+            _ => Err(anyhow!("Unknown block rate action '{}', expected 'log', 'delay', or 'reject'", value))
+        }
+    }
+}
+
+// When the deny and allow lists are loaded, configured with LIST_LOADING
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListLoading {
+    // On first use, by whichever request gets there first
+    Inline,
+    // In the background at startup, holding requests until done or out of
+    // time
+    Wait,
+    // In the background at startup, answering SERVFAIL until done
+    Fail
+}
+
+impl FromStr for ListLoading {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "inline" => Ok(ListLoading::Inline),
+            "wait" => Ok(ListLoading::Wait),
+            "fail" => Ok(ListLoading::Fail),
+            _ => Err(anyhow!("Unknown list loading '{}', expected 'inline', 'wait', or 'fail'", value))
+        }
+    }
+}
+
+// How blocked names are answered outside stealth mode, configured with
+// BLOCK_MODE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockMode {
+    NXDomain,
+    // A and AAAA queries get 0.0.0.0 and :: answers, which some clients retry
+    // less aggressively than NXDOMAIN. Other types still get NXDOMAIN.
+    Sinkhole
+}
+
+impl FromStr for BlockMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "nxdomain" => Ok(BlockMode::NXDomain),
+            "sinkhole" => Ok(BlockMode::Sinkhole),
+            _ => Err(anyhow!("Unknown block mode '{}', expected 'nxdomain' or 'sinkhole'", value))
+        }
+    }
+}
+
+// How names on the newly registered domains feed are answered, configured
+// with NRD_HANDLING
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NrdHandling {
+    // Like any other blocked name
+    Block,
+    // NODATA with an Extended DNS Error saying why, which breaks fewer
+    // legitimate new sites than NXDOMAIN
+    NoData
+}
+
+impl FromStr for NrdHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "block" => Ok(NrdHandling::Block),
+            "nodata" => Ok(NrdHandling::NoData),
+            _ => Err(anyhow!("Unknown newly registered domain handling '{}', expected 'block' or 'nodata'", value))
+        }
+    }
+}
+
+// A value in the config file. Lists stand in for comma-separated env var
+// values.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    List(Vec<String>)
+}
+
+impl FileValue {
+    fn into_var(self) -> String {
+        match self {
+            FileValue::Bool(value) => value.to_string(),
+            FileValue::Number(value) => value.to_string(),
+            FileValue::String(value) => value,
+            FileValue::List(values) => values.join(",")
+        }
+    }
+}
+
+// Every setting is read from its env var, falling back to the JSON file at
+// CONFIG_FILE when set. The file holds an object keyed by the lowercase env
+// var names, e.g.:
+//
+//     {
+//         "stealth_blocking": true,
+//         "blocked_tlds": ["zip", "mov"],
+//         "negative_ttl": 30,
+//         "upstream_resolvers": "tls://1.1.1.1#cloudflare-dns.com"
+//     }
+//
+// A config.json next to the responder's Makefile is bundled with the function
+// and kept when the deny list updater repackages, so CONFIG_FILE=./config.json
+// points at it.
+#[derive(Default)]
+pub struct Settings {
+    env: HashMap<String, String>,
+    file: HashMap<String, String>
+}
+
+impl Settings {
+    pub fn load() -> Result<Self> {
+        // Variables that aren't valid Unicode are treated as unset
+        let env = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+
+        Self::from_env(env)
+    }
+
+    // From the given env vars rather than the process environment
+    pub fn from_env(env: HashMap<String, String>) -> Result<Self> {
+        let path = match env.get("CONFIG_FILE") {
+            Some(path) => path.clone(),
+            None => return Ok(Self { env, file: HashMap::new() })
+        };
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file '{}'", path))?;
+
+        let file: HashMap<String, Option<FileValue>> = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid config file '{}', expected an object of settings", path))?;
+
+        println!("Loaded {} settings from config file '{}'", file.len(), path);
+
+        Ok(Self {
+            env,
+            file: file
+                .into_iter()
+                .filter_map(|(name, value)| Some((name.to_uppercase(), value?.into_var())))
+                .collect()
+        })
+    }
+
+    // Like `env::var`, but falling back to the config file
+    pub fn var(&self, name: &str) -> Result<String, VarError> {
+        self.env
+            .get(name)
+            .or_else(|| self.file.get(name))
+            .cloned()
+            .ok_or(VarError::NotPresent)
+    }
+
+    fn string(&self, name: &str, default: &str) -> String {
+        self.var(name).unwrap_or(default.to_string())
+    }
+
+    fn list(&self, name: &str, default: &str) -> Vec<String> {
+        self.string(name, default)
+            .split(',')
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    fn flag(&self, name: &str, default: bool) -> Result<bool> {
+        match self.var(name).as_deref() {
+            Ok("true") => Ok(true),
+            Ok("false") => Ok(false),
+            Ok(value) => Err(anyhow!("{} must be 'true' or 'false', got '{}'", name, value)),
+            Err(_) => Ok(default)
+        }
+    }
+
+    fn parse<T>(&self, name: &str, expected: &str) -> Result<Option<T>>
+    where T: FromStr, {
+        self.var(name)
+            .ok()
+            .map(|value| value.parse().map_err(|_| anyhow!("{} must be {}, got '{}'", name, expected, value)))
+            .transpose()
+    }
+
+    // For values with their own parse errors, e.g. the handling enums
+    fn value<T>(&self, name: &str, default: &str) -> Result<T>
+    where T: FromStr<Err = anyhow::Error>, {
+        self.string(name, default)
+            .parse()
+            .with_context(|| format!("Invalid {}", name))
+    }
+
+    // A domain name, made fully qualified
+    fn name(&self, name: &str) -> Result<Option<Name>> {
+        self.var(name)
+            .ok()
+            .map(|value| {
+                let mut domain = Name::from_ascii(&value).with_context(|| format!("Invalid {}", name))?;
+                domain.set_fqdn(true);
+
+                Ok(domain)
+            })
+            .transpose()
+    }
+}
+
+// Every responder setting, read and validated once at startup
+pub struct Config {
+    // How the deny and allow lists are stored in memory
+    pub matcher_backend: Matcher,
+    // Put a Bloom filter in front of the deny list, so lookups for the vast
+    // majority of names, which aren't on it, skip the matcher
+    pub bloom_filter: bool,
+    pub list_loading: ListLoading,
+    // Records pinned for specific names, bypassing the denylist and upstream
+    pub overrides_file: String,
+    // Per-client policy profiles, selected by client network
+    pub views_file: String,
+    pub view_profiles_dir: String,
+
+    // Make blocked names indistinguishable from names without records when
+    // they exist upstream. This sends blocked queries upstream, if only once
+    // per cache lifetime.
+    pub stealth_blocking: bool,
+    // Add a TXT record to the additional section of blocked responses
+    // explaining the block, for tooling and curious users. The answer itself
+    // is unchanged. Never sent in stealth mode, which would give it away.
+    pub block_explain_txt: bool,
+    pub block_mode: BlockMode,
+    pub nrd_handling: NrdHandling,
+    // Never block the built-in list of essential infrastructure (OCSP and
+    // CRLs, ACME, time servers, OS updates), whatever the lists and views say.
+    // Turned off with ESSENTIAL_ALLOW_LIST=false.
+    pub essential_allow_list: bool,
+    // Block every name that isn't allow-listed, for locked-down deployments
+    pub default_deny: bool,
+    // Deny list entries also block every name under them, e.g. `ads.example`
+    // blocks `x.ads.example`, unless the name itself is allow-listed. On by
+    // default, as ad and tracker lists assume their entries cover the subtree.
+    pub suffix_matching: bool,
+    // Let blocked names through when their CNAME chain reaches an
+    // allow-listed name, so allow-listed CDN-fronted content isn't caught by
+    // a broad block
+    pub cname_uncloaking: bool,
+    // Longest CNAME chain answered or evaluated for uncloaking. Longer chains
+    // get SERVFAIL, as a guard against chains crafted to exhaust the walk.
+    pub max_cname_hops: usize,
+    // Where users can learn why a name was blocked and request an exception,
+    // sent in the Extended DNS Error of blocked responses
+    pub block_info_url: Option<String>,
+    // `blocked.<BLOCK_ZONE>`, answered to reverse lookups of the sinkhole
+    // addresses 0.0.0.0 and :: so tooling labels them as blocked
+    pub block_marker: Option<Name>,
+    // Further networks whose whole reverse space answers with the block
+    // marker, e.g. a block page subnet, in SINKHOLE_CIDRS as "192.0.2.0/24"
+    pub sinkhole_networks: Vec<IpNet>,
+    // Entire TLDs to block (e.g. "zip,mov"), unless a name is allow-listed
+    pub blocked_tlds: DomainSet,
+    // Names let through by an allow list are remembered so repeated queries
+    // for them skip the policy walk. Disabled with a size of 0.
+    pub allow_cache_size: usize,
+    pub allow_cache_max_age: Duration,
+    // Flag clients getting more than this many blocked responses per
+    // `block_rate_window`, such as apps stuck retrying a blocked name. Unset
+    // turns tracking off.
+    pub block_rate_limit: Option<u32>,
+    pub block_rate_window: Duration,
+    pub block_rate_action: BlockRateAction,
+    pub block_rate_delay: Duration,
+
+    // The only path DNS messages are answered on. Other paths get a 404,
+    // apart from the health check, debug endpoints, and the landing response.
+    pub doh_path: String,
+    // Query string parameter carrying GET messages, "dns" per RFC 8484
+    pub dns_query_param: String,
+    // Endpoints for inspecting the resolver's policy. These reveal list
+    // contents, so they're off unless ENABLE_DEBUG_ENDPOINTS is set. This
+    // also lets a `format` parameter of `json`, `pretty-json`, or `pretty`
+    // return readable answers from the DoH path.
+    pub debug_endpoints: bool,
+    // Report the age of the lists and their sources on the health check, from
+    // the build info the deny list updater ships with them
+    pub report_list_age: bool,
+    pub root_response: RootResponse,
+    pub post_body_encoding: BodyEncoding,
+    // Permits for the MAX_INFLIGHT concurrent requests handled by this
+    // instance. Requests beyond it get a 503 instead of queueing.
+    pub in_flight: Option<Semaphore>,
+    // Time reserved at the end of each invocation to return a ServFail when
+    // answering runs long, rather than being killed by the Lambda timeout
+    pub handler_timeout_margin: Duration,
+
+    // Answer every query with SERVFAIL during planned upstream maintenance.
+    // It's switched with the function's MAINTENANCE_MODE variable rather than
+    // an SSM parameter, since updating the environment replaces running
+    // instances on their next invocation anyway.
+    pub maintenance_mode: bool,
+    // HTTP cache lifetime for maintenance responses, kept short so clients
+    // retry soon after maintenance ends
+    pub maintenance_ttl: u32,
+
+    // Largest HTTP response API Gateway will pass back from Lambda, including
+    // the base64 encoding of the body. The default, Lambda's 6 MB, is more
+    // than any DNS message needs, leaving the 65535 byte DNS message limit to
+    // catch oversized responses unless this is set lower.
+    pub response_size_limit: usize,
+    // Pad responses to a multiple of this many bytes (RFC 8467) for clients
+    // that pad their queries, or every EDNS client with FORCE_PADDING
+    pub padding_block_size: usize,
+    pub force_padding: bool,
+    pub oversized_responses: OversizedResponseHandling,
+    // ANY queries can pull large responses for amplification, so they can be
+    // refused outright, and otherwise answered with at most
+    // `any_response_size_limit` bytes of records (RFC 8482 allows any subset)
+    pub allow_any: bool,
+    pub any_response_size_limit: usize,
+    // Answer FormErr to queries without an OPT record, for deployments that
+    // rely on EDNS options such as cookies or padding
+    pub require_edns: bool,
+    // Answer every question of messages with several, rather than only the
+    // first. Almost no client sends them, and few servers answer them.
+    pub answer_all_questions: bool,
+    // Answer FormErr to queries with the reserved Z header bit set, a sign of
+    // a malformed or probing client
+    pub reject_reserved_bits: bool,
+    // Cap on non-glue records in the additional section, 0 to strip them.
+    // Unset keeps everything.
+    pub additional_records_limit: Option<usize>,
+
+    pub answer_name_mismatch: AnswerNameMismatchHandling,
+    pub ip_literal_queries: IpLiteralHandling,
+    // Answer AAAA queries with NODATA for names that have A records, so
+    // clients on IPv4-only networks don't waste time trying IPv6
+    pub synth_nodata_aaaa: bool,
+    // DNS64 (RFC 6147), for IPv6-only clients behind NAT64: AAAA queries for
+    // names with only A records are answered with their addresses embedded
+    // in this prefix, set with DNS64_PREFIX when ENABLE_DNS64 is on
+    pub dns64_prefix: Option<Ipv6Net>,
+    // Rotate the order of multiple A/AAAA records per response, spreading
+    // client load across them. Off by default to preserve upstream ordering.
+    pub rotate_answers: bool,
+    // Clear AD in responses, as the resolver doesn't validate DNSSEC and so
+    // can't vouch for the data
+    pub strip_ad_flag: bool,
+    // Copy CD from the query into the response (RFC 6840 section 5.9). CD
+    // never exempts a name from blocking.
+    //
+    // Both only set the flags of responses. The resolver sends its own
+    // queries upstream, always with AD and CD clear, offering no way to
+    // forward the client's flags.
+    pub propagate_cd_flag: bool,
+    // Synthesize HTTPS records from A/AAAA answers for allowed names whose
+    // upstream answer has none, advertising these ALPN protocols
+    pub synthesize_https_records: bool,
+    pub synthesized_https_alpn: Vec<String>,
+    // How long clients may cache negative answers made up by the responder
+    pub negative_ttl: u32,
+    // MNAME and RNAME of the SOA records sent with negative answers
+    pub soa_mname: Option<Name>,
+    pub soa_rname: Option<Name>,
+    // DNS cookie (RFC 7873) handling, with a random secret per instance
+    // unless DNS_COOKIE_SECRET is set
+    pub dns_cookies: Option<CookieSecret>,
+    // Only answer queries carrying a valid server cookie, answering others
+    // with BADCOOKIE and a fresh server cookie to retry with
+    pub require_dns_cookies: bool,
+
+    // Specs as accepted by `upstream::parse_name_servers`
+    pub upstream_resolvers: Option<String>,
+    // Upstreams for particular query types, used instead of the default
+    // upstream and its fallback, in UPSTREAM_RESOLVERS_BY_TYPE as
+    // `;`-separated `<type>=<spec>` entries, e.g.
+    //
+    //     TXT=tls://9.9.9.9#dns.quad9.net;CAA=udp://8.8.8.8
+    pub upstream_resolvers_by_type: HashMap<RecordType, String>,
+    // Answer from the canned records in STUB_ANSWERS_FILE instead of any
+    // upstream, for deterministic tests without a network
+    pub test_mode: bool,
+    pub stub_answers_file: String,
+    // Plaintext resolvers to fall back to when the encrypted upstream fails.
+    // This leaks queries to the network, so it must be explicitly enabled.
+    pub allow_plaintext_fallback: bool,
+    pub plaintext_fallback_resolvers: Option<String>,
+    // After an upstream nameserver fails, send queries to the others for this
+    // long rather than waiting on it to fail again. Once every nameserver has
+    // failed, queries go straight to the fallback if there is one.
+    pub upstream_failure_cooldown: Option<Duration>,
+    pub upstream_ip_version: IpVersion,
+    pub search_domains: Vec<Name>,
+    pub ndots: Option<usize>,
+    pub upstream_timeout: Option<Duration>,
+    pub upstream_concurrent_requests: Option<usize>,
+    // Floor for how long upstream answers are retained in the resolver
+    // cache, so hot names with artificially low TTLs don't stampede the
+    // upstream
+    pub min_upstream_ttl: Option<u32>,
+    // Whether the MIN_UPSTREAM_TTL floor also applies to TTLs sent to clients
+    pub min_upstream_ttl_for_clients: bool,
+    // Upstream answers kept in memory, so warm instances don't re-resolve
+    // popular names. The least recently used go when it's full, and 0
+    // disables the cache.
+    pub response_cache_size: usize,
+    // DynamoDB table upstream answers are shared through across instances, so
+    // cold instances don't each resolve the same popular names
+    pub shared_cache_table: Option<String>,
+    pub upstream_unreachable: UpstreamUnreachableHandling,
+    // Names kept for serving stale answers
+    pub stale_cache_size: usize,
+
+    pub stats_log_every_requests: u64,
+    pub stats_log_every_seconds: u64,
+    // Serve the same counters, plus a latency histogram, for Prometheus to
+    // scrape at /metrics
+    pub metrics_endpoint: bool,
+    // Audit trail of queries, written in batches to QUERY_LOG_SINK when set
+    pub query_log: Option<QueryLogSettings>
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        Self::from_settings(&Settings::load()?)
+    }
+
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let dns_cookies = match settings.flag("ENABLE_DNS_COOKIES", false)? {
+            true => Some(CookieSecret::from_hex(settings.var("DNS_COOKIE_SECRET").ok().as_deref()).with_context(|| "Invalid DNS_COOKIE_SECRET")?),
+            false => None
+        };
+
+        let require_dns_cookies = settings.flag("REQUIRE_DNS_COOKIES", false)?;
+
+        if require_dns_cookies && dns_cookies.is_none() {
+            return Err(anyhow!("REQUIRE_DNS_COOKIES requires ENABLE_DNS_COOKIES"));
+        }
+
+        let dns64_prefix = match settings.flag("ENABLE_DNS64", false)? {
+            true => Some(dns64::parse_prefix(&settings.string("DNS64_PREFIX", "64:ff9b::/96")).with_context(|| "Invalid DNS64_PREFIX")?),
+            false => None
+        };
+
+        let synth_nodata_aaaa = settings.flag("SYNTH_NODATA_AAAA", false)?;
+
+        // One hides AAAA records that the other makes up
+        if dns64_prefix.is_some() && synth_nodata_aaaa {
+            return Err(anyhow!("ENABLE_DNS64 and SYNTH_NODATA_AAAA can't both be set"));
+        }
+
+        let block_marker = match settings.var("BLOCK_ZONE") {
+            Ok(zone) => {
+                let mut marker = Name::from_ascii(format!("blocked.{}", zone.trim_end_matches('.'))).with_context(|| "Invalid BLOCK_ZONE")?;
+                marker.set_fqdn(true);
+
+                Some(marker)
+            },
+            Err(_) => None
+        };
+
+        let sinkhole_networks = settings
+            .list("SINKHOLE_CIDRS", "")
+            .iter()
+            .map(|network| network.parse::<IpNet>().map(|network| network.trunc()).map_err(|_| anyhow!("Invalid SINKHOLE_CIDRS network '{}'", network)))
+            .collect::<Result<Vec<IpNet>>>()?;
+
+        if !sinkhole_networks.is_empty() && block_marker.is_none() {
+            return Err(anyhow!("SINKHOLE_CIDRS requires BLOCK_ZONE"));
+        }
+
+        let upstream_resolvers_by_type = settings
+            .string("UPSTREAM_RESOLVERS_BY_TYPE", "")
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (query_type, spec) = entry
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid UPSTREAM_RESOLVERS_BY_TYPE entry '{}', expected '<type>=<spec>'", entry))?;
+
+                let query_type: RecordType = query_type
+                    .trim()
+                    .to_uppercase()
+                    .parse()
+                    .map_err(|_| anyhow!("Unknown record type '{}' in UPSTREAM_RESOLVERS_BY_TYPE", query_type.trim()))?;
+
+                Ok((query_type, spec.trim().to_string()))
+            })
+            .collect::<Result<HashMap<RecordType, String>>>()?;
+
+        let query_log = match settings.var("QUERY_LOG_SINK") {
+            Ok(sink) => Some(QueryLogSettings {
+                sink: sink.parse().with_context(|| "Invalid QUERY_LOG_SINK")?,
+                hash_names: settings.flag("QUERY_LOG_HASH_NAMES", false)?,
+                hash_salt: settings.string("QUERY_LOG_HASH_SALT", ""),
+                log_client_ip: settings.flag("QUERY_LOG_CLIENT_IP", false)?,
+                batch_size: settings.parse("QUERY_LOG_BATCH_SIZE", "a number")?.unwrap_or(100),
+                max_batch_age: Duration::from_secs(settings.parse("QUERY_LOG_MAX_BATCH_AGE", "a number of seconds")?.unwrap_or(60))
+            }),
+            Err(_) => None
+        };
+
+        let doh_path = settings.string("DOH_PATH", "/dns-query");
+
+        // Overrides are read from the function package or S3 at init
+        let overrides_file = settings.string("OVERRIDES_FILE", "./overrides");
+        overrides::s3_location(&overrides_file)?;
+
+        Ok(Self {
+            matcher_backend: settings.value("MATCHER_BACKEND", "hashset")?,
+            bloom_filter: settings.flag("BLOOM_FILTER", false)?,
+            list_loading: settings.value("LIST_LOADING", "inline")?,
+            overrides_file,
+            views_file: settings.string("VIEWS_FILE", "./views"),
+            view_profiles_dir: settings.string("VIEW_PROFILES_DIR", "./view-profiles"),
+
+            stealth_blocking: settings.flag("STEALTH_BLOCKING", false)?,
+            block_explain_txt: settings.flag("BLOCK_EXPLAIN_TXT", false)?,
+            block_mode: settings.value("BLOCK_MODE", "nxdomain")?,
+            nrd_handling: settings.value("NRD_HANDLING", "nodata")?,
+            essential_allow_list: settings.flag("ESSENTIAL_ALLOW_LIST", true)?,
+            default_deny: settings.flag("DEFAULT_DENY", false)?,
+            suffix_matching: settings.flag("SUFFIX_MATCHING", true)?,
+            cname_uncloaking: settings.flag("CNAME_UNCLOAKING", false)?,
+            max_cname_hops: settings.parse("MAX_CNAME_HOPS", "a number")?.unwrap_or(8),
+            block_info_url: settings.var("BLOCK_INFO_URL").ok(),
+            block_marker,
+            sinkhole_networks,
+            blocked_tlds: settings
+                .list("BLOCKED_TLDS", "")
+                .iter()
+                .map(|tld| tld.trim_matches('.').to_lowercase())
+                .filter(|tld| !tld.is_empty())
+                .collect(),
+            allow_cache_size: settings.parse("ALLOW_CACHE_SIZE", "a number")?.unwrap_or(1000),
+            allow_cache_max_age: Duration::from_secs(settings.parse("ALLOW_CACHE_MAX_AGE", "a number of seconds")?.unwrap_or(300)),
+            block_rate_limit: settings.parse("BLOCK_RATE_LIMIT", "a number")?,
+            block_rate_window: Duration::from_secs(settings.parse("BLOCK_RATE_WINDOW", "a number of seconds")?.unwrap_or(60)),
+            block_rate_action: settings.value("BLOCK_RATE_ACTION", "log")?,
+            block_rate_delay: Duration::from_millis(settings.parse("BLOCK_RATE_DELAY_MS", "a number of milliseconds")?.unwrap_or(1000)),
+
+            doh_path: match doh_path.starts_with('/') {
+                true => doh_path,
+                false => format!("/{}", doh_path)
+            },
+            dns_query_param: settings.string("DNS_QUERY_PARAM", "dns"),
+            debug_endpoints: settings.flag("ENABLE_DEBUG_ENDPOINTS", false)?,
+            report_list_age: settings.flag("REPORT_LIST_AGE", false)?,
+            root_response: settings.value("ROOT_RESPONSE", "landing")?,
+            post_body_encoding: settings.value("POST_BODY_ENCODING", "auto")?,
+            in_flight: settings.parse("MAX_INFLIGHT", "a number")?.map(Semaphore::new),
+            handler_timeout_margin: Duration::from_millis(settings.parse("HANDLER_TIMEOUT_MARGIN_MS", "a number of milliseconds")?.unwrap_or(500)),
+
+            maintenance_mode: settings.flag("MAINTENANCE_MODE", false)?,
+            maintenance_ttl: settings.parse("MAINTENANCE_TTL", "a number of seconds")?.unwrap_or(5),
+
+            response_size_limit: settings.parse("RESPONSE_SIZE_LIMIT", "a number of bytes")?.unwrap_or(6 * 1024 * 1024),
+            padding_block_size: settings.parse("PADDING_BLOCK_SIZE", "a number of bytes")?.unwrap_or(468),
+            force_padding: settings.flag("FORCE_PADDING", false)?,
+            oversized_responses: settings.value("OVERSIZED_RESPONSES", "truncate")?,
+            allow_any: settings.flag("ALLOW_ANY", true)?,
+            require_edns: settings.flag("REQUIRE_EDNS", false)?,
+            answer_all_questions: settings.flag("ANSWER_ALL_QUESTIONS", false)?,
+            reject_reserved_bits: settings.flag("REJECT_RESERVED_BITS", false)?,
+            any_response_size_limit: settings.parse("ANY_RESPONSE_SIZE_LIMIT", "a number of bytes")?.unwrap_or(1232),
+            additional_records_limit: settings.parse("ADDITIONAL_RECORDS_LIMIT", "a number")?,
+
+            answer_name_mismatch: settings.value("ANSWER_NAME_MISMATCH", "log")?,
+            ip_literal_queries: settings.value("IP_LITERAL_QUERIES", "nxdomain")?,
+            synth_nodata_aaaa,
+            dns64_prefix,
+            rotate_answers: settings.flag("ROTATE_ANSWERS", false)?,
+            strip_ad_flag: settings.flag("STRIP_AD_FLAG", true)?,
+            propagate_cd_flag: settings.flag("PROPAGATE_CD_FLAG", true)?,
+            synthesize_https_records: settings.flag("SYNTHESIZE_HTTPS_RECORDS", false)?,
+            synthesized_https_alpn: settings.list("SYNTHESIZED_HTTPS_ALPN", "h3,h2"),
+            negative_ttl: settings.parse("NEGATIVE_TTL", "a number of seconds")?.unwrap_or(60),
+            soa_mname: settings.name("SOA_MNAME")?,
+            soa_rname: settings.name("SOA_RNAME")?,
+            dns_cookies,
+            require_dns_cookies,
+
+            upstream_resolvers: settings.var("UPSTREAM_RESOLVERS").ok(),
+            upstream_resolvers_by_type,
+            test_mode: settings.flag("TEST_MODE", false)?,
+            stub_answers_file: settings.string("STUB_ANSWERS_FILE", "./stub-answers"),
+            allow_plaintext_fallback: settings.flag("ALLOW_PLAINTEXT_FALLBACK", false)?,
+            plaintext_fallback_resolvers: settings.var("PLAINTEXT_FALLBACK_RESOLVERS").ok(),
+            upstream_failure_cooldown: settings
+                .parse("UPSTREAM_FAILURE_COOLDOWN", "a number of seconds")?
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            upstream_ip_version: settings.value("UPSTREAM_IP_VERSION", "both")?,
+            search_domains: parse_search_domains(&settings.string("SEARCH_DOMAINS", "")).with_context(|| "Invalid SEARCH_DOMAINS")?,
+            ndots: settings.parse("NDOTS", "a number")?,
+            upstream_timeout: settings.parse("UPSTREAM_TIMEOUT_MS", "a number of milliseconds")?.map(Duration::from_millis),
+            upstream_concurrent_requests: settings.parse("UPSTREAM_CONCURRENT_REQUESTS", "a number")?,
+            min_upstream_ttl: settings.parse("MIN_UPSTREAM_TTL", "a number of seconds")?,
+            response_cache_size: settings.parse("RESPONSE_CACHE_SIZE", "a number")?.unwrap_or(1000),
+            shared_cache_table: settings.var("SHARED_CACHE_TABLE").ok().filter(|table| !table.is_empty()),
+            min_upstream_ttl_for_clients: settings.flag("MIN_UPSTREAM_TTL_FOR_CLIENTS", false)?,
+            upstream_unreachable: settings.value("UPSTREAM_UNREACHABLE", "servfail")?,
+            stale_cache_size: settings.parse("STALE_CACHE_SIZE", "a number")?.unwrap_or(10000),
+
+            stats_log_every_requests: settings.parse("STATS_LOG_EVERY_REQUESTS", "a number")?.unwrap_or(1000),
+            stats_log_every_seconds: settings.parse("STATS_LOG_EVERY_SECONDS", "a number of seconds")?.unwrap_or(300),
+            metrics_endpoint: settings.flag("ENABLE_METRICS_ENDPOINT", false)?,
+            query_log
+        })
+    }
+}
+
+// Settings are given through the config file map rather than the environment,
+// which the handler tests in the same process configure
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(values: &[(&str, &str)]) -> Settings {
+        Settings {
+            env: HashMap::new(),
+            file: values.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+        }
+    }
+
+    fn config(values: &[(&str, &str)]) -> Result<Config> {
+        Config::from_settings(&settings(values))
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[]).unwrap();
+
+        assert!(matches!(config.matcher_backend, Matcher::HashSet));
+        assert!(!config.stealth_blocking);
+        assert!(config.suffix_matching);
+        assert!(config.strip_ad_flag);
+        assert!(config.propagate_cd_flag);
+        assert!(matches!(config.oversized_responses, OversizedResponseHandling::Truncate));
+        assert_eq!(config.doh_path, "/dns-query");
+        assert_eq!(config.overrides_file, "./overrides");
+        assert_eq!(config.maintenance_ttl, 5);
+        assert_eq!(config.negative_ttl, 60);
+        assert_eq!(config.synthesized_https_alpn, vec!["h3", "h2"]);
+        assert!(!config.blocked_tlds.contains_suffix_of("example.zip"));
+        assert!(config.dns_cookies.is_none());
+        assert!(config.query_log.is_none());
+    }
+
+    #[test]
+    fn parses_values() {
+        let config = config(&[
+            ("STEALTH_BLOCKING", "true"),
+            ("MATCHER_BACKEND", "fst"),
+            ("MAINTENANCE_TTL", "30"),
+            ("OVERSIZED_RESPONSES", "servfail"),
+            ("BLOCKED_TLDS", " .ZIP, mov,,"),
+            ("DOH_PATH", "resolve"),
+            ("SOA_MNAME", "ns.example"),
+            ("UPSTREAM_RESOLVERS_BY_TYPE", "txt = udp://192.0.2.1; aaaa=udp://192.0.2.2")
+        ]).unwrap();
+
+        assert!(config.stealth_blocking);
+        assert!(matches!(config.matcher_backend, Matcher::Fst));
+        assert_eq!(config.maintenance_ttl, 30);
+        assert!(matches!(config.oversized_responses, OversizedResponseHandling::ServFail));
+        assert!(config.blocked_tlds.contains("zip") && config.blocked_tlds.contains("mov"));
+        assert!(!config.blocked_tlds.contains_suffix_of("example.com"));
+        assert_eq!(config.doh_path, "/resolve");
+        assert_eq!(config.soa_mname, Some(Name::from_ascii("ns.example.").unwrap()));
+        assert_eq!(config.upstream_resolvers_by_type.get(&RecordType::TXT).map(String::as_str), Some("udp://192.0.2.1"));
+        assert_eq!(config.upstream_resolvers_by_type.get(&RecordType::AAAA).map(String::as_str), Some("udp://192.0.2.2"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(config(&[("STEALTH_BLOCKING", "yes")]).is_err());
+        assert!(config(&[("MAINTENANCE_TTL", "soon")]).is_err());
+        assert!(config(&[("MATCHER_BACKEND", "btree")]).is_err());
+        assert!(config(&[("OVERSIZED_RESPONSES", "drop")]).is_err());
+        assert!(config(&[("SINKHOLE_CIDRS", "not-a-network"), ("BLOCK_ZONE", "example")]).is_err());
+        assert!(config(&[("UPSTREAM_RESOLVERS_BY_TYPE", "udp://192.0.2.1")]).is_err());
+        assert!(config(&[("UPSTREAM_RESOLVERS_BY_TYPE", "NOPE=udp://192.0.2.1")]).is_err());
+        assert!(config(&[("QUERY_LOG_SINK", "ftp://logs")]).is_err());
+        assert!(config(&[("OVERRIDES_FILE", "s3://bucket/overrides")]).is_ok());
+        assert!(config(&[("OVERRIDES_FILE", "s3://bucket")]).is_err());
+    }
+
+    #[test]
+    fn rejects_conflicting_settings() {
+        assert!(config(&[("REQUIRE_DNS_COOKIES", "true")]).is_err());
+        assert!(config(&[("ENABLE_DNS64", "true"), ("SYNTH_NODATA_AAAA", "true")]).is_err());
+        assert!(config(&[("SINKHOLE_CIDRS", "192.0.2.0/24")]).is_err());
+
+        assert!(config(&[("ENABLE_DNS64", "true")]).is_ok());
+        assert!(config(&[("SINKHOLE_CIDRS", "192.0.2.0/24"), ("BLOCK_ZONE", "example")]).is_ok());
+    }
+
+    fn config_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("config-tests-{}-{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+
+        path.to_string_lossy().into_owned()
+    }
+
+    fn env(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn reads_the_config_file() {
+        let path = config_file("valid", r#"{
+            "stealth_blocking": true,
+            "blocked_tlds": ["zip", "mov"],
+            "negative_ttl": 30,
+            "maintenance_ttl": 20,
+            "soa_mname": null
+        }"#);
+
+        let settings = Settings::from_env(env(&[("CONFIG_FILE", &path), ("NEGATIVE_TTL", "10")])).unwrap();
+        let config = Config::from_settings(&settings).unwrap();
+
+        assert!(config.stealth_blocking);
+        assert!(config.blocked_tlds.contains("zip") && config.blocked_tlds.contains("mov"));
+        assert!(!config.blocked_tlds.contains_suffix_of("example.com"));
+        assert_eq!(config.maintenance_ttl, 20);
+        assert!(config.soa_mname.is_none());
+        // Env vars take precedence over the file
+        assert_eq!(config.negative_ttl, 10);
+    }
+
+    #[test]
+    fn rejects_unreadable_or_malformed_config_files() {
+        assert!(Settings::from_env(env(&[("CONFIG_FILE", "/nonexistent/config.json")])).is_err());
+        assert!(Settings::from_env(env(&[("CONFIG_FILE", &config_file("malformed", "{"))])).is_err());
+        assert!(Settings::from_env(env(&[("CONFIG_FILE", &config_file("list", "[true]"))])).is_err());
+    }
+
+    #[test]
+    fn config_file_errors_name_the_file() {
+        let path = config_file("named", "{\"negative_ttl\": ");
+        let err = Settings::from_env(env(&[("CONFIG_FILE", &path)])).err().unwrap();
+
+        assert!(format!("{:#}", err).contains(&format!("Invalid config file '{}'", path)));
+
+        let err = Settings::from_env(env(&[("CONFIG_FILE", "/nonexistent/config.json")])).err().unwrap();
+
+        assert!(format!("{:#}", err).contains("Failed to read config file '/nonexistent/config.json'"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    hash::Hasher,
+    time::{
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+use anyhow::{
+    anyhow,
+    Result
+};
+
+use siphasher::sip::SipHasher24;
+
+use trust_dns_proto::{
+    op::message::Message,
+    rr::rdata::opt::{
+        EdnsCode,
+        EdnsOption
+    }
+};
+
+// DNS Cookies (RFC 7873), with server cookies constructed per RFC 9018 so any
+// instance sharing the secret can verify them:
+//
+//     Version (1) | Reserved (3) | Timestamp (4) | Hash (8)
+//
+// Note the upstream side is not covered: the resolver doesn't allow attaching
+// EDNS options to the queries it sends.
+const COOKIE_OPTION_CODE: u16 = 10;
+const CLIENT_COOKIE_LENGTH: usize = 8;
+const SERVER_COOKIE_VERSION: u8 = 1;
+const SERVER_COOKIE_LENGTH: usize = 16;
+// How long a server cookie stays valid, and how far ahead of this instance's
+// clock another instance may have issued one (RFC 9018 section 4.3)
+const SERVER_COOKIE_LIFETIME_SECONDS: u32 = 60 * 60;
+const SERVER_COOKIE_CLOCK_SKEW_SECONDS: u32 = 5 * 60;
+
+pub struct CookieSecret([u8; 16]);
+
+impl CookieSecret {
+    // Secrets are 32 hex characters. When unset each instance uses its own
+    // random secret, so server cookies are only valid on the issuing instance.
+    pub fn from_hex(hex: Option<&str>) -> Result<Self> {
+        let hex = match hex {
+            Some(hex) => hex,
+            None => return Ok(Self(rand::random()))
+        };
+
+        if hex.len() != 32 || !hex.is_ascii() {
+            return Err(anyhow!("DNS cookie secret must be 32 hex characters"));
+        }
+
+        let mut secret = [0u8; 16];
+        for (index, byte) in secret.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)?;
+        }
+
+        Ok(Self(secret))
+    }
+}
+
+pub struct RequestCookie {
+    pub client: [u8; CLIENT_COOKIE_LENGTH],
+    pub server: Option<Vec<u8>>
+}
+
+// Returns the cookie sent with the request, if any. A malformed cookie is an
+// error, which must be answered with FORMERR.
+pub fn request_cookie(request: &Message) -> Result<Option<RequestCookie>> {
+    let data = match request.edns().and_then(|edns| edns.option(EdnsCode::Cookie)) {
+        Some(EdnsOption::Unknown(_, data)) => data,
+        _ => return Ok(None)
+    };
+
+    // Client cookie only, or client cookie plus an 8 to 32 byte server cookie
+    if data.len() != CLIENT_COOKIE_LENGTH && !(16..=40).contains(&data.len()) {
+        return Err(anyhow!("Malformed DNS cookie of length {}", data.len()));
+    }
+
+    let mut client = [0u8; CLIENT_COOKIE_LENGTH];
+    client.copy_from_slice(&data[..CLIENT_COOKIE_LENGTH]);
+
+    let server = match data.len() > CLIENT_COOKIE_LENGTH {
+        true => Some(data[CLIENT_COOKIE_LENGTH..].to_vec()),
+        false => None
+    };
+
+    Ok(Some(RequestCookie { client, server }))
+}
+
+pub fn server_cookie(secret: &CookieSecret, client: &[u8; CLIENT_COOKIE_LENGTH], client_ip: &str) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0);
+
+    server_cookie_at(secret, client, client_ip, timestamp)
+}
+
+// Whether `server` is a server cookie issued to this client, by any instance
+// sharing the secret, that hasn't expired yet
+pub fn is_valid_server_cookie(secret: &CookieSecret, client: &[u8; CLIENT_COOKIE_LENGTH], server: &[u8], client_ip: &str) -> bool {
+    if server.len() != SERVER_COOKIE_LENGTH || server[0] != SERVER_COOKIE_VERSION {
+        return false;
+    }
+
+    let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0);
+
+    if timestamp.saturating_add(SERVER_COOKIE_LIFETIME_SECONDS) < now || timestamp > now.saturating_add(SERVER_COOKIE_CLOCK_SKEW_SECONDS) {
+        return false;
+    }
+
+    server_cookie_at(secret, client, client_ip, timestamp) == server
+}
+
+fn server_cookie_at(secret: &CookieSecret, client: &[u8; CLIENT_COOKIE_LENGTH], client_ip: &str, timestamp: u32) -> Vec<u8> {
+    let mut cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+    cookie.extend_from_slice(&timestamp.to_be_bytes());
+
+    let mut hasher = SipHasher24::new_with_key(&secret.0);
+    hasher.write(client);
+    hasher.write(&cookie);
+    hasher.write(client_ip.as_bytes());
+
+    cookie.extend_from_slice(&hasher.finish().to_be_bytes());
+
+    cookie
+}
+
+// Adds the client cookie echoed back with a fresh server cookie
+pub fn set_cookie(response: &mut Message, client: &[u8; CLIENT_COOKIE_LENGTH], server: &[u8]) {
+    let mut data = client.to_vec();
+    data.extend_from_slice(server);
+
+    response.edns_mut().options_mut().insert(EdnsOption::Unknown(COOKIE_OPTION_CODE, data));
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::op::Edns;
+
+    use super::*;
+
+    const CLIENT: [u8; CLIENT_COOKIE_LENGTH] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn secret() -> CookieSecret {
+        CookieSecret::from_hex(Some("000102030405060708090a0b0c0d0e0f")).unwrap()
+    }
+
+    fn request(cookie: Option<Vec<u8>>) -> Message {
+        let mut request = Message::new();
+        let mut edns = Edns::new();
+
+        if let Some(cookie) = cookie {
+            edns.options_mut().insert(EdnsOption::Unknown(COOKIE_OPTION_CODE, cookie));
+        }
+
+        request.set_edns(edns);
+        request
+    }
+
+    #[test]
+    fn parses_secrets() {
+        assert_eq!(secret().0, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert!(CookieSecret::from_hex(None).is_ok());
+        assert!(CookieSecret::from_hex(Some("0001")).is_err());
+        assert!(CookieSecret::from_hex(Some("zz0102030405060708090a0b0c0d0e0f")).is_err());
+    }
+
+    #[test]
+    fn reads_request_cookies() {
+        assert!(request_cookie(&Message::new()).unwrap().is_none());
+        assert!(request_cookie(&request(None)).unwrap().is_none());
+
+        let cookie = request_cookie(&request(Some(CLIENT.to_vec()))).unwrap().unwrap();
+
+        assert_eq!(cookie.client, CLIENT);
+        assert!(cookie.server.is_none());
+
+        let mut data = CLIENT.to_vec();
+        data.extend_from_slice(&[9; 16]);
+
+        let cookie = request_cookie(&request(Some(data))).unwrap().unwrap();
+
+        assert_eq!(cookie.client, CLIENT);
+        assert_eq!(cookie.server, Some(vec![9; 16]));
+    }
+
+    #[test]
+    fn rejects_malformed_cookies() {
+        assert!(request_cookie(&request(Some(vec![1; 7]))).is_err());
+        assert!(request_cookie(&request(Some(vec![1; 12]))).is_err());
+        assert!(request_cookie(&request(Some(vec![1; 41]))).is_err());
+    }
+
+    #[test]
+    fn issues_server_cookies() {
+        let server = server_cookie(&secret(), &CLIENT, "192.0.2.1");
+
+        assert_eq!(server.len(), SERVER_COOKIE_LENGTH);
+        assert_eq!(server[..4], [SERVER_COOKIE_VERSION, 0, 0, 0]);
+        assert_eq!(server_cookie_at(&secret(), &CLIENT, "192.0.2.1", 1000), server_cookie_at(&secret(), &CLIENT, "192.0.2.1", 1000));
+        assert_ne!(server_cookie_at(&secret(), &CLIENT, "192.0.2.1", 1000), server_cookie_at(&secret(), &CLIENT, "192.0.2.2", 1000));
+    }
+
+    #[test]
+    fn echoes_cookies() {
+        let mut response = Message::new();
+        set_cookie(&mut response, &CLIENT, &[9; 16]);
+
+        let cookie = request_cookie(&response).unwrap().unwrap();
+
+        assert_eq!(cookie.client, CLIENT);
+        assert_eq!(cookie.server, Some(vec![9; 16]));
+    }
+
+    #[test]
+    fn validates_server_cookies() {
+        let server = server_cookie(&secret(), &CLIENT, "192.0.2.1");
+
+        assert!(is_valid_server_cookie(&secret(), &CLIENT, &server, "192.0.2.1"));
+        // Any instance sharing the secret accepts it
+        assert!(is_valid_server_cookie(&CookieSecret::from_hex(Some("000102030405060708090a0b0c0d0e0f")).unwrap(), &CLIENT, &server, "192.0.2.1"));
+
+        assert!(!is_valid_server_cookie(&secret(), &CLIENT, &server, "192.0.2.2"));
+        assert!(!is_valid_server_cookie(&secret(), &[8; CLIENT_COOKIE_LENGTH], &server, "192.0.2.1"));
+        assert!(!is_valid_server_cookie(&CookieSecret::from_hex(None).unwrap(), &CLIENT, &server, "192.0.2.1"));
+        assert!(!is_valid_server_cookie(&secret(), &CLIENT, &server[..8], "192.0.2.1"));
+    }
+
+    #[test]
+    fn rejects_expired_and_future_server_cookies() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+
+        let expired = server_cookie_at(&secret(), &CLIENT, "192.0.2.1", now - SERVER_COOKIE_LIFETIME_SECONDS - 10);
+        let skewed = server_cookie_at(&secret(), &CLIENT, "192.0.2.1", now + 60);
+        let future = server_cookie_at(&secret(), &CLIENT, "192.0.2.1", now + SERVER_COOKIE_CLOCK_SKEW_SECONDS + 10);
+
+        assert!(!is_valid_server_cookie(&secret(), &CLIENT, &expired, "192.0.2.1"));
+        assert!(is_valid_server_cookie(&secret(), &CLIENT, &skewed, "192.0.2.1"));
+        assert!(!is_valid_server_cookie(&secret(), &CLIENT, &future, "192.0.2.1"));
+    }
+}
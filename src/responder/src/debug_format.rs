@@ -0,0 +1,182 @@
+use std::{
+    fmt::Write,
+    str::FromStr
+};
+
+use anyhow::{
+    anyhow,
+    Error
+};
+use trust_dns_proto::{
+    op::Message,
+    rr::Record
+};
+
+// How a DNS response is sent back, chosen with the `format` query string
+// parameter when debug endpoints are enabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    // application/dns-message, as for any client
+    Wire,
+    // The JSON layout used by public resolvers' JSON APIs
+    Json,
+    PrettyJson,
+    // A dig style text dump
+    Pretty
+}
+
+impl FromStr for ResponseFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Error> {
+        match format {
+            "wire" => Ok(ResponseFormat::Wire),
+            "json" => Ok(ResponseFormat::Json),
+            "pretty-json" => Ok(ResponseFormat::PrettyJson),
+            "pretty" => Ok(ResponseFormat::Pretty),
+            _ => Err(anyhow!("Unknown format '{}', expected 'wire', 'json', 'pretty-json', or 'pretty'", format))
+        }
+    }
+}
+
+pub fn to_json(response: &Message) -> serde_json::Value {
+    let header = response.header();
+
+    serde_json::json!({
+        "Status": u16::from(response.response_code()),
+        "TC": header.truncated(),
+        "RD": header.recursion_desired(),
+        "RA": header.recursion_available(),
+        "AD": header.authentic_data(),
+        "CD": header.checking_disabled(),
+        "Question": response.queries().iter().map(|query| serde_json::json!({
+            "name": query.name().to_utf8(),
+            "type": u16::from(query.query_type())
+        })).collect::<Vec<_>>(),
+        "Answer": response.answers().iter().map(record_json).collect::<Vec<_>>(),
+        "Authority": response.name_servers().iter().map(record_json).collect::<Vec<_>>(),
+        "Additional": response.additionals().iter().map(record_json).collect::<Vec<_>>()
+    })
+}
+
+fn record_json(record: &Record) -> serde_json::Value {
+    serde_json::json!({
+        "name": record.name().to_utf8(),
+        "type": u16::from(record.record_type()),
+        "TTL": record.ttl(),
+        "data": record.data().map(|data| data.to_string()).unwrap_or_default()
+    })
+}
+
+// Laid out like dig's output, e.g.
+//
+//     ;; HEADER: 1234:RD:NoError:...
+//
+//     ;; QUESTION SECTION:
+//     ;example.com. IN A
+//
+//     ;; ANSWER SECTION:
+//     example.com. 300 IN A 93.184.216.34
+pub fn to_text(response: &Message) -> String {
+    let mut text = String::new();
+
+    // Writing to a String can't fail
+    let _ = writeln!(text, ";; HEADER: {}", response.header());
+
+    if let Some(edns) = response.edns() {
+        let _ = writeln!(text, ";; EDNS: version {}, max payload {}, DO {}", edns.version(), edns.max_payload(), edns.dnssec_ok());
+    }
+
+    let _ = writeln!(text, "\n;; QUESTION SECTION:");
+    for query in response.queries() {
+        let _ = writeln!(text, ";{} {} {}", query.name(), query.query_class(), query.query_type());
+    }
+
+    for (section, records) in [
+        ("ANSWER", response.answers()),
+        ("AUTHORITY", response.name_servers()),
+        ("ADDITIONAL", response.additionals())
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+
+        let _ = writeln!(text, "\n;; {} SECTION:", section);
+        for record in records {
+            let _ = writeln!(text, "{}", record);
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr
+    };
+
+    use trust_dns_proto::{
+        op::{
+            MessageType,
+            Query
+        },
+        rr::{
+            Name,
+            RData,
+            RecordType
+        }
+    };
+
+    use super::*;
+
+    fn response() -> Message {
+        let name = Name::from_str("example.com.").unwrap();
+
+        let mut response = Message::new();
+        response.set_id(1234);
+        response.set_message_type(MessageType::Response);
+        response.set_recursion_desired(true);
+        response.add_query(Query::query(name.clone(), RecordType::A));
+        response.add_answer(Record::from_rdata(name, 300, RData::A(Ipv4Addr::new(93, 184, 216, 34))));
+        response
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("wire".parse::<ResponseFormat>().unwrap(), ResponseFormat::Wire);
+        assert_eq!("json".parse::<ResponseFormat>().unwrap(), ResponseFormat::Json);
+        assert_eq!("pretty-json".parse::<ResponseFormat>().unwrap(), ResponseFormat::PrettyJson);
+        assert_eq!("pretty".parse::<ResponseFormat>().unwrap(), ResponseFormat::Pretty);
+        assert!("xml".parse::<ResponseFormat>().is_err());
+    }
+
+    #[test]
+    fn formats_json() {
+        assert_eq!(to_json(&response()), serde_json::json!({
+            "Status": 0,
+            "TC": false,
+            "RD": true,
+            "RA": false,
+            "AD": false,
+            "CD": false,
+            "Question": [{ "name": "example.com.", "type": 1 }],
+            "Answer": [{ "name": "example.com.", "type": 1, "TTL": 300, "data": "93.184.216.34" }],
+            "Authority": [],
+            "Additional": []
+        }));
+    }
+
+    #[test]
+    fn formats_text() {
+        let text = to_text(&response());
+
+        assert!(text.starts_with(";; HEADER: 1234:"));
+        assert!(text.contains("\n;; QUESTION SECTION:\n;example.com. IN A\n"));
+        assert!(text.contains("\n;; ANSWER SECTION:\nexample.com. 300 IN A 93.184.216.34\n"));
+        // Empty sections are left out
+        assert!(!text.contains("AUTHORITY"));
+        assert!(!text.contains("EDNS"));
+    }
+}
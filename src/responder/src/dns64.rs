@@ -0,0 +1,122 @@
+use std::net::{
+    Ipv4Addr,
+    Ipv6Addr
+};
+
+use anyhow::{
+    anyhow,
+    Result
+};
+
+use ipnet::Ipv6Net;
+
+use trust_dns_proto::rr::{
+    RData,
+    Record,
+    RecordType
+};
+
+// Prefix lengths RFC 6052 defines address embeddings for
+const PREFIX_LENGTHS: &[u8] = &[32, 40, 48, 56, 64, 96];
+
+pub fn parse_prefix(prefix: &str) -> Result<Ipv6Net> {
+    let prefix: Ipv6Net = prefix.parse().map_err(|_| anyhow!("'{}' is not an IPv6 prefix", prefix))?;
+
+    if !PREFIX_LENGTHS.contains(&prefix.prefix_len()) {
+        return Err(anyhow!("NAT64 prefix length must be one of 32, 40, 48, 56, 64, or 96, got {}", prefix.prefix_len()));
+    }
+
+    Ok(prefix.trunc())
+}
+
+// The IPv4 address embedded in the prefix (RFC 6052 section 2.2), skipping
+// bits 64 to 71, which must be zero
+pub fn embed(prefix: &Ipv6Net, address: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.network().octets();
+    let start = usize::from(prefix.prefix_len() / 8);
+
+    let mut index = start;
+    for byte in address.octets() {
+        if index == 8 {
+            index += 1;
+        }
+
+        octets[index] = byte;
+        index += 1;
+    }
+
+    Ipv6Addr::from(octets)
+}
+
+// Turns the answer to an A query into the answer to the AAAA query (RFC 6147
+// section 5.1.7), keeping any CNAMEs and replacing each A record with one for
+// its address embedded in the prefix
+pub fn synthesize_aaaa(a_records: &[Record], prefix: &Ipv6Net) -> Vec<Record> {
+    a_records
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::A(address)) => Some(Record::from_rdata(record.name().clone(), record.ttl(), RData::AAAA(embed(prefix, *address)))),
+            _ if record.record_type() == RecordType::CNAME => Some(record.clone()),
+            _ => None
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::rr::{
+        rdata::TXT,
+        Name
+    };
+
+    use super::*;
+
+    #[test]
+    fn parses_prefixes() {
+        assert_eq!(parse_prefix("64:ff9b::/96").unwrap(), "64:ff9b::/96".parse::<Ipv6Net>().unwrap());
+        assert_eq!(parse_prefix("2001:db8::1/32").unwrap(), "2001:db8::/32".parse::<Ipv6Net>().unwrap());
+        assert!(parse_prefix("2001:db8::/33").is_err());
+        assert!(parse_prefix("192.0.2.0/24").is_err());
+        assert!(parse_prefix("nat64").is_err());
+    }
+
+    // Examples from RFC 6052 section 2.4
+    #[test]
+    fn embeds_addresses() {
+        let address = Ipv4Addr::new(192, 0, 2, 33);
+        let examples = [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33")
+        ];
+
+        for (prefix, expected) in examples {
+            assert_eq!(embed(&parse_prefix(prefix).unwrap(), address), expected.parse::<Ipv6Addr>().unwrap(), "prefix {}", prefix);
+        }
+    }
+
+    #[test]
+    fn synthesizes_aaaa_records() {
+        let name = Name::from_str("www.example.").unwrap();
+        let target = Name::from_str("v4only.example.").unwrap();
+        let records = vec![
+            Record::from_rdata(name.clone(), 300, RData::CNAME(target.clone())),
+            Record::from_rdata(target.clone(), 60, RData::A(Ipv4Addr::new(192, 0, 2, 33))),
+            Record::from_rdata(target.clone(), 60, RData::TXT(TXT::new(vec!["ignored".to_string()])))
+        ];
+
+        let synthesized = synthesize_aaaa(&records, &parse_prefix("64:ff9b::/96").unwrap());
+
+        assert_eq!(synthesized.len(), 2);
+        assert_eq!(synthesized[0], records[0]);
+        assert_eq!(synthesized[1].name(), &target);
+        assert_eq!(synthesized[1].ttl(), 60);
+        assert_eq!(synthesized[1].data(), Some(&RData::AAAA("64:ff9b::192.0.2.33".parse().unwrap())));
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet
+    },
+    fs::{
+        self,
+        File
+    },
+    io::{
+        self,
+        BufRead
+    },
+    path::Path,
+    str::FromStr
+};
+
+use chrono::{
+    DateTime,
+    Utc
+};
+
+use common::list_entry::parse_entry;
+
+use fst::{
+    Set,
+    SetBuilder
+};
+
+use crate::{
+    bloom::{
+        self,
+        BloomFilter
+    },
+    label_trie::LabelTrie
+};
+
+// How a DomainSet stores its domains, configured with MATCHER_BACKEND
+#[derive(Debug, Clone, Copy)]
+pub enum Matcher {
+    HashSet,
+    // Labels shared between names under the same parent are stored once,
+    // though per-node overhead leaves it about the size of the HashSet
+    Trie,
+    // A finite state transducer, far more compact than a HashSet for large
+    // lists. Built straight from the file as it's read when the file is
+    // sorted, as written by the updater.
+    Fst
+}
+
+impl FromStr for Matcher {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "hashset" => Ok(Matcher::HashSet),
+            "trie" => Ok(Matcher::Trie),
+            "fst" => Ok(Matcher::Fst),
+            _ => Err(anyhow::anyhow!("Unknown matcher backend '{}', expected 'hashset', 'trie', or 'fst'", value))
+        }
+    }
+}
+
+enum Domains {
+    HashSet(HashSet<String>),
+    Trie(LabelTrie),
+    Fst(Set<Vec<u8>>)
+}
+
+impl Default for Domains {
+    fn default() -> Self {
+        Domains::HashSet(HashSet::new())
+    }
+}
+
+// A set of domains loaded from a file with one domain per line. Entries may
+// carry an expiry, after which they are ignored:
+//
+//     evil.com #expires=2025-01-01
+//
+// Expiries are either a date (expiring at the start of that day, UTC) or an
+// RFC 3339 timestamp.
+#[derive(Default)]
+pub struct DomainSet {
+    domains: Domains,
+    expiries: HashMap<String, DateTime<Utc>>,
+    // Consulted before the domains, so most misses skip the exact lookup
+    bloom: Option<BloomFilter>
+}
+
+// A set of domains given directly rather than loaded from a file, like the
+// BLOCKED_TLDS in the config
+impl FromIterator<String> for DomainSet {
+    fn from_iter<I>(domains: I) -> Self
+    where I: IntoIterator<Item = String>, {
+        Self { domains: Domains::HashSet(domains.into_iter().collect()), ..Self::default() }
+    }
+}
+
+impl DomainSet {
+    pub fn load<P>(path: P) -> Self
+    where P: AsRef<Path>, {
+        Self::load_with(path, Matcher::HashSet)
+    }
+
+    // Prefers a set the updater compiled ahead of time, written next to the
+    // file with an `.fst` extension, regardless of the matcher
+    pub fn load_with<P>(path: P, matcher: Matcher) -> Self
+    where P: AsRef<Path>, {
+        let path = path.as_ref();
+        let compiled_path = path.with_extension("fst");
+
+        if compiled_path.exists() {
+            match Self::load_compiled(path, &compiled_path) {
+                Ok(set) => return set,
+                Err(err) => println!("Failed to load compiled set '{}' ({}), loading '{}'", compiled_path.display(), err, path.display())
+            };
+        }
+
+        match matcher {
+            Matcher::HashSet => Self::load_hash_set(path),
+            Matcher::Trie => Self::load_trie(path),
+            Matcher::Fst => match Self::load_fst(path) {
+                Ok(set) => set,
+                Err(err) => {
+                    println!("Failed to stream '{}' into a matcher ({}), sorting it first", path.display(), err);
+                    Self::load_sorted_fst(path)
+                }
+            }
+        }
+    }
+
+    // Also builds a Bloom filter in front of the matcher, which is worth it
+    // for large sets where most lookups miss. Reads the file a second time.
+    pub fn load_with_bloom_filter<P>(path: P, matcher: Matcher) -> Self
+    where P: AsRef<Path>, {
+        let path = path.as_ref();
+        let mut set = Self::load_with(path, matcher);
+
+        // The plain file next to a compiled set only holds expiring entries
+        if path.with_extension("fst").exists() {
+            println!("Loaded compiled set for '{}', skipping Bloom filter", path.display());
+            return set;
+        }
+
+        let mut hashes = Vec::new();
+        if let Err(err) = for_each_entry(path, |domain| {
+            hashes.push(bloom::hash(domain));
+            Ok(())
+        }) {
+            println!("Failed to build Bloom filter for '{}' ({}), using the matcher alone", path.display(), err);
+            return set;
+        }
+
+        let mut filter = BloomFilter::with_capacity(hashes.len());
+        for hash in hashes {
+            filter.insert_hash(hash);
+        }
+
+        set.bloom = Some(filter);
+        set
+    }
+
+    fn load_hash_set(path: &Path) -> Self {
+        let mut domains = HashSet::new();
+
+        let expiries = for_each_entry(path, |domain| {
+            domains.insert(domain.to_string());
+            Ok(())
+        }).unwrap_or_default();
+
+        Self { domains: Domains::HashSet(domains), expiries, bloom: None }
+    }
+
+    fn load_trie(path: &Path) -> Self {
+        let mut domains = LabelTrie::default();
+
+        let expiries = for_each_entry(path, |domain| {
+            domains.insert(domain);
+            Ok(())
+        }).unwrap_or_default();
+
+        domains.shrink_to_fit();
+
+        Self { domains: Domains::Trie(domains), expiries, bloom: None }
+    }
+
+    // Only works for files sorted bytewise without duplicates
+    fn load_fst(path: &Path) -> fst::Result<Self> {
+        let mut builder = SetBuilder::memory();
+
+        let expiries = for_each_entry(path, |domain| builder.insert(domain))?;
+
+        Ok(Self { domains: Domains::Fst(builder.into_set()), expiries, bloom: None })
+    }
+
+    // The plain file only lists the entries that expire, which the compiled
+    // set includes even once they have
+    fn load_compiled(path: &Path, compiled_path: &Path) -> fst::Result<Self> {
+        let domains = Set::new(fs::read(compiled_path)?)?;
+
+        let expiries = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(parse_entry)
+                .filter_map(|(domain, expiry)| Some((domain.to_string(), expiry?)))
+                .collect(),
+            Err(_) => HashMap::new()
+        };
+
+        println!("Loaded compiled set of {} domains, {} expiring", domains.len(), expiries.len());
+
+        Ok(Self { domains: Domains::Fst(domains), expiries, bloom: None })
+    }
+
+    fn load_sorted_fst(path: &Path) -> Self {
+        let mut domains = Vec::new();
+
+        let expiries = for_each_entry(path, |domain| {
+            domains.push(domain.to_string());
+            Ok(())
+        }).unwrap_or_default();
+
+        domains.sort();
+        domains.dedup();
+
+        let domains = Set::from_iter(domains).expect("Sorted domains must build a matcher");
+
+        Self { domains: Domains::Fst(domains), expiries, bloom: None }
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(domain) {
+                return false;
+            }
+        }
+
+        let contained = match &self.domains {
+            Domains::HashSet(domains) => domains.contains(domain),
+            Domains::Trie(domains) => domains.contains(domain),
+            Domains::Fst(domains) => domains.contains(domain)
+        };
+
+        if !contained {
+            return false;
+        }
+
+        // Warm instances can outlive an entry's expiry
+        match self.expiries.get(domain) {
+            Some(expiry) => *expiry > Utc::now(),
+            None => true
+        }
+    }
+
+    // Whether the domain or any of its parent domains is in the set
+    pub fn contains_suffix_of(&self, domain: &str) -> bool {
+        parent_domains(domain).any(|candidate| self.contains(candidate))
+    }
+}
+
+// The domain itself followed by each of its parents, e.g. `a.b.com`, `b.com`,
+// `com`
+pub fn parent_domains(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |domain| domain.split_once('.').map(|(_, parent)| parent))
+}
+
+// Calls `insert` with each unexpired domain in the file, in file order, and
+// returns the expiries of the inserted domains. A missing file is empty.
+fn for_each_entry<F>(path: &Path, mut insert: F) -> fst::Result<HashMap<String, DateTime<Utc>>>
+where F: FnMut(&str) -> fst::Result<()>, {
+    let mut expiries = HashMap::new();
+
+    let lines = match File::open(path) {
+        Ok(file) => io::BufReader::new(file).lines(),
+        Err(_) => return Ok(expiries)
+    };
+
+    let now = Utc::now();
+    let mut expired = 0;
+
+    for line in lines.map_while(Result::ok) {
+        let (domain, expiry) = parse_entry(&line);
+
+        if domain.is_empty() {
+            continue;
+        }
+
+        match expiry {
+            Some(expiry) if expiry <= now => expired += 1,
+            Some(expiry) => {
+                insert(domain)?;
+                expiries.insert(domain.to_string(), expiry);
+            },
+            None => insert(domain)?
+        };
+    }
+
+    if expired > 0 {
+        println!("Skipped {} expired entries", expired);
+    }
+
+    Ok(expiries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(domains: &[&str]) -> DomainSet {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_exact_names_and_subdomains() {
+        let set = set(&["doubleclick.net"]);
+
+        assert!(set.contains_suffix_of("doubleclick.net"));
+        assert!(set.contains_suffix_of("stats.g.doubleclick.net"));
+    }
+
+    #[test]
+    fn does_not_match_siblings_or_parents() {
+        let set = set(&["ads.example.com"]);
+
+        assert!(!set.contains_suffix_of("cdn.example.com"));
+        assert!(!set.contains_suffix_of("example.com"));
+        assert!(!set.contains_suffix_of("badads.example.com"));
+    }
+
+    #[test]
+    fn matches_single_labels() {
+        let set = set(&["com"]);
+
+        assert!(!set.contains_suffix_of(""));
+        assert!(set.contains_suffix_of("com"));
+        assert!(set.contains_suffix_of("example.com"));
+        assert!(!set.contains_suffix_of("localhost"));
+    }
+
+    // Covers the HashSet, Trie, and Fst matchers
+    fn load_with_each_matcher(name: &str, contents: &str) -> Vec<DomainSet> {
+        let dir = std::env::temp_dir().join(format!("domain-set-tests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+
+        [Matcher::HashSet, Matcher::Trie, Matcher::Fst]
+            .into_iter()
+            .map(|matcher| DomainSet::load_with(&path, matcher))
+            .collect()
+    }
+
+    #[test]
+    fn matchers_agree() {
+        // Unsorted, so the Fst matcher falls back to sorting it
+        let sets = load_with_each_matcher("agree", "tracker.net\nads.example.com\n\nimg.example.com\ncom.example\nold.example #expires=2000-01-01\nnew.example #expires=2999-01-01\n");
+
+        let names = [
+            "ads.example.com",
+            "img.example.com",
+            "x.ads.example.com",
+            "example.com",
+            "com",
+            "tracker.net",
+            "b.tracker.net",
+            "tracker.network",
+            "com.example",
+            "old.example",
+            "new.example",
+            "sub.new.example",
+            ""
+        ];
+
+        for name in names {
+            let contains: Vec<bool> = sets.iter().map(|set| set.contains(name)).collect();
+            let contains_suffix: Vec<bool> = sets.iter().map(|set| set.contains_suffix_of(name)).collect();
+
+            assert!(contains.iter().all(|contained| *contained == contains[0]), "contains disagrees for '{}': {:?}", name, contains);
+            assert!(contains_suffix.iter().all(|contained| *contained == contains_suffix[0]), "contains_suffix_of disagrees for '{}': {:?}", name, contains_suffix);
+        }
+
+        for set in &sets {
+            assert!(set.contains("ads.example.com"));
+            assert!(!set.contains("example.com"));
+            assert!(set.contains_suffix_of("b.tracker.net"));
+            assert!(!set.contains("old.example"));
+            assert!(set.contains_suffix_of("sub.new.example"));
+        }
+    }
+}
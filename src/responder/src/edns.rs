@@ -0,0 +1,170 @@
+use trust_dns_proto::{
+    op::message::Message,
+    rr::rdata::opt::{
+        EdnsCode,
+        EdnsOption
+    },
+    serialize::binary::BinEncodable
+};
+
+// Extended DNS Errors (RFC 8914)
+const EDE_OPTION_CODE: u16 = 15;
+
+// Padding (RFC 7830), with an option header of code and length
+const PADDING_OPTION_CODE: u16 = 12;
+const OPTION_HEADER_LENGTH: usize = 4;
+
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_STALE_ANSWER: u16 = 3;
+pub const EDE_FILTERED: u16 = 17;
+pub const EDE_NETWORK_ERROR: u16 = 23;
+
+// Attaches an Extended DNS Error to the response. EDNS options may only be
+// sent to clients that included an OPT record in their query.
+pub fn set_extended_error(response: &mut Message, request: &Message, info_code: u16, extra_text: &str) {
+    if request.edns().is_none() {
+        return;
+    }
+
+    let mut data = info_code.to_be_bytes().to_vec();
+    data.extend_from_slice(extra_text.as_bytes());
+
+    response.edns_mut().options_mut().insert(EdnsOption::Unknown(EDE_OPTION_CODE, data));
+}
+
+// Pads the response to a multiple of `block_size` bytes (RFC 8467), so its
+// size doesn't give away the name queried, when the request was padded or
+// `force` is set. Never pads past the request's advertised payload size,
+// padding only as far as it allows instead. Like all options, only sent to
+// clients that included an OPT record.
+pub fn pad_response(response: &mut Message, request: &Message, block_size: usize, force: bool) {
+    let edns = match request.edns() {
+        Some(edns) => edns,
+        None => return
+    };
+
+    // The response starts out as a copy of the request, padding and all
+    response.edns_mut().options_mut().remove(EdnsCode::Padding);
+
+    if edns.option(EdnsCode::Padding).is_none() && !force {
+        return;
+    }
+
+    let max_payload = usize::from(edns.max_payload()).max(512);
+
+    let size = match response.to_bytes() {
+        Ok(bytes) => bytes.len() + OPTION_HEADER_LENGTH,
+        Err(_) => return
+    };
+
+    let padded_size = match size.div_ceil(block_size.max(1)) * block_size.max(1) {
+        padded_size if padded_size <= max_payload => padded_size,
+        _ if size <= max_payload => max_payload,
+        _ => return
+    };
+
+    response.edns_mut().options_mut().insert(EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; padded_size - size]));
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::op::{
+        Edns,
+        MessageType
+    };
+
+    use super::*;
+
+    fn padded_request(max_payload: u16) -> Message {
+        let mut request = Message::new();
+        let mut edns = Edns::new();
+
+        edns.set_max_payload(max_payload);
+        edns.options_mut().insert(EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; 8]));
+        request.set_edns(edns);
+
+        request
+    }
+
+    // The response starts out as a copy of the request
+    fn response_to(request: &Message) -> Message {
+        let mut response = request.clone();
+        response.set_message_type(MessageType::Response);
+        response
+    }
+
+    fn padding(response: &Message) -> Option<usize> {
+        match response.edns()?.option(EdnsCode::Padding)? {
+            EdnsOption::Unknown(_, data) => Some(data.len()),
+            _ => None
+        }
+    }
+
+    fn request(edns: bool) -> Message {
+        let mut request = Message::new();
+
+        if edns {
+            request.set_edns(Edns::new());
+        }
+
+        request
+    }
+
+    #[test]
+    fn sets_extended_errors() {
+        let mut response = Message::new();
+        set_extended_error(&mut response, &request(true), EDE_NETWORK_ERROR, "Upstream resolver under maintenance");
+
+        let mut expected = EDE_NETWORK_ERROR.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"Upstream resolver under maintenance");
+
+        assert_eq!(response.edns().unwrap().option(EdnsCode::from(EDE_OPTION_CODE)), Some(&EdnsOption::Unknown(EDE_OPTION_CODE, expected)));
+    }
+
+    #[test]
+    fn sets_no_extended_errors_without_edns() {
+        let mut response = Message::new();
+        set_extended_error(&mut response, &request(false), EDE_NETWORK_ERROR, "");
+
+        assert!(response.edns().is_none());
+    }
+
+    #[test]
+    fn pads_to_the_block_size() {
+        let request = padded_request(4096);
+        let mut response = response_to(&request);
+        pad_response(&mut response, &request, 468, false);
+
+        assert!(padding(&response).is_some());
+        assert_eq!(response.to_bytes().unwrap().len(), 468);
+    }
+
+    #[test]
+    fn pads_unpadded_requests_only_when_forced() {
+        let mut response = response_to(&request(true));
+        pad_response(&mut response, &request(true), 468, false);
+
+        assert!(padding(&response).is_none());
+
+        pad_response(&mut response, &request(true), 468, true);
+
+        assert_eq!(response.to_bytes().unwrap().len(), 468);
+    }
+
+    #[test]
+    fn pads_only_as_far_as_the_payload_size() {
+        let request = padded_request(512);
+        let mut response = response_to(&request);
+        pad_response(&mut response, &request, 1024, false);
+
+        assert_eq!(response.to_bytes().unwrap().len(), 512);
+    }
+
+    #[test]
+    fn pads_nothing_without_edns() {
+        let mut response = Message::new();
+        pad_response(&mut response, &request(false), 468, true);
+
+        assert!(response.edns().is_none());
+    }
+}
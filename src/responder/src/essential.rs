@@ -0,0 +1,88 @@
+use crate::domain_set::parent_domains;
+
+// Infrastructure a network can't do without, let through whatever the lists
+// say so bad list data can't lock clients out of fixing themselves. Each entry
+// covers every name under it.
+const ESSENTIAL_DOMAINS: &[&str] = &[
+    // Certificate revocation (OCSP and CRLs)
+    "ocsp.digicert.com",
+    "crl3.digicert.com",
+    "crl4.digicert.com",
+    "ocsp.pki.goog",
+    "crl.pki.goog",
+    "ocsp.sectigo.com",
+    "crl.sectigo.com",
+    "ocsp.globalsign.com",
+    "crl.globalsign.com",
+    "ocsp.entrust.net",
+    "crl.entrust.net",
+    "ocsp.apple.com",
+    "crl.apple.com",
+    "oneocsp.microsoft.com",
+    "ocsp.msocsp.com",
+    "crl.microsoft.com",
+    "r3.o.lencr.org",
+    "x1.c.lencr.org",
+    "o.lencr.org",
+    "c.lencr.org",
+    // Certificate issuance (ACME)
+    "acme-v02.api.letsencrypt.org",
+    "api.letsencrypt.org",
+    "acme.zerossl.com",
+    "dv.acme-v02.api.pki.goog",
+    // Time
+    "pool.ntp.org",
+    "time.apple.com",
+    "time.windows.com",
+    "time.google.com",
+    "time.cloudflare.com",
+    "time.nist.gov",
+    // OS updates
+    "swscan.apple.com",
+    "swcdn.apple.com",
+    "swdist.apple.com",
+    "mesu.apple.com",
+    "updates.cdn-apple.com",
+    "windowsupdate.com",
+    "update.microsoft.com",
+    "windowsupdate.microsoft.com",
+    "delivery.mp.microsoft.com",
+    "android.clients.google.com",
+    "dl.google.com",
+    "archive.ubuntu.com",
+    "security.ubuntu.com",
+    "deb.debian.org",
+    "security.debian.org",
+    "mirrors.fedoraproject.org"
+];
+
+// Whether the domain (lowercase, without the root label) or any of its
+// parents is essential
+pub fn is_essential(domain: &str) -> bool {
+    parent_domains(domain).any(|candidate| ESSENTIAL_DOMAINS.contains(&candidate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_essential_domains_and_their_subdomains() {
+        assert!(is_essential("ocsp.digicert.com"));
+        assert!(is_essential("0.pool.ntp.org"));
+        assert!(is_essential("au.download.windowsupdate.com"));
+    }
+
+    #[test]
+    fn does_not_cover_parents_or_lookalikes() {
+        assert!(!is_essential("digicert.com"));
+        assert!(!is_essential("ntp.org"));
+        assert!(!is_essential("evilpool.ntp.org"));
+        assert!(!is_essential("pool.ntp.org.evil.example"));
+    }
+
+    #[test]
+    fn lists_lowercase_domains_without_the_root_label() {
+        assert!(ESSENTIAL_DOMAINS.iter().all(|domain| *domain == domain.to_lowercase() && !domain.ends_with('.')));
+    }
+}
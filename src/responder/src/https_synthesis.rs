@@ -0,0 +1,112 @@
+use trust_dns_proto::rr::{
+    rdata::svcb::{
+        Alpn,
+        IpHint,
+        SvcParamKey,
+        SvcParamValue,
+        SVCB
+    },
+    Name,
+    RData,
+    Record
+};
+
+// Builds a minimal HTTPS record (RFC 9460) for `name` from its address
+// records, for upstreams that don't serve HTTPS records themselves. The record
+// is in ServiceMode with the owner as target, advertising `alpn` and the
+// addresses as hints. Returns None when there are no addresses.
+pub fn synthesize_https(name: &Name, address_records: &[Record], alpn: &[String]) -> Option<Record> {
+    let mut ipv4_hints = Vec::new();
+    let mut ipv6_hints = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for record in address_records {
+        match record.data() {
+            Some(RData::A(address)) => ipv4_hints.push(*address),
+            Some(RData::AAAA(address)) => ipv6_hints.push(*address),
+            _ => continue
+        };
+
+        ttl = ttl.min(record.ttl());
+    }
+
+    if ipv4_hints.is_empty() && ipv6_hints.is_empty() {
+        return None;
+    }
+
+    // Parameters must be in ascending key order
+    let mut svc_params = Vec::new();
+
+    if !alpn.is_empty() {
+        svc_params.push((SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(alpn.to_vec()))));
+    }
+
+    if !ipv4_hints.is_empty() {
+        svc_params.push((SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(ipv4_hints))));
+    }
+
+    if !ipv6_hints.is_empty() {
+        svc_params.push((SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(ipv6_hints))));
+    }
+
+    Some(Record::from_rdata(name.clone(), ttl, RData::HTTPS(SVCB::new(1, Name::root(), svc_params))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{
+            Ipv4Addr,
+            Ipv6Addr
+        },
+        str::FromStr
+    };
+
+    use super::*;
+
+    fn name() -> Name {
+        Name::from_str("www.example.").unwrap()
+    }
+
+    #[test]
+    fn synthesizes_from_addresses() {
+        let records = vec![
+            Record::from_rdata(name(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            Record::from_rdata(name(), 60, RData::AAAA(Ipv6Addr::from_str("2001:db8::1").unwrap()))
+        ];
+
+        let record = synthesize_https(&name(), &records, &["h3".to_string(), "h2".to_string()]).unwrap();
+
+        let expected = SVCB::new(1, Name::root(), vec![
+            (SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(vec!["h3".to_string(), "h2".to_string()]))),
+            (SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(vec![Ipv4Addr::new(192, 0, 2, 1)]))),
+            (SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(vec![Ipv6Addr::from_str("2001:db8::1").unwrap()])))
+        ]);
+
+        assert_eq!(record.name(), &name());
+        // The lowest TTL of the addresses it's built from
+        assert_eq!(record.ttl(), 60);
+        assert_eq!(record.data(), Some(&RData::HTTPS(expected)));
+    }
+
+    #[test]
+    fn leaves_out_empty_params() {
+        let records = vec![Record::from_rdata(name(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))];
+
+        let record = synthesize_https(&name(), &records, &[]).unwrap();
+
+        let expected = SVCB::new(1, Name::root(), vec![
+            (SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(vec![Ipv4Addr::new(192, 0, 2, 1)])))
+        ]);
+
+        assert_eq!(record.data(), Some(&RData::HTTPS(expected)));
+    }
+
+    #[test]
+    fn synthesizes_nothing_without_addresses() {
+        let records = vec![Record::from_rdata(name(), 300, RData::CNAME(Name::from_str("cdn.example.").unwrap()))];
+
+        assert!(synthesize_https(&name(), &records, &["h2".to_string()]).is_none());
+        assert!(synthesize_https(&name(), &[], &["h2".to_string()]).is_none());
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    path::Path,
+    sync::Mutex
+};
+
+use anyhow::Result;
+
+use rusqlite::{
+    Connection,
+    OpenFlags,
+    OptionalExtension,
+    ToSql
+};
+
+use crate::domain_set::parent_domains;
+
+// Embedded SQLite deny/allow list database, built by the deny_list_updater
+// when it is configured with DENY_LIST_FORMAT=sqlite
+pub const LIST_DATABASE_FILENAME: &str = "./hosts.db";
+
+// Category of deny entries from the updater's newly registered domains feed
+pub const NRD_CATEGORY: &str = "newly-registered";
+
+pub struct ListDatabase {
+    // rusqlite connections are Send but not Sync
+    connection: Mutex<Connection>
+}
+
+impl ListDatabase {
+    pub fn open<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>, {
+        // The Lambda package directory is read-only, so open the database as
+        // immutable to skip all locking and journal handling
+        let uri = format!("file:{}?immutable=1", path.as_ref().display());
+
+        let connection = Connection::open_with_flags(
+            uri,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX
+        )?;
+
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    // A domain is denied if it has an unexpired entry in the deny table and
+    // none in the allow table. Entries without an expiry never expire.
+    pub fn is_denied(&self, domain: &str) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare_cached(
+            "SELECT 1 FROM deny WHERE domain = ?1 AND (expires IS NULL OR expires > unixepoch()) \
+                AND NOT EXISTS (SELECT 1 FROM allow WHERE domain = ?1 AND (expires IS NULL OR expires > unixepoch()))"
+        )?;
+
+        Ok(statement.query_row([domain], |_| Ok(())).optional()?.is_some())
+    }
+
+    // Suffix matching in one indexed query: a domain is denied if it or any of
+    // its parents has an unexpired entry in the deny table without an
+    // unexpired one in the allow table
+    pub fn is_denied_under(&self, domain: &str) -> Result<bool> {
+        let candidates: Vec<&str> = parent_domains(domain).collect();
+        let placeholders = (1..=candidates.len()).map(|index| format!("?{}", index)).collect::<Vec<String>>().join(", ");
+
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT 1 FROM deny WHERE domain IN ({}) AND (expires IS NULL OR expires > unixepoch()) \
+                AND NOT EXISTS (SELECT 1 FROM allow WHERE allow.domain = deny.domain \
+                    AND (allow.expires IS NULL OR allow.expires > unixepoch())) LIMIT 1",
+            placeholders
+        ))?;
+
+        let params: Vec<&dyn ToSql> = candidates.iter().map(|candidate| candidate as &dyn ToSql).collect();
+
+        Ok(statement.query_row(params.as_slice(), |_| Ok(())).optional()?.is_some())
+    }
+
+    pub fn is_allowed(&self, domain: &str) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare_cached(
+            "SELECT 1 FROM allow WHERE domain = ?1 AND (expires IS NULL OR expires > unixepoch())"
+        )?;
+
+        Ok(statement.query_row([domain], |_| Ok(())).optional()?.is_some())
+    }
+
+    pub fn category(&self, domain: &str) -> Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection.prepare_cached(
+            "SELECT category FROM deny WHERE domain = ?1 AND (expires IS NULL OR expires > unixepoch())"
+        )?;
+
+        Ok(statement.query_row([domain], |row| row.get(0)).optional()?.flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs
+    };
+
+    use super::*;
+
+    // The deny_list_updater's schema
+    const SCHEMA: &str = "
+        CREATE TABLE deny (domain TEXT PRIMARY KEY NOT NULL, category TEXT, source TEXT NOT NULL, added INTEGER NOT NULL, expires INTEGER) WITHOUT ROWID;
+        CREATE TABLE allow (domain TEXT PRIMARY KEY NOT NULL, category TEXT, source TEXT NOT NULL, added INTEGER NOT NULL, expires INTEGER) WITHOUT ROWID;
+
+        INSERT INTO deny VALUES ('ads.example', NULL, 'test', 0, NULL);
+        INSERT INTO deny VALUES ('tracker.example', 'newly-registered', 'test', 0, NULL);
+        INSERT INTO deny VALUES ('expired.example', NULL, 'test', 0, 1);
+        INSERT INTO deny VALUES ('cdn.example', NULL, 'test', 0, NULL);
+        INSERT INTO allow VALUES ('cdn.example', NULL, 'test', 0, NULL);
+        INSERT INTO allow VALUES ('ok.ads.example', NULL, 'test', 0, NULL);
+        INSERT INTO deny VALUES ('expired-nrd.example', 'newly-registered', 'test', 0, 1);
+        INSERT INTO deny VALUES ('lapsed.example', NULL, 'test', 0, NULL);
+        INSERT INTO allow VALUES ('lapsed.example', NULL, 'test', 0, 1);
+        INSERT INTO allow VALUES ('later.example', NULL, 'test', 0, 4102444800);
+    ";
+
+    fn database(name: &str) -> ListDatabase {
+        let path = env::temp_dir().join(format!("list-db-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        Connection::open(&path).unwrap().execute_batch(SCHEMA).unwrap();
+
+        let database = ListDatabase::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        database
+    }
+
+    #[test]
+    fn exact_lookups() {
+        let database = database("exact");
+
+        assert!(database.is_denied("ads.example").unwrap());
+        assert!(!database.is_denied("sub.ads.example").unwrap());
+        assert!(!database.is_denied("expired.example").unwrap());
+        assert!(!database.is_denied("cdn.example").unwrap());
+        assert!(!database.is_denied("other.example").unwrap());
+
+        assert!(database.is_allowed("cdn.example").unwrap());
+        assert!(!database.is_allowed("ads.example").unwrap());
+    }
+
+    #[test]
+    fn ignores_expired_allow_entries() {
+        let database = database("expired-allow");
+
+        assert!(!database.is_allowed("lapsed.example").unwrap());
+        assert!(database.is_allowed("later.example").unwrap());
+
+        // The deny entry applies again once its exception has expired
+        assert!(database.is_denied("lapsed.example").unwrap());
+        assert!(database.is_denied_under("www.lapsed.example").unwrap());
+    }
+
+    #[test]
+    fn suffix_lookups() {
+        let database = database("suffix");
+
+        assert!(database.is_denied_under("ads.example").unwrap());
+        assert!(database.is_denied_under("a.b.ads.example").unwrap());
+        assert!(!database.is_denied_under("badads.example").unwrap());
+        assert!(!database.is_denied_under("www.expired.example").unwrap());
+        assert!(!database.is_denied_under("img.cdn.example").unwrap());
+        assert!(!database.is_denied_under("example").unwrap());
+    }
+
+    #[test]
+    fn categories() {
+        let database = database("categories");
+
+        assert_eq!(database.category("tracker.example").unwrap().as_deref(), Some(NRD_CATEGORY));
+        assert_eq!(database.category("ads.example").unwrap(), None);
+        assert_eq!(database.category("other.example").unwrap(), None);
+    }
+
+    #[test]
+    fn ignores_expired_categories() {
+        let database = database("expired-categories");
+
+        assert_eq!(database.category("expired-nrd.example").unwrap(), None);
+    }
+}
@@ -1,17 +1,65 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod additional;
+mod allow_cache;
+mod block_rate;
+mod bloom;
+mod build_info;
+mod circuit_breaker;
+mod cname;
+mod config;
+mod cookies;
+mod debug_format;
+mod dns64;
+mod domain_set;
+mod edns;
+mod essential;
+mod https_synthesis;
+mod label_trie;
+mod list_db;
+mod overrides;
+mod policy;
+mod query_log;
+mod readiness;
+mod response_cache;
+mod single_flight;
+mod rotate;
+mod shared_cache;
+mod soa;
+mod stale;
+mod stats;
+mod stub_resolver;
+mod upstream;
+mod views;
+
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::HashMap,
     fmt,
-    fs::File,
-    io::{self, BufRead},
-    path::Path
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr
+    },
+    path::Path,
+    sync::OnceLock,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH
+    }
 };
 
 // Enable arbitrary error bubbling
-use anyhow::Result;
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use futures_util::future::join_all;
 
 use lambda_http::{
     http::Method,
@@ -28,28 +76,187 @@ use lambda_http::{
 };
 
 use trust_dns_proto::{
+    error::{
+        ProtoError,
+        ProtoErrorKind
+    },
     op::{
         header::MessageType,
         message::Message,
-        response_code::ResponseCode::NXDomain
+        op_code::OpCode,
+        query::Query,
+        response_code::ResponseCode::{
+            BADCOOKIE,
+            FormErr,
+            NoError,
+            NotImp,
+            NXDomain,
+            Refused,
+            ServFail
+        }
+    },
+    rr::{
+        rdata::TXT,
+        Name,
+        RData,
+        Record,
+        RecordType
     },
     serialize::binary::{
         BinDecodable,
-        BinEncodable
-    },
-    xfer::DnsRequestOptions
+        BinEncodable,
+        DecodeError
+    }
 };
 
 use trust_dns_resolver::{
-    error::ResolveErrorKind::{
-        NoRecordsFound,
-        Proto
+    config::NameServerConfig,
+    error::{
+        ResolveError,
+        ResolveErrorKind::{
+            NoRecordsFound,
+            Proto
+        }
     },
-    TokioAsyncResolver
+    lookup::Lookup
 };
 
 use url::Url;
 
+use additional::trim_additionals;
+
+use allow_cache::AllowCache;
+
+use block_rate::BlockRateTracker;
+
+use build_info::{
+    BuildInfo,
+    BUILD_INFO_FILENAME
+};
+
+use circuit_breaker::{
+    CircuitBreaker,
+    FailoverResolver
+};
+
+use cname::{
+    answers_chain_from,
+    cname_chain
+};
+
+use config::{
+    AnswerNameMismatchHandling,
+    BlockMode,
+    BlockRateAction,
+    BodyEncoding,
+    Config,
+    IpLiteralHandling,
+    ListLoading,
+    NrdHandling,
+    OversizedResponseHandling,
+    RootResponse,
+    UpstreamUnreachableHandling
+};
+
+use cookies::{
+    is_valid_server_cookie,
+    request_cookie,
+    server_cookie,
+    set_cookie
+};
+
+use debug_format::ResponseFormat;
+
+use dns64::synthesize_aaaa;
+
+use domain_set::{
+    parent_domains,
+    DomainSet
+};
+
+use edns::{
+    pad_response,
+    set_extended_error,
+    EDE_FILTERED,
+    EDE_NETWORK_ERROR,
+    EDE_OTHER,
+    EDE_STALE_ANSWER
+};
+
+use essential::is_essential;
+
+use https_synthesis::synthesize_https;
+
+use list_db::{
+    ListDatabase,
+    LIST_DATABASE_FILENAME,
+    NRD_CATEGORY
+};
+
+use overrides::Overrides;
+
+use policy::{
+    decide,
+    PolicyDecision,
+    PolicyMatches
+};
+
+use readiness::Readiness;
+
+use response_cache::ResponseCache;
+
+use query_log::QueryLog;
+
+use rotate::rotate_answers;
+
+use shared_cache::{
+    DynamoDbTable,
+    SharedCache
+};
+
+use single_flight::SingleFlight;
+
+use soa::synthesized_soa;
+
+use stale::StaleCache;
+
+use stats::{
+    Outcome,
+    Stats
+};
+
+use stub_resolver::StubResolver;
+
+use upstream::{
+    build_resolver,
+    build_resolvers_per_name_server,
+    is_encrypted,
+    parse_name_servers,
+    UpstreamResolver,
+    UpstreamSettings
+};
+
+use views::{
+    View,
+    Views
+};
+
+const LANDING_PAGE: &str = "This is a DNS-over-HTTPS (RFC 8484) endpoint. Configure it as the DoH resolver URL of your device or browser.\n";
+
+// TTL of stale answers, per RFC 8767
+const STALE_ANSWER_TTL: u32 = 30;
+
+// Addresses blocked names are pointed at in sinkhole mode, as by other
+// sinkholing resolvers
+const SINKHOLE_ADDRESSES: [IpAddr; 2] = [IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)];
+
+// Sent with 503s when the instance is saturated
+const RETRY_AFTER_SECONDS: u32 = 1;
+
+// Room left for the Lambda proxy response envelope (status, headers, JSON)
+// around the base64-encoded body
+const RESPONSE_ENVELOPE_OVERHEAD: usize = 1024;
+
 #[derive(Debug, Clone)]
 struct BadRequestError {
     message: String
@@ -73,43 +280,275 @@ impl fmt::Display for BadRequestError {
     }
 }
 
+// Set by `validate_config` at startup, before anything reads CONFIG
+static VALIDATED_CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Also set by `validate_config`, since they may be fetched from S3
+static LOADED_OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
 lazy_static! {
-    static ref HOSTS: HashSet<String> = {
-        let mut hosts: HashSet<String> = HashSet::new();
-
-        if let Ok(lines) = read_lines("./hosts") {
-            // Consumes the iterator, returns an (Optional) String
-            for line in lines {
-                if let Ok(host) = line {
-                    hosts.insert(host);
-                }
+    static ref CONFIG: &'static Config = VALIDATED_CONFIG.get().expect("Configuration read before validate_config");
+
+    static ref HOSTS: DomainSet = match CONFIG.bloom_filter {
+        true => DomainSet::load_with_bloom_filter("./hosts", CONFIG.matcher_backend),
+        false => DomainSet::load_with("./hosts", CONFIG.matcher_backend)
+    };
+
+    static ref ALLOW: DomainSet = DomainSet::load_with("./allow", CONFIG.matcher_backend);
+
+    static ref OVERRIDES: &'static Overrides = LOADED_OVERRIDES.get().expect("Overrides read before validate_config");
+
+    static ref VIEWS: Views = {
+        let views = Views::load(&CONFIG.views_file, &CONFIG.view_profiles_dir).expect("Failed to load views");
+
+        if views.len() > 0 {
+            println!("Loaded {} view profiles from '{}'", views.len(), CONFIG.views_file);
+        }
+
+        views
+    };
+
+    static ref LIST_DATABASE: Option<ListDatabase> = {
+        if !Path::new(LIST_DATABASE_FILENAME).exists() {
+            return None;
+        }
+
+        match ListDatabase::open(LIST_DATABASE_FILENAME) {
+            Ok(database) => {
+                println!("Using deny/allow list database '{}'", LIST_DATABASE_FILENAME);
+                Some(database)
+            },
+            Err(err) => {
+                println!("Failed to open deny/allow list database, falling back to hosts file: {}", err);
+                None
             }
         }
+    };
+
+    // Only marked ready when loading the lists in the background
+    static ref LISTS_READY: Readiness = Readiness::new();
+
+    static ref BUILD_INFO: Option<BuildInfo> = match BuildInfo::load(BUILD_INFO_FILENAME) {
+        Ok(build_info) => build_info,
+        Err(err) => {
+            println!("Failed to load list build info: {:#}", err);
+            None
+        }
+    };
+
+    static ref STATS: Stats = Stats::new(CONFIG.stats_log_every_requests, CONFIG.stats_log_every_seconds);
+
+    // Build failures are kept rather than panicking, so they're reported at
+    // startup and every request gets a clean error instead of a crash
+    static ref RESOLVER: Result<Box<dyn UpstreamResolver>> = primary_resolver();
+
+    static ref TYPE_RESOLVERS: Result<HashMap<RecordType, Box<dyn UpstreamResolver>>> = type_resolvers();
+
+    // Only built when ALLOW_PLAINTEXT_FALLBACK is set
+    static ref FALLBACK_RESOLVER: Result<Option<Box<dyn UpstreamResolver>>> = fallback_resolver();
+
+    // Only used with a fallback resolver
+    static ref UPSTREAM_CIRCUIT_BREAKER: Option<CircuitBreaker> = CONFIG.upstream_failure_cooldown.map(CircuitBreaker::new);
+
+    // Only kept when serving stale answers
+    static ref STALE_CACHE: Option<StaleCache> = {
+        match CONFIG.upstream_unreachable {
+            UpstreamUnreachableHandling::ServeStale => Some(StaleCache::new(CONFIG.stale_cache_size)),
+            _ => None
+        }
+    };
+
+    static ref RESPONSE_CACHE: Option<ResponseCache> =
+        (CONFIG.response_cache_size > 0).then(|| ResponseCache::new(CONFIG.response_cache_size));
 
-        hosts
+    static ref ALLOW_CACHE: Option<AllowCache> = {
+        (CONFIG.allow_cache_size > 0).then(|| AllowCache::new(CONFIG.allow_cache_size, CONFIG.allow_cache_max_age))
     };
 
-    static ref RESOLVER: TokioAsyncResolver = {
-        TokioAsyncResolver::tokio_from_system_conf().expect("Failed to create async resolver")
+    static ref BLOCK_RATE: Option<BlockRateTracker> = CONFIG.block_rate_limit.map(|limit| BlockRateTracker::new(limit, CONFIG.block_rate_window));
+
+    // Upstream lookups currently in flight, shared by concurrent identical
+    // queries
+    static ref UPSTREAM_LOOKUPS: SingleFlight<(String, RecordType), Result<Lookup, ResolveError>> = SingleFlight::new();
+
+    static ref QUERY_LOG: Option<QueryLog> = CONFIG.query_log.clone().map(QueryLog::new);
+
+    // Only kept when SHARED_CACHE_TABLE is set, holding as many answers in
+    // memory as the resolver cache would
+    static ref SHARED_CACHE: Option<SharedCache> = {
+        CONFIG.shared_cache_table.clone().map(|table| SharedCache::new(Box::new(DynamoDbTable::new(table)), CONFIG.response_cache_size))
     };
 }
 
+fn upstream_settings(name_servers: Option<Vec<NameServerConfig>>) -> UpstreamSettings {
+    UpstreamSettings {
+        name_servers,
+        ip_version: CONFIG.upstream_ip_version,
+        min_ttl: CONFIG.min_upstream_ttl,
+        search_domains: CONFIG.search_domains.clone(),
+        ndots: CONFIG.ndots,
+        timeout: CONFIG.upstream_timeout,
+        concurrent_requests: CONFIG.upstream_concurrent_requests
+    }
+}
+
+fn primary_resolver() -> Result<Box<dyn UpstreamResolver>> {
+    if CONFIG.test_mode {
+        println!("Test mode enabled, answering from stub answers in '{}'", CONFIG.stub_answers_file);
+        return Ok(Box::new(StubResolver::load(&CONFIG.stub_answers_file)?));
+    }
+
+    let name_servers = CONFIG.upstream_resolvers
+        .as_ref()
+        .map(|spec| parse_name_servers(spec).with_context(|| "Invalid UPSTREAM_RESOLVERS"))
+        .transpose()?;
+
+    if let Some(cooldown) = CONFIG.upstream_failure_cooldown {
+        let resolvers = build_resolvers_per_name_server(&upstream_settings(name_servers)).with_context(|| "Failed to create async resolvers")?;
+
+        return Ok(Box::new(FailoverResolver::new(
+            resolvers.into_iter().map(|resolver| Box::new(resolver) as Box<dyn UpstreamResolver>).collect(),
+            cooldown
+        )));
+    }
+
+    let resolver = build_resolver(&upstream_settings(name_servers)).with_context(|| "Failed to create async resolver")?;
+
+    Ok(Box::new(resolver))
+}
+
+// Test mode answers every type from the stub answers
+fn type_resolvers() -> Result<HashMap<RecordType, Box<dyn UpstreamResolver>>> {
+    if CONFIG.test_mode {
+        return Ok(HashMap::new());
+    }
+
+    CONFIG.upstream_resolvers_by_type
+        .iter()
+        .map(|(query_type, spec)| {
+            let name_servers = parse_name_servers(spec).with_context(|| format!("Invalid UPSTREAM_RESOLVERS_BY_TYPE upstream for {}", query_type))?;
+
+            let resolver: Box<dyn UpstreamResolver> = Box::new(build_resolver(&upstream_settings(Some(name_servers)))
+                .with_context(|| format!("Failed to create async resolver for {}", query_type))?);
+
+            Ok((*query_type, resolver))
+        })
+        .collect()
+}
+
+fn fallback_resolver() -> Result<Option<Box<dyn UpstreamResolver>>> {
+    if !CONFIG.allow_plaintext_fallback || CONFIG.test_mode {
+        return Ok(None);
+    }
+
+    let name_servers = CONFIG.plaintext_fallback_resolvers
+        .as_ref()
+        .map(|spec| parse_name_servers(spec).with_context(|| "Invalid PLAINTEXT_FALLBACK_RESOLVERS"))
+        .transpose()?;
+
+    if name_servers.iter().flatten().any(is_encrypted) {
+        return Err(anyhow!("PLAINTEXT_FALLBACK_RESOLVERS must only contain udp or tcp upstreams"));
+    }
+
+    let resolver = build_resolver(&upstream_settings(name_servers))
+        .with_context(|| "Failed to create plaintext fallback resolver")?;
+
+    Ok(Some(Box::new(resolver)))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), lambda_http::Error> {
-    lambda_http::run(service_fn(respond)).await?;
+    validate_config().await?;
+
+    if CONFIG.list_loading != ListLoading::Inline {
+        tokio::task::spawn_blocking(load_lists);
+    }
+
+    lambda_http::run(service_fn(|request| respond(*CONFIG, request))).await?;
+
+    Ok(())
+}
+
+// For the authority section of negative answers, so they can be cached
+fn negative_soa(config: &Config, name: &Name) -> Record {
+    synthesized_soa(name, config.soa_mname.as_ref(), config.soa_rname.as_ref(), config.negative_ttl)
+}
+
+// TXT character strings are at most 255 bytes each, so longer text is split
+// across several
+fn block_explanation(config: &Config, name: &Name) -> Record {
+    let mut text = vec!["Blocked by resolver policy".to_string()];
+
+    if let Some(url) = &config.block_info_url {
+        text.push(format!("More info: {}", url));
+    }
+
+    let strings = text
+        .iter()
+        .flat_map(|text| text.as_bytes().chunks(255))
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect();
+
+    Record::from_rdata(name.clone(), config.negative_ttl, RData::TXT(TXT::new(strings)))
+}
+
+// The responder has no required env vars, but parses the optional ones at
+// startup so invalid values fail the init phase with a clear message rather
+// than panicking in the middle of the first request
+async fn validate_config() -> Result<(), lambda_http::Error> {
+    let config = Config::from_env().map_err(|err| format!("Invalid configuration: {:#}", err))?;
+    VALIDATED_CONFIG.get_or_init(|| config);
+
+    lazy_static::initialize(&CONFIG);
+
+    let overrides = Overrides::load_from(&CONFIG.overrides_file)
+        .await
+        .map_err(|err| format!("Failed to load static overrides: {:#}", err))?;
+
+    if overrides.len() > 0 {
+        println!("Loaded static overrides for {} names from '{}'", overrides.len(), CONFIG.overrides_file);
+    }
+
+    LOADED_OVERRIDES.get_or_init(|| overrides);
+    lazy_static::initialize(&OVERRIDES);
+    lazy_static::initialize(&VIEWS);
+    lazy_static::initialize(&STATS);
+    lazy_static::initialize(&QUERY_LOG);
+    lazy_static::initialize(&SHARED_CACHE);
+    lazy_static::initialize(&STALE_CACHE);
+    lazy_static::initialize(&UPSTREAM_CIRCUIT_BREAKER);
+    lazy_static::initialize(&ALLOW_CACHE);
+    lazy_static::initialize(&RESPONSE_CACHE);
+    lazy_static::initialize(&BUILD_INFO);
+
+    if let Err(err) = &*RESOLVER {
+        return Err(format!("Invalid upstream resolver config: {:#}", err).into());
+    }
+
+    if let Err(err) = &*FALLBACK_RESOLVER {
+        return Err(format!("Invalid plaintext fallback resolver config: {:#}", err).into());
+    }
+
+    if let Err(err) = &*TYPE_RESOLVERS {
+        return Err(format!("Invalid per-type upstream resolver config: {:#}", err).into());
+    }
 
     Ok(())
 }
 
-// The output is wrapped in a Result to allow matching on errors
-// Returns an Iterator to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+fn load_lists() {
+    lazy_static::initialize(&LIST_DATABASE);
+
+    if LIST_DATABASE.is_none() {
+        lazy_static::initialize(&HOSTS);
+        lazy_static::initialize(&ALLOW);
+    }
+
+    println!("Finished loading deny/allow lists");
+    LISTS_READY.mark_ready();
 }
 
-async fn respond(request: Request) -> Result<Response<Body>, lambda_http::Error> {
+async fn respond(config: &Config, request: Request) -> Result<Response<Body>, lambda_http::Error> {
     let ip = match request.request_context() {
         ApiGatewayV1(context) => context.identity.source_ip.unwrap_or("Unknown".to_string()),
         ApiGatewayV2(context) => context.http.source_ip.unwrap_or("Unknown".to_string()),
@@ -120,24 +559,94 @@ async fn respond(request: Request) -> Result<Response<Body>, lambda_http::Error>
 
     if request.method() == Method::GET && request.uri().path() == "/reachable" {
         println!("Received reachability request, done!");
+
+        let mut body = "Ok\n".to_string();
+
+        if config.report_list_age {
+            match &*BUILD_INFO {
+                Some(build_info) => body.push_str(&build_info.describe()),
+                None => body.push_str("No list build info\n")
+            };
+        }
+
         return Ok(Response::builder()
             .status(StatusCode::OK)
             .header("Content-Type", "text/plain")
-            .body(Body::from("Ok\n"))?
+            .body(Body::from(body))?
         );
     };
 
+    if config.metrics_endpoint && request.method() == Method::GET && request.uri().path() == "/metrics" {
+        println!("Received metrics request");
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(STATS.prometheus()))?);
+    }
+
+    if config.debug_endpoints && request.method() == Method::GET && request.uri().path() == "/debug/lookup" {
+        println!("Received debug lookup request");
+        return debug_lookup(config, &request, &ip).await;
+    }
+
+    if request.method() == Method::GET && request.uri().path() == "/" && !has_dns_query_param(config, request.uri().query()) {
+        println!("Received request for the endpoint root, returning landing response");
+        return landing_response(config);
+    }
+
+    if request.uri().path() != config.doh_path.as_str() {
+        println!("Received request for unknown path '{}', returning 404", request.uri().path());
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body(Body::from("Not found\n"))?);
+    }
+
+    // Held until the response is built
+    let _permit = match &config.in_flight {
+        Some(in_flight) => match in_flight.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                println!("Too many requests in flight, returning 503");
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Retry-After", RETRY_AFTER_SECONDS.to_string())
+                    .body(Body::from(()))?);
+            }
+        },
+        None => None
+    };
+
+    STATS.record_request();
+    let started = Instant::now();
+
+    // Read before the request is consumed below
+    let format = match debug_format_param(config, request.uri().query()) {
+        Ok(format) => format,
+        Err(err) => {
+            println!("Bad request: {}", err);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body(Body::from(format!("Bad request: {}\n", err)))?);
+        }
+    };
+
+    let deadline = request.lambda_context().deadline;
+
     let message = match *request.method() {
-        Method::GET => message_from_get(request).await,
-        Method::POST => message_from_post(request).await,
+        Method::GET => message_from_get(config, request).await,
+        Method::POST => message_from_post(config, request).await,
         _ => return Ok(Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
                 .body(Body::from(()))?)
     };
 
-    let message = match message {
+    let (message, reserved_bit) = match message {
         Ok(message) => message,
         Err(err) => {
+            STATS.record(Outcome::Error);
+
             return match err.downcast_ref::<BadRequestError>() {
                 Some(err) => {
                     println!("Bad request: {}", err.message());
@@ -156,110 +665,2175 @@ async fn respond(request: Request) -> Result<Response<Body>, lambda_http::Error>
         }
     };
 
-    // While the DNS protocol supports multiple questions in theory,
-    // in practice no one supports it (i.e. BIND doesn't...)
-    let query = &message.queries()[0];
-    let domain = query.name().to_utf8();
-    let mut domain_without_last_period = domain.clone();
+    if config.reject_reserved_bits && reserved_bit {
+        println!("Query has the reserved Z bit set, returning FormErr");
+        let mut response = message.clone();
+        response
+            .set_message_type(MessageType::Response)
+            .set_response_code(FormErr);
 
-    if domain.len() > 0 && domain.chars().last().unwrap() == '.' {
-        domain_without_last_period.remove(domain.chars().count() - 1);
+        STATS.record(Outcome::Error);
+        return dns_response(&response, None);
     }
 
-    let mut response = message.clone();
-    response
-        .set_message_type(MessageType::Response)
-        .set_recursion_available(true);
+    if config.list_loading == ListLoading::Fail && !LISTS_READY.is_ready() {
+        println!("Deny/allow lists still loading, returning ServFail");
+        let mut response = message.clone();
+        response
+            .set_message_type(MessageType::Response)
+            .set_response_code(ServFail);
 
-    if HOSTS.contains(&domain_without_last_period) {
-        println!("Domain '{}' matches denylist, returning NXDomain", domain);
-        response.set_response_code(NXDomain);
-    } else {
-        println!("Domain '{}' does not match denylist, proxying query...", domain);
-        let results = RESOLVER
-            .lookup(domain, query.query_type(), DnsRequestOptions::default())
-            .await;
+        STATS.record(Outcome::Error);
+        return dns_response(&response, None);
+    }
 
-        match results {
-            Ok(results) => {
-                for answer in results.record_iter() {
-                    response.add_answer(answer.clone());
-                }
-            },
-            Err(err) => {
-                match err.kind() {
-                    NoRecordsFound { .. } => {
-                        response.set_response_code(NXDomain);
-                    },
-                    Proto(_) => {
-                        println!("Invalid domain: {}", domain_without_last_period);
-                        response.set_response_code(NXDomain);
-                    },
-                    _ => {
-                        println!("Failed to query for domain: {}", err);
-                        return Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from(()))?);
-                    }
-                };
-            }
-        };
+    // Held until the lists are loaded, within the same time budget as
+    // answering
+    let answer_when_ready = async {
+        if config.list_loading == ListLoading::Wait && !LISTS_READY.is_ready() {
+            println!("Deny/allow lists still loading, waiting");
+            LISTS_READY.wait().await;
+        }
+
+        answer_questions(config, &message, &ip).await
     };
 
-    let response_bytes = response.to_bytes().expect("Failed to serialize response");
+    let answer = match handler_budget(config, deadline) {
+        Some(budget) => match tokio::time::timeout(budget, answer_when_ready).await {
+            Ok(answer) => answer,
+            Err(_) => {
+                println!("Ran out of time answering query, returning ServFail");
+                let mut response = message.clone();
+                response
+                    .set_message_type(MessageType::Response)
+                    .set_response_code(ServFail);
 
-    println!("Done!");
+                Ok(Answer::new(response, Outcome::Error))
+            }
+        },
+        None => answer_when_ready.await
+    };
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/dns-message")
-        .body(Body::from(response_bytes))?)
-}
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(err) => {
+            println!("Failed to process request: {:#}", err);
+            STATS.record(Outcome::Error);
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(()))?);
+        }
+    };
 
-async fn message_from_get(request: Request) -> Result<Message> {
-    println!("URI: {}", request.uri().to_string());
+    STATS.record(answer.outcome);
+    STATS.record_latency(started.elapsed());
 
-    let url = Url::parse(&request.uri().to_string())?;
+    if let (Outcome::Blocked, Some(block_rate)) = (answer.outcome, &*BLOCK_RATE) {
+        if block_rate.record_block(&ip) {
+            STATS.record_block_rate_exceeded();
 
-    let encoded_payload = match url.query_pairs().find(|pair| pair.0 == Cow::Borrowed("dns")) {
-        Some(pair) => pair.1,
-        None => return Err(BadRequestError::new("Missing 'dns' query string parameter"))?
-    };
+            match config.block_rate_action {
+                BlockRateAction::Log => {},
+                // Within what's left of the time budget
+                BlockRateAction::Delay => {
+                    let delay = handler_budget(config, deadline).map(|budget| budget.min(config.block_rate_delay)).unwrap_or(config.block_rate_delay);
+                    tokio::time::sleep(delay).await;
+                },
+                BlockRateAction::Reject => {
+                    println!("Client IP {} is over the block rate limit, returning 429", ip);
+                    return Ok(Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header("Retry-After", config.block_rate_window.as_secs().to_string())
+                        .body(Body::from(()))?);
+                }
+            };
+        }
+    }
 
-    let payload = match base64_url::decode(&encoded_payload.to_string()) {
-        Ok(payload) => payload,
-        Err(err) => {
-            println!("Failed to base64 decode DNS message '{}': {}", encoded_payload, err);
-            return Err(BadRequestError::new("Invalid DNS message"))?;
+    if let Some(query_log) = &*QUERY_LOG {
+        if let Some(query) = message.queries().first() {
+            query_log.record(&query.name().to_utf8(), query.query_type(), matches!(answer.outcome, Outcome::Blocked), &ip);
         }
-    };
 
-    match Message::from_bytes(payload.as_ref()) {
-        Ok(message) => Ok(message),
-        Err(err) => {
-            println!("Failed to parse DNS message: {}", err);
-            Err(BadRequestError::new("Invalid DNS message"))?
+        // Flush before responding, as the instance may be frozen right after
+        if let Err(err) = query_log.flush_if_due().await {
+            println!("{:#}", err);
         }
     }
-}
 
-async fn message_from_post(request: Request) -> Result<Message> {
-    let body = request.body();
+    let mut response = answer.response;
 
-    match body {
-        Body::Empty => Err(BadRequestError::new("Empty body"))?,
+    if let Some(limit) = config.additional_records_limit {
+        // The explanations are how the block is reported, not extra data
+        let explanations: Vec<Record> = match (answer.outcome, config.block_explain_txt) {
+            (Outcome::Blocked, true) => message.queries().iter().map(|query| block_explanation(config, query.name())).collect(),
+            _ => Vec::new()
+        };
 
-        Body::Text(_) => Err(BadRequestError::new("Text body"))?,
+        trim_additionals(&mut response, limit, &explanations);
+    }
 
-        Body::Binary(data) => match Message::from_bytes(data.as_ref()) {
-            Ok(message) => {
-                println!("dns request message base64-URL encoded: {}", base64_url::encode(data));
-                Ok(message)
-            },
-            Err(err) => {
-                println!("Failed to parse DNS message: {}", err);
-                Err(BadRequestError::new("Invalid DNS message"))?
-            }
-        }
+    if message.queries().first().map(|query| query.query_type()) == Some(RecordType::ANY) {
+        cap_any_response(&mut response, config.any_response_size_limit);
+    }
+
+    fit_response_size(&mut response, &message, config.response_size_limit, config.oversized_responses);
+
+    // Last, as any change to the response changes its padded size
+    pad_response(&mut response, &message, config.padding_block_size, config.force_padding);
+
+    match format {
+        ResponseFormat::Wire => dns_response(&response, answer.max_age),
+        format => debug_response(&response, format)
     }
-}
\ No newline at end of file
+}
+
+// The response format forced with the `format` query string parameter, only
+// honoured when debug endpoints are enabled
+fn debug_format_param(config: &Config, query: Option<&str>) -> Result<ResponseFormat> {
+    if !config.debug_endpoints {
+        return Ok(ResponseFormat::Wire);
+    }
+
+    match url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()).find(|(name, _)| name == "format") {
+        Some((_, format)) => format.parse(),
+        None => Ok(ResponseFormat::Wire)
+    }
+}
+
+// Time left to answer before Lambda kills the invocation, less a margin to
+// send the response in. None when the deadline is unknown.
+fn handler_budget(config: &Config, deadline_ms: u64) -> Option<Duration> {
+    if deadline_ms == 0 {
+        return None;
+    }
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+
+    Some(Duration::from_millis(deadline_ms.saturating_sub(now_ms).saturating_sub(config.handler_timeout_margin.as_millis() as u64)))
+}
+
+fn has_dns_query_param(config: &Config, query: Option<&str>) -> bool {
+    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .any(|(name, _)| name == config.dns_query_param.as_str())
+}
+
+// For browsers and crawlers visiting the endpoint root, rather than a bare
+// "bad request"
+fn landing_response(config: &Config) -> Result<Response<Body>, lambda_http::Error> {
+    let (status, body) = match config.root_response {
+        RootResponse::Landing => (StatusCode::OK, LANDING_PAGE),
+        RootResponse::NotFound => (StatusCode::NOT_FOUND, "Not found\n")
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("X-Robots-Tag", "noindex")
+        .body(Body::from(body))?)
+}
+
+// Keeps as many leading answers as fit within `limit` bytes, dropping the
+// authority and additional sections
+fn cap_any_response(response: &mut Message, limit: usize) {
+    match response.to_bytes() {
+        Ok(bytes) if bytes.len() > limit => {},
+        _ => return
+    };
+
+    let answers = response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+
+    let total = answers.len();
+
+    for answer in answers {
+        response.add_answer(answer);
+
+        if response.to_bytes().map(|bytes| bytes.len() > limit).unwrap_or(true) {
+            response.answers_mut().pop();
+            break;
+        }
+    }
+
+    println!("Capped ANY response to {} of {} answers", response.answers().len(), total);
+}
+
+// Keeps the response within what DNS and the HTTP transport can carry: a DNS
+// message can't be longer than 65535 bytes (RFC 1035 section 4.2.2, RFC 8484
+// section 6), and API Gateway rejects responses over `limit` bytes with an
+// opaque error. The encoder leaves out whatever doesn't fit in a DNS message,
+// setting TC, which would send part of an RRset.
+fn fit_response_size(response: &mut Message, request: &Message, limit: usize, handling: OversizedResponseHandling) {
+    let oversized = match response.to_bytes() {
+        Ok(bytes) => (is_truncated(&bytes) && !response.truncated()) || bytes.len().div_ceil(3) * 4 + RESPONSE_ENVELOPE_OVERHEAD > limit,
+        Err(_) => true
+    };
+
+    if !oversized {
+        return;
+    }
+
+    println!("Response exceeds the DNS message or HTTP response size limit, handling as {:?}", handling);
+
+    response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+
+    match handling {
+        OversizedResponseHandling::Truncate => {
+            response.set_truncated(true);
+        },
+        OversizedResponseHandling::ServFail => {
+            response.set_response_code(ServFail);
+            set_extended_error(response, request, EDE_OTHER, "Response too large for DNS over HTTPS");
+        }
+    };
+}
+
+// A DNS response to send back, along with how it was arrived at
+struct Answer {
+    response: Message,
+    outcome: Outcome,
+    // Overrides the HTTP cache lifetime of the response
+    max_age: Option<u32>
+}
+
+impl Answer {
+    fn new(response: Message, outcome: Outcome) -> Self {
+        Self { response, outcome, max_age: None }
+    }
+}
+
+// With ANSWER_ALL_QUESTIONS, a message with several distinct questions gets
+// each answered on its own, merged in the order asked: questions are echoed
+// in order, and in every section each question's records follow those of the
+// questions before it. SERVFAIL for any question fails the whole message,
+// keeping the records of the others. Otherwise the first question answered
+// with an error sets the response code.
+async fn answer_questions(config: &Config, message: &Message, ip: &str) -> Result<Answer> {
+    let mut queries: Vec<Query> = Vec::new();
+    for query in message.queries() {
+        if !queries.contains(query) {
+            queries.push(query.clone());
+        }
+    }
+
+    if !config.answer_all_questions || queries.len() <= 1 || message.op_code() != OpCode::Query {
+        return answer_query(config, message, ip).await;
+    }
+
+    println!("Answering {} questions separately", queries.len());
+
+    let answers = join_all(queries.iter().map(|query| {
+        let mut single = message.clone();
+        single.take_queries();
+        single.add_query(query.clone());
+
+        async move { answer_query(config, &single, ip).await }
+    })).await
+        .into_iter()
+        .collect::<Result<Vec<Answer>>>()?;
+
+    let mut response = answers[0].response.clone();
+    response.take_queries();
+    response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+    response.add_queries(queries);
+
+    let mut outcome = Outcome::Resolved;
+    let mut max_age = None;
+    let mut response_code = NoError;
+
+    for answer in answers {
+        let mut single = answer.response;
+
+        if single.response_code() == ServFail || (response_code == NoError && single.response_code() != NoError) {
+            response_code = single.response_code();
+        }
+
+        if single.truncated() {
+            response.set_truncated(true);
+        }
+
+        response.add_answers(single.take_answers());
+        response.add_name_servers(single.take_name_servers());
+        for record in single.take_additionals() {
+            response.add_additional(record);
+        }
+
+        outcome = match (outcome, answer.outcome) {
+            (Outcome::Error, _) | (_, Outcome::Error) => Outcome::Error,
+            (Outcome::Blocked, _) | (_, Outcome::Blocked) => Outcome::Blocked,
+            _ => Outcome::Resolved
+        };
+
+        max_age = match (max_age, answer.max_age) {
+            (Some(merged), Some(single)) => Some(u32::min(merged, single)),
+            (merged, single) => merged.or(single)
+        };
+    }
+
+    response.set_response_code(response_code);
+
+    Ok(Answer { max_age, ..Answer::new(response, outcome) })
+}
+
+// The response starts as a copy of the query, so the DNSSEC flags would
+// otherwise just echo the client's. Only the response's flags are set here:
+// the resolver builds its own upstream queries, always with AD and CD clear,
+// so upstream validates regardless of the client's CD.
+fn set_dnssec_flags(response: &mut Message, strip_ad: bool, propagate_cd: bool) {
+    if strip_ad {
+        response.set_authentic_data(false);
+    }
+
+    if !propagate_cd {
+        response.set_checking_disabled(false);
+    }
+}
+
+async fn answer_query(config: &Config, message: &Message, ip: &str) -> Result<Answer> {
+    let mut response = message.clone();
+    response
+        .set_message_type(MessageType::Response)
+        .set_recursion_available(true);
+
+    set_dnssec_flags(&mut response, config.strip_ad_flag, config.propagate_cd_flag);
+
+    // Identical questions are resolved and echoed back only once
+    let mut queries: Vec<Query> = Vec::new();
+    for query in response.take_queries() {
+        if !queries.contains(&query) {
+            queries.push(query);
+        }
+    }
+
+    if queries.len() < message.queries().len() {
+        println!("Collapsed {} duplicate questions", message.queries().len() - queries.len());
+    }
+
+    response.add_queries(queries);
+
+    // NOTIFY, UPDATE, etc. have no meaning for a recursive DoH resolver and
+    // must not be forwarded
+    if message.op_code() != OpCode::Query {
+        println!("Unsupported opcode {:?}, returning NotImp", message.op_code());
+        response.set_response_code(NotImp);
+
+        return Ok(Answer::new(response, Outcome::Error));
+    }
+
+    // While the DNS protocol supports multiple questions in theory,
+    // in practice no one supports it (i.e. BIND doesn't...)
+    let query = match message.queries().first() {
+        Some(query) => query,
+        None => {
+            println!("Query has no question, returning FormErr");
+            response.set_response_code(FormErr);
+
+            return Ok(Answer::new(response, Outcome::Error));
+        }
+    };
+    let domain = query.name().to_utf8();
+    let mut domain_without_last_period = domain.clone();
+
+    if domain.ends_with('.') {
+        domain_without_last_period.remove(domain.chars().count() - 1);
+    }
+
+    if config.require_edns && message.edns().is_none() {
+        println!("Query has no EDNS OPT record, returning FormErr");
+        response.set_response_code(FormErr);
+
+        return Ok(Answer::new(response, Outcome::Error));
+    }
+
+    if let Some(secret) = &config.dns_cookies {
+        match request_cookie(message) {
+            Ok(Some(cookie)) => {
+                set_cookie(&mut response, &cookie.client, &server_cookie(secret, &cookie.client, ip));
+
+                let valid = cookie.server
+                    .map(|server| is_valid_server_cookie(secret, &cookie.client, &server, ip))
+                    .unwrap_or(false);
+
+                // The fresh server cookie lets the client retry straight away
+                if config.require_dns_cookies && !valid {
+                    println!("Query has no valid server cookie, returning BadCookie");
+                    response.set_response_code(BADCOOKIE);
+
+                    return Ok(Answer::new(response, Outcome::Error));
+                }
+            },
+            // Without a client cookie there's no server cookie to hand out,
+            // and without an OPT record not even BADCOOKIE can be sent
+            Ok(None) if config.require_dns_cookies => {
+                let response_code = match message.edns() {
+                    Some(_) => BADCOOKIE,
+                    None => Refused
+                };
+
+                println!("Query has no DNS cookie, returning {:?}", response_code);
+                response.set_response_code(response_code);
+
+                return Ok(Answer::new(response, Outcome::Error));
+            },
+            Ok(None) => {},
+            Err(err) => {
+                println!("Bad request: {}", err);
+                response.set_response_code(FormErr);
+
+                return Ok(Answer::new(response, Outcome::Error));
+            }
+        };
+    }
+
+    if query.query_type() == RecordType::ANY && !config.allow_any {
+        println!("ANY queries are disabled, returning Refused");
+        response.set_response_code(Refused);
+
+        return Ok(Answer::new(response, Outcome::Error));
+    }
+
+    if config.maintenance_mode {
+        println!("Maintenance mode enabled, returning ServFail");
+        response.set_response_code(ServFail);
+        set_extended_error(&mut response, message, EDE_NETWORK_ERROR, "Upstream resolver under maintenance");
+
+        return Ok(Answer { max_age: Some(config.maintenance_ttl), ..Answer::new(response, Outcome::Error) });
+    }
+
+    let view = VIEWS.select(ip);
+
+    if let Some(view) = view {
+        println!("Client IP {} matches view '{}'", ip, view.name);
+    }
+
+    let overrides = view
+        .and_then(|view| view.overrides.lookup(query.name(), query.query_type()))
+        .or_else(|| OVERRIDES.lookup(query.name(), query.query_type()));
+
+    if let Some(records) = overrides {
+        println!("Domain '{}' matches static override, returning pinned records", domain);
+        response.add_answers(records);
+
+        return Ok(Answer::new(response, Outcome::Resolved));
+    }
+
+    if let (RecordType::PTR, Some(marker)) = (query.query_type(), &config.block_marker) {
+        if is_sinkhole_reverse_name(config, query.name()) {
+            println!("Domain '{}' is the reverse name of a sinkhole address, returning block marker", domain);
+            response.add_answer(Record::from_rdata(query.name().clone(), config.negative_ttl, RData::PTR(marker.clone())));
+
+            return Ok(Answer::new(response, Outcome::Resolved));
+        }
+    }
+
+    if domain_without_last_period.parse::<IpAddr>().is_ok() {
+        let response_code = match config.ip_literal_queries {
+            IpLiteralHandling::NXDomain => Some(NXDomain),
+            IpLiteralHandling::Refused => Some(Refused),
+            IpLiteralHandling::Forward => None
+        };
+
+        if let Some(response_code) = response_code {
+            println!("Domain '{}' is an IP literal, returning {}", domain, response_code);
+            response.set_response_code(response_code);
+
+            if response_code == NXDomain {
+                response.add_name_server(negative_soa(config, query.name()));
+            }
+
+            return Ok(Answer::new(response, Outcome::Resolved));
+        }
+    }
+
+    // CD only asks the resolver to skip DNSSEC validation. Blocking is policy,
+    // not validation, so it applies whatever the flag says.
+    let mut blocked = is_blocked(config, &domain_without_last_period, view)
+        .with_context(|| "Failed to query deny/allow list database")?;
+
+    let mut upstream_results = None;
+
+    if blocked && config.cname_uncloaking {
+        // Resolve first to find out whether the blocked name is just fronting
+        // allow-listed content, e.g. a CDN
+        let results = upstream_lookup(&domain, query.query_type()).await;
+
+        if let Ok(results) = &results {
+            let records: Vec<Record> = results.record_iter().cloned().collect();
+
+            if exceeds_cname_hops(config, query.name(), &records) {
+                println!("Upstream answer for '{}' has more than {} CNAME hops, returning ServFail", domain, config.max_cname_hops);
+                response.set_response_code(ServFail);
+
+                return Ok(Answer::new(response, Outcome::Error));
+            }
+
+            if let Some(target) = allowed_cname_target(query.name(), &records, view)
+                .with_context(|| "Failed to query deny/allow list database")? {
+                println!("Domain '{}' is a CNAME to allow-listed '{}', unblocking", domain, target);
+                blocked = false;
+            }
+        }
+
+        upstream_results = Some(results);
+    }
+
+    if blocked {
+        let category = deny_category(config, &domain_without_last_period)
+            .with_context(|| "Failed to query deny/allow list database")?;
+
+        match &category {
+            Some(category) => println!("Domain '{}' is blocked (category: {}), returning NXDomain", domain, category),
+            None => println!("Domain '{}' is blocked, returning NXDomain", domain)
+        };
+
+        // Answering NXDOMAIN for a name that exists gives the block away, so
+        // in stealth mode existing names get NODATA instead, and no EDE
+        if config.stealth_blocking {
+            if !name_exists(&domain, query.query_type()).await {
+                response.set_response_code(NXDomain);
+            } else {
+                println!("Domain '{}' exists upstream, returning NODATA instead", domain);
+            }
+
+            response.add_name_server(negative_soa(config, query.name()));
+
+            return Ok(Answer::new(response, Outcome::Blocked));
+        }
+
+        return Ok(blocked_answer(config, response, message, query, category.as_deref()));
+    }
+
+    if query.query_type() == RecordType::AAAA && config.synth_nodata_aaaa && has_a_records(&domain).await {
+        println!("Domain '{}' has A records, returning NODATA for AAAA", domain);
+        response.add_name_server(negative_soa(config, query.name()));
+
+        return Ok(Answer::new(response, Outcome::Resolved));
+    }
+
+    println!("Domain '{}' is not blocked, proxying query...", domain);
+    let results = match upstream_results {
+        Some(results) => results,
+        None => upstream_lookup(&domain, query.query_type()).await
+    };
+
+    // Only reached for names that aren't blocked
+    if query.query_type() == RecordType::HTTPS && config.synthesize_https_records && lacks_https_records(&results) {
+        if let Some(record) = synthesized_https_record(config, query.name(), &domain).await {
+            println!("Upstream has no HTTPS records for '{}', returning synthesized record", domain);
+            response.add_answer(record);
+
+            return Ok(Answer::new(response, Outcome::Resolved));
+        }
+    }
+
+    if let (RecordType::AAAA, Some(prefix)) = (query.query_type(), &config.dns64_prefix) {
+        if lacks_aaaa_records(&results) {
+            if let Ok(a_results) = upstream_lookup(&domain, RecordType::A).await {
+                let a_records: Vec<Record> = a_results.record_iter().cloned().collect();
+                let records = synthesize_aaaa(&a_records, prefix);
+
+                if records.iter().any(|record| record.record_type() == RecordType::AAAA) {
+                    println!("Upstream has no AAAA records for '{}', returning DNS64 synthesized records", domain);
+                    response.add_answers(records);
+
+                    return Ok(Answer::new(response, Outcome::Resolved));
+                }
+            }
+        }
+    }
+
+    match results {
+        // The upstream answered without error but with no records, which is
+        // NODATA: answer NOERROR with an SOA so clients can cache it
+        Ok(results) if results.record_iter().next().is_none() => {
+            println!("Upstream returned no records for '{}', returning NODATA", domain);
+            response.add_name_server(negative_soa(config, query.name()));
+        },
+        Ok(results) => {
+            let mut records: Vec<Record> = results.record_iter().cloned().collect();
+
+            if exceeds_cname_hops(config, query.name(), &records) {
+                println!("Upstream answer for '{}' has more than {} CNAME hops, returning ServFail", domain, config.max_cname_hops);
+                response.set_response_code(ServFail);
+
+                return Ok(Answer::new(response, Outcome::Error));
+            }
+
+            if !answers_chain_from(query.name(), &records) {
+                match config.answer_name_mismatch {
+                    AnswerNameMismatchHandling::Accept => {},
+                    AnswerNameMismatchHandling::Log => {
+                        println!("Upstream answer for '{}' contains records that don't chain from the question", domain);
+                    },
+                    AnswerNameMismatchHandling::ServFail => {
+                        println!("Upstream answer for '{}' contains records that don't chain from the question, returning ServFail", domain);
+                        response.set_response_code(ServFail);
+
+                        return Ok(Answer::new(response, Outcome::Error));
+                    }
+                };
+            }
+
+            // Only answers that passed the checks above, so serving stale can't
+            // hand out one that was refused
+            if let Some(stale_cache) = &*STALE_CACHE {
+                stale_cache.insert(&domain, query.query_type(), &records);
+            }
+
+            if config.rotate_answers {
+                rotate_answers(&mut records);
+            }
+
+            // The resolver cache hands back records with the TTLs they were
+            // cached with, so cap them at the lifetime the cached lookup has
+            // left. Once that runs out the resolver queries upstream again.
+            let remaining_ttl = results
+                .valid_until()
+                .saturating_duration_since(Instant::now())
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX);
+
+            for mut answer in records {
+                answer.set_ttl(answer.ttl().min(remaining_ttl));
+
+                // Upstream lookups are made lowercase, so records owned by the
+                // question name are given it back as the client spelled it,
+                // matching the echoed question for clients that check case
+                if answer.name() == query.name() {
+                    answer.set_name(query.name().clone());
+                }
+
+                if let (Some(min_ttl), true) = (config.min_upstream_ttl, config.min_upstream_ttl_for_clients) {
+                    answer.set_ttl(answer.ttl().max(min_ttl));
+                }
+
+                response.add_answer(answer);
+            }
+        },
+        Err(err) => {
+            match err.kind() {
+                NoRecordsFound { .. } => {
+                    response.set_response_code(NXDomain);
+                    response.add_name_server(negative_soa(config, query.name()));
+                },
+                Proto(_) => {
+                    println!("Invalid domain: {}", domain_without_last_period);
+                    response.set_response_code(NXDomain);
+                    response.add_name_server(negative_soa(config, query.name()));
+                },
+                _ => {
+                    println!("No upstream reachable for '{}': {}", domain, err);
+
+                    return Ok(unreachable_upstream_answer(config, response, message, &domain, query.query_type()));
+                }
+            };
+        }
+    };
+
+    Ok(Answer::new(response, Outcome::Resolved))
+}
+
+// Answers a blocked name outside stealth mode, per its deny list category and
+// the block mode
+fn blocked_answer(config: &Config, mut response: Message, request: &Message, query: &Query, category: Option<&str>) -> Answer {
+    if category == Some(NRD_CATEGORY) && config.nrd_handling == NrdHandling::NoData {
+        println!("Domain '{}' is newly registered, returning NODATA instead", query.name());
+        response.add_name_server(negative_soa(config, query.name()));
+        set_extended_error(&mut response, request, EDE_FILTERED, "Newly registered domain");
+
+        return Answer::new(response, Outcome::Blocked);
+    }
+
+    let sinkhole_address = match (config.block_mode, query.query_type()) {
+        (BlockMode::Sinkhole, RecordType::A) => Some(RData::A(Ipv4Addr::UNSPECIFIED)),
+        (BlockMode::Sinkhole, RecordType::AAAA) => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED)),
+        _ => None
+    };
+
+    match sinkhole_address {
+        Some(address) => {
+            println!("Returning sinkhole address for blocked domain '{}'", query.name());
+            response.add_answer(Record::from_rdata(query.name().clone(), config.negative_ttl, address));
+        },
+        None => {
+            response.set_response_code(NXDomain);
+            response.add_name_server(negative_soa(config, query.name()));
+        }
+    };
+
+    set_extended_error(&mut response, request, EDE_FILTERED, config.block_info_url.as_deref().unwrap_or_default());
+
+    if config.block_explain_txt {
+        response.add_additional(block_explanation(config, query.name()));
+    }
+
+    Answer::new(response, Outcome::Blocked)
+}
+
+fn unreachable_upstream_answer(config: &Config, mut response: Message, request: &Message, domain: &str, query_type: RecordType) -> Answer {
+    let handling = match (config.upstream_unreachable, &*STALE_CACHE) {
+        (UpstreamUnreachableHandling::ServeStale, Some(stale_cache)) => match stale_cache.get(domain, query_type, STALE_ANSWER_TTL) {
+            Some(records) => {
+                println!("Serving stale answer for '{}'", domain);
+                response.add_answers(records);
+                set_extended_error(&mut response, request, EDE_STALE_ANSWER, "");
+
+                return Answer::new(response, Outcome::Resolved);
+            },
+            // Nothing to serve, fall back to the default
+            None => UpstreamUnreachableHandling::ServFail
+        },
+        (handling, _) => handling
+    };
+
+    match handling {
+        UpstreamUnreachableHandling::Refused => {
+            response.set_response_code(Refused);
+        },
+        _ => {
+            response.set_response_code(ServFail);
+            set_extended_error(&mut response, request, EDE_NETWORK_ERROR, "No upstream resolver reachable");
+        }
+    };
+
+    Answer::new(response, Outcome::Error)
+}
+
+async fn upstream_lookup(domain: &str, query_type: RecordType) -> Result<Lookup, ResolveError> {
+    let domain = domain.to_lowercase();
+
+    if let Some(lookup) = RESPONSE_CACHE.as_ref().and_then(|cache| cache.get(&domain, query_type)) {
+        return Ok(lookup);
+    }
+
+    let results = UPSTREAM_LOOKUPS
+        .run((domain.clone(), query_type), {
+            let domain = domain.clone();
+            move || resolve_shared(domain, query_type)
+        })
+        .await;
+
+    if let (Some(cache), Ok(lookup)) = (RESPONSE_CACHE.as_ref(), &results) {
+        cache.insert(&domain, query_type, lookup);
+    }
+
+    results
+}
+
+// Answers from the shared cache when it has the name, and otherwise resolves
+// upstream and shares the answer with other instances
+async fn resolve_shared(domain: String, query_type: RecordType) -> Result<Lookup, ResolveError> {
+    let shared_cache = match &*SHARED_CACHE {
+        Some(shared_cache) => shared_cache,
+        None => return resolve_upstream(domain, query_type).await
+    };
+
+    if let Some(lookup) = shared_cache.get(&domain, query_type).await {
+        return Ok(lookup);
+    }
+
+    let results = resolve_upstream(domain.clone(), query_type).await;
+
+    if let Ok(lookup) = &results {
+        shared_cache.insert(&domain, query_type, lookup).await;
+    }
+
+    results
+}
+
+async fn resolve_upstream(domain: String, query_type: RecordType) -> Result<Lookup, ResolveError> {
+    let resolver = match &*RESOLVER {
+        Ok(resolver) => resolver,
+        Err(err) => return Err(format!("Upstream resolver unavailable: {:#}", err).into())
+    };
+
+    let upstreams = Upstreams {
+        by_type: TYPE_RESOLVERS.as_ref().ok(),
+        resolver: &**resolver,
+        fallback: match &*FALLBACK_RESOLVER {
+            Ok(Some(fallback)) => Some(&**fallback),
+            _ => None
+        },
+        circuit_breaker: UPSTREAM_CIRCUIT_BREAKER.as_ref()
+    };
+
+    upstreams.lookup(&domain, query_type).await
+}
+
+// Where upstream queries go: the resolver for the query type if there is one,
+// otherwise the default resolver, falling back to the plaintext resolver when
+// it fails or has failed recently
+struct Upstreams<'a> {
+    by_type: Option<&'a HashMap<RecordType, Box<dyn UpstreamResolver>>>,
+    resolver: &'a dyn UpstreamResolver,
+    fallback: Option<&'a dyn UpstreamResolver>,
+    circuit_breaker: Option<&'a CircuitBreaker>
+}
+
+impl Upstreams<'_> {
+    async fn lookup(&self, domain: &str, query_type: RecordType) -> Result<Lookup, ResolveError> {
+        if let Some(resolver) = self.by_type.and_then(|by_type| by_type.get(&query_type)) {
+            println!("Querying the {} upstream resolver", query_type);
+            return resolver.lookup(domain, query_type).await;
+        }
+
+        if let (Some(fallback), Some(true)) = (self.fallback, self.circuit_breaker.map(CircuitBreaker::is_open)) {
+            println!("Upstream resolver failed recently, querying plaintext resolver");
+            return fallback
+                .lookup(domain, query_type)
+                .await;
+        }
+
+        let results = self.resolver
+            .lookup(domain, query_type)
+            .await;
+
+        let failed = matches!(&results, Err(err) if !matches!(err.kind(), NoRecordsFound { .. }));
+
+        if let Some(circuit_breaker) = self.circuit_breaker {
+            match failed {
+                true => circuit_breaker.record_failure(),
+                false => circuit_breaker.record_success()
+            };
+        }
+
+        match (&results, self.fallback) {
+            (Err(err), Some(fallback)) if failed => {
+                println!("Upstream query failed, falling back to plaintext resolver: {}", err);
+                fallback
+                    .lookup(domain, query_type)
+                    .await
+            },
+            _ => results
+        }
+    }
+}
+
+// Whether upstream knows the name, even if not for this record type. Lookup
+// failures count as nonexistent.
+async fn name_exists(domain: &str, query_type: RecordType) -> bool {
+    match upstream_lookup(domain, query_type).await {
+        Ok(_) => true,
+        Err(err) => matches!(err.kind(), NoRecordsFound { response_code, .. } if *response_code != NXDomain)
+    }
+}
+
+async fn has_a_records(domain: &str) -> bool {
+    match upstream_lookup(domain, RecordType::A).await {
+        Ok(results) => results.record_iter().any(|record| record.record_type() == RecordType::A),
+        Err(_) => false
+    }
+}
+
+fn lacks_https_records(results: &Result<Lookup, ResolveError>) -> bool {
+    match results {
+        Ok(results) => !results.record_iter().any(|record| record.record_type() == RecordType::HTTPS),
+        Err(err) => matches!(err.kind(), NoRecordsFound { .. })
+    }
+}
+
+// NODATA for AAAA, but not NXDOMAIN, as a name that doesn't exist has no A
+// records to synthesize from either
+fn lacks_aaaa_records(results: &Result<Lookup, ResolveError>) -> bool {
+    match results {
+        Ok(results) => !results.record_iter().any(|record| record.record_type() == RecordType::AAAA),
+        Err(err) => matches!(err.kind(), NoRecordsFound { response_code, .. } if *response_code != NXDomain)
+    }
+}
+
+async fn synthesized_https_record(config: &Config, name: &Name, domain: &str) -> Option<Record> {
+    let (a_results, aaaa_results) = tokio::join!(
+        upstream_lookup(domain, RecordType::A),
+        upstream_lookup(domain, RecordType::AAAA)
+    );
+
+    let address_records: Vec<Record> = [a_results, aaaa_results]
+        .into_iter()
+        .flatten()
+        .flat_map(|results| results.record_iter().cloned().collect::<Vec<Record>>())
+        .collect();
+
+    synthesize_https(name, &address_records, &config.synthesized_https_alpn)
+}
+
+fn dns_response(response: &Message, max_age: Option<u32>) -> Result<Response<Body>, lambda_http::Error> {
+    // The default (non-canonical) encoder compresses names, replacing repeated
+    // suffixes such as the question name in every answer with pointers
+    let response_bytes = response.to_bytes().expect("Failed to serialize response");
+
+    println!("Done!");
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/dns-message");
+
+    if let Some(max_age) = max_age {
+        builder = builder.header("Cache-Control", format!("max-age={}", max_age));
+    }
+
+    Ok(builder.body(Body::from(response_bytes))?)
+}
+
+// A readable rendering of `response`, for checking answers from a browser
+fn debug_response(response: &Message, format: ResponseFormat) -> Result<Response<Body>, lambda_http::Error> {
+    let (content_type, body) = match format {
+        ResponseFormat::Json => ("application/json", serde_json::to_string(&debug_format::to_json(response))?),
+        ResponseFormat::PrettyJson => ("application/json", serde_json::to_string_pretty(&debug_format::to_json(response))?),
+        _ => ("text/plain", debug_format::to_text(response))
+    };
+
+    println!("Done!");
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(Body::from(body))?)
+}
+
+fn is_denied(config: &Config, domain: &str) -> Result<bool> {
+    if !config.suffix_matching {
+        return is_denied_exactly(domain);
+    }
+
+    if is_allowed(domain, None)? {
+        return Ok(false);
+    }
+
+    if let Some(database) = &*LIST_DATABASE {
+        return database.is_denied_under(domain);
+    }
+
+    for candidate in parent_domains(domain) {
+        if is_denied_exactly(candidate)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// The category of the deny list entry for the domain, or with suffix matching
+// the closest of its parents that has one. Only the database has categories.
+fn deny_category(config: &Config, domain: &str) -> Result<Option<String>> {
+    let database = match &*LIST_DATABASE {
+        Some(database) => database,
+        None => return Ok(None)
+    };
+
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    for candidate in parent_domains(&domain).take(if config.suffix_matching { usize::MAX } else { 1 }) {
+        if let Some(category) = database.category(candidate)? {
+            return Ok(Some(category));
+        }
+    }
+
+    Ok(None)
+}
+
+fn is_denied_exactly(domain: &str) -> Result<bool> {
+    match &*LIST_DATABASE {
+        Some(database) => database.is_denied(domain),
+        None => Ok(HOSTS.contains(domain))
+    }
+}
+
+fn is_allowed(domain: &str, view: Option<&View>) -> Result<bool> {
+    if view.map(|view| view.allow.contains(domain)).unwrap_or(false) {
+        return Ok(true);
+    }
+
+    match &*LIST_DATABASE {
+        Some(database) => database.is_allowed(domain),
+        None => Ok(ALLOW.contains(domain))
+    }
+}
+
+fn is_blocked_tld(config: &Config, domain: &str) -> bool {
+    config.blocked_tlds.contains_suffix_of(domain)
+}
+
+// Whether the name is the reverse name of 0.0.0.0, ::, or any address in the
+// sinkhole networks
+fn is_sinkhole_reverse_name(config: &Config, name: &Name) -> bool {
+    if SINKHOLE_ADDRESSES.iter().any(|address| Name::from(*address) == *name) {
+        return true;
+    }
+
+    // Only full addresses, not the names of the networks themselves
+    match name.parse_arpa_name() {
+        Ok(network) if network.prefix_len() == network.max_prefix_len() => {
+            config.sinkhole_networks.iter().any(|sinkhole| sinkhole.contains(&network.addr()))
+        },
+        _ => false
+    }
+}
+
+fn exceeds_cname_hops(config: &Config, name: &Name, records: &[Record]) -> bool {
+    cname_chain(name, records).len() > config.max_cname_hops
+}
+
+// Returns the first allow-listed name in the CNAME chain of the answers
+fn allowed_cname_target(name: &Name, records: &[Record], view: Option<&View>) -> Result<Option<Name>> {
+    for target in cname_chain(name, records) {
+        let target_utf8 = target.to_utf8();
+
+        if is_allowed(target_utf8.trim_end_matches('.'), view)? {
+            return Ok(Some(target));
+        }
+    }
+
+    Ok(None)
+}
+
+fn is_blocked(config: &Config, domain: &str, view: Option<&View>) -> Result<bool> {
+    let view_name = view.map(|view| view.name.as_str());
+
+    if let Some(allow_cache) = &*ALLOW_CACHE {
+        if allow_cache.contains(view_name, domain) {
+            return Ok(false);
+        }
+    }
+
+    let decision = policy_decision(config, domain, view)?;
+
+    if let (true, Some(rule)) = (decision.blocked, decision.winning_rule) {
+        println!("Domain '{}' is blocked by rule '{}'", domain, rule);
+    }
+
+    let allow_listed = decision.trace
+        .iter()
+        .any(|trace| trace.matched && (trace.rule == "view-allow" || trace.rule == "allow-list"));
+
+    if let (false, true, Some(allow_cache)) = (decision.blocked, allow_listed, &*ALLOW_CACHE) {
+        allow_cache.insert(view_name, domain);
+    }
+
+    Ok(decision.blocked)
+}
+
+fn policy_decision(config: &Config, domain: &str, view: Option<&View>) -> Result<PolicyDecision> {
+    // List entries are lowercase without the root label
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let domain = domain.as_str();
+
+    let matches = PolicyMatches {
+        view: view.map(|view| view.name.as_str()),
+        essential: config.essential_allow_list && is_essential(domain),
+        view_allowed: view.map(|view| view.allow.contains(domain)).unwrap_or(false),
+        view_denied: view
+            .map(|view| match config.suffix_matching {
+                true => view.deny.contains_suffix_of(domain),
+                false => view.deny.contains(domain)
+            })
+            .unwrap_or(false),
+        default_deny: config.default_deny,
+        denied: is_denied(config, domain)?,
+        allowed: is_allowed(domain, None)?,
+        blocked_tld: is_blocked_tld(config, domain)
+    };
+
+    Ok(decide(&matches))
+}
+
+// An RFC 7807 problem details response, for errors from the JSON endpoints
+fn problem_response(status: StatusCode, detail: &str) -> Result<Response<Body>, lambda_http::Error> {
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+        "detail": detail
+    });
+
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/problem+json")
+        .body(Body::from(serde_json::to_string_pretty(&body)?))?)
+}
+
+// Explains the blocking decision for `name`, as seen by `client` (defaulting
+// to the caller) so view selection can be checked too:
+//
+//     GET /debug/lookup?name=ads.example&client=192.168.1.70
+async fn debug_lookup(config: &Config, request: &Request, ip: &str) -> Result<Response<Body>, lambda_http::Error> {
+    let params: Vec<(String, String)> = url::form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect();
+
+    let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+    let name = match param("name") {
+        Some(name) => name.trim_end_matches('.').to_lowercase(),
+        None => return problem_response(StatusCode::BAD_REQUEST, "Missing 'name' query string parameter")
+    };
+
+    let view = VIEWS.select(param("client").unwrap_or(ip));
+
+    let decision = match policy_decision(config, &name, view) {
+        Ok(decision) => decision,
+        Err(err) => {
+            println!("Failed to decide policy for '{}': {:#}", name, err);
+            return problem_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to query deny/allow list database");
+        }
+    };
+
+    let category = match deny_category(config, &name) {
+        Ok(category) => category,
+        Err(err) => {
+            println!("Failed to look up category for '{}': {:#}", name, err);
+            return problem_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to query list database");
+        }
+    };
+
+    let body = serde_json::json!({
+        "name": name,
+        "category": category,
+        "decision": decision
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&body)?))?)
+}
+
+// Along with whether the reserved Z bit of the header was set, which the
+// parsed message doesn't keep
+async fn message_from_get(config: &Config, request: Request) -> Result<(Message, bool)> {
+    println!("URI: {}", request.uri());
+
+    let url = Url::parse(&request.uri().to_string())?;
+
+    // Other parameters are ignored wherever they appear, e.g. padding added by
+    // clients to normalize URL lengths. If the message parameter is repeated,
+    // the first one wins.
+    let encoded_payload = match url.query_pairs().find(|pair| pair.0 == Cow::Borrowed(config.dns_query_param.as_str())) {
+        Some(pair) => pair.1,
+        None => return Err(BadRequestError::new(&format!("Missing '{}' query string parameter", config.dns_query_param)))?
+    };
+
+    let payload = match base64_url::decode(&encoded_payload.to_string()) {
+        Ok(payload) => payload,
+        Err(err) => {
+            println!("Failed to base64 decode DNS message '{}': {}", encoded_payload, err);
+            return Err(BadRequestError::new("Invalid DNS message"))?;
+        }
+    };
+
+    match Message::from_bytes(payload.as_ref()) {
+        Ok(message) => Ok((message, has_reserved_bit(&payload))),
+        Err(err) => {
+            println!("Failed to parse DNS message: {}", err);
+            Err(parse_error_request(&err))?
+        }
+    }
+}
+
+async fn message_from_post(config: &Config, request: Request) -> Result<(Message, bool)> {
+    let body = request.body();
+
+    let mut parse_error = None;
+
+    for data in post_body_candidates(config, body)? {
+        match Message::from_bytes(data.as_ref()) {
+            Ok(message) => {
+                println!("dns request message base64-URL encoded: {}", base64_url::encode(&data));
+                return Ok((message, has_reserved_bit(&data)));
+            },
+            // Reported for the most likely encoding
+            Err(err) => {
+                parse_error.get_or_insert(err);
+            }
+        }
+    }
+
+    match parse_error {
+        Some(err) => {
+            println!("Failed to parse DNS message: {}", err);
+            Err(parse_error_request(&err))?
+        },
+        None => Err(BadRequestError::new("Invalid DNS message"))?
+    }
+}
+
+// Messages cut short, e.g. by a client sending only part of its buffer, are
+// told apart from garbage to help debug client serialization
+fn parse_error_request(err: &ProtoError) -> BadRequestError {
+    match err.kind() {
+        ProtoErrorKind::Msg(message) if *message == DecodeError::InsufficientBytes.to_string() => {
+            BadRequestError::new("Truncated DNS message, the data ended before the message did")
+        },
+        _ => BadRequestError::new("Invalid DNS message")
+    }
+}
+
+// The Z bit sits between RA and AD in the fourth byte of the header, and must
+// be zero (RFC 1035 section 4.1.1)
+fn has_reserved_bit(wire: &[u8]) -> bool {
+    wire.get(3).map(|flags| flags & 0b0100_0000 != 0).unwrap_or(false)
+}
+
+// TC is the second lowest bit of the third byte of the header
+fn is_truncated(wire: &[u8]) -> bool {
+    wire.get(2).map(|flags| flags & 0b0000_0010 != 0).unwrap_or(false)
+}
+
+// API Gateway only decodes the body for us when it flags the request as
+// base64-encoded, which depends on its binary media types configuration. The
+// wire bytes may therefore arrive raw or still base64-encoded in either body
+// variant. Returns the candidate wire encodings to try, most likely first.
+fn post_body_candidates(config: &Config, body: &Body) -> Result<Vec<Vec<u8>>> {
+    let (raw, text) = match body {
+        Body::Empty => Err(BadRequestError::new("Empty body"))?,
+        Body::Text(text) => (text.as_bytes(), Some(text.as_str())),
+        Body::Binary(data) => (data.as_slice(), std::str::from_utf8(data).ok())
+    };
+
+    let decoded = text.and_then(decode_base64);
+
+    Ok(match (config.post_body_encoding, body) {
+        (BodyEncoding::Raw, Body::Text(_)) => Err(BadRequestError::new("Text body"))?,
+        (BodyEncoding::Raw, _) => vec![raw.to_vec()],
+        (BodyEncoding::Base64, _) => match decoded {
+            Some(decoded) => vec![decoded],
+            None => Err(BadRequestError::new("Body is not base64 encoded"))?
+        },
+        (BodyEncoding::Auto, Body::Text(_)) => decoded.into_iter().chain([raw.to_vec()]).collect(),
+        (BodyEncoding::Auto, _) => [raw.to_vec()].into_iter().chain(decoded).collect()
+    })
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+
+    [base64::STANDARD, base64::URL_SAFE, base64::STANDARD_NO_PAD, base64::URL_SAFE_NO_PAD]
+        .into_iter()
+        .find_map(|config| base64::decode_config(text, config).ok())
+}
+
+// End to end tests of the handler, answering from canned records in test mode
+// with the lists below. The lists and upstream are loaded once per process
+// from the environment set up here, and tests of other settings pass the
+// handler a config of their own.
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs
+    };
+
+    use lambda_http::{
+        http,
+        request::RequestContext
+    };
+
+    use tokio::sync::OnceCell;
+
+    use trust_dns_proto::{
+        op::Edns,
+        rr::rdata::opt::{
+            EdnsCode,
+            EdnsOption
+        },
+        serialize::binary::BinEncoder
+    };
+
+    use crate::config::Settings;
+
+    use super::*;
+
+    const STUB_ANSWERS: &str = "
+example.com A 93.184.216.34
+example.com A 93.184.216.35
+ok.ads.example A 192.0.2.10
+broken.example SERVFAIL
+pinned.example A 93.184.216.40
+short-ttl.example A 192.0.2.20 5
+files.zip A 192.0.2.30
+ok.zip A 192.0.2.31
+192.0.2.1 A 192.0.2.1
+countdown.example A 192.0.2.40 4
+dual.example A 192.0.2.50
+dual.example AAAA 2001:db8::50
+exists.ads.example A 192.0.2.60
+";
+
+    const HOSTS_FILE: &str = "
+ads.example
+doubleclick.net
+";
+
+    const ALLOW_FILE: &str = "
+ok.ads.example
+ok.zip
+";
+
+    const OVERRIDES_FILE: &str = "
+pinned.example A 10.1.2.3
+pinned.ads.example A 10.1.2.4
+";
+
+    async fn setup() {
+        static SETUP: OnceCell<()> = OnceCell::const_new();
+
+        SETUP.get_or_init(|| async {
+            let dir = env::temp_dir().join(format!("responder-tests-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            fs::write(dir.join("hosts"), HOSTS_FILE).unwrap();
+            fs::write(dir.join("allow"), ALLOW_FILE).unwrap();
+            fs::write(dir.join("overrides"), OVERRIDES_FILE).unwrap();
+            // Too many records for one DNS message
+            let huge: String = (0..5000).map(|index| format!("huge.example A 10.0.{}.{}\n", index / 256, index % 256)).collect();
+            fs::write(dir.join("stub-answers"), format!("{}{}", STUB_ANSWERS, huge)).unwrap();
+
+            // The lists are read from the working directory
+            env::set_current_dir(&dir).unwrap();
+
+            env::set_var("TEST_MODE", "true");
+            env::set_var("BLOCK_EXPLAIN_TXT", "true");
+            env::set_var("ADDITIONAL_RECORDS_LIMIT", "0");
+
+            validate_config().await.unwrap();
+        }).await;
+    }
+
+    fn query(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message.set_id(1234);
+        message.set_recursion_desired(true);
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        message
+    }
+
+    fn edns_query(name: &str, query_type: RecordType) -> Message {
+        let mut message = query(name, query_type);
+        message.set_edns(Edns::new());
+        message
+    }
+
+    // The info code of the response's Extended DNS Error, if any
+    fn extended_error(response: &Message) -> Option<u16> {
+        match response.edns()?.option(EdnsCode::from(15))? {
+            EdnsOption::Unknown(_, data) => Some(u16::from_be_bytes([data[0], data[1]])),
+            _ => None
+        }
+    }
+
+    fn with_contexts(builder: http::request::Builder, body: Body) -> Request {
+        let context = serde_json::from_value(serde_json::json!({ "elb": { "targetGroupArn": "test" } })).unwrap();
+
+        let mut request = builder
+            .body(body)
+            .unwrap()
+            .with_lambda_context(lambda_http::Context::default());
+
+        request.extensions_mut().insert(RequestContext::Alb(context));
+        request
+    }
+
+    // The settings `setup` gives the shared config, with others on top, for
+    // requests to exercise the behaviour they enable
+    fn config(values: &[(&str, &str)]) -> Config {
+        let env = [("TEST_MODE", "true"), ("BLOCK_EXPLAIN_TXT", "true"), ("ADDITIONAL_RECORDS_LIMIT", "0")]
+            .iter()
+            .chain(values)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Config::from_settings(&Settings::from_env(env).unwrap()).unwrap()
+    }
+
+    async fn post(message: &Message) -> Response<Body> {
+        setup().await;
+        post_with(*CONFIG, message).await
+    }
+
+    async fn post_with(config: &Config, message: &Message) -> Response<Body> {
+        post_body(config, Body::from(message.to_bytes().unwrap())).await
+    }
+
+    async fn post_body(config: &Config, body: Body) -> Response<Body> {
+        setup().await;
+
+        let request = with_contexts(
+            http::Request::builder()
+                .method(Method::POST)
+                .uri("https://dns.test/dns-query")
+                .header("Content-Type", "application/dns-message"),
+            body
+        );
+
+        respond(config, request).await.unwrap()
+    }
+
+    async fn get(query_string: &str) -> Response<Body> {
+        setup().await;
+        get_with(*CONFIG, query_string).await
+    }
+
+    async fn get_with(config: &Config, query_string: &str) -> Response<Body> {
+        get_path(config, &format!("/dns-query?{}", query_string)).await
+    }
+
+    // The path and query string of the request
+    async fn get_path(config: &Config, path: &str) -> Response<Body> {
+        setup().await;
+
+        let request = with_contexts(
+            http::Request::builder()
+                .method(Method::GET)
+                .uri(format!("https://dns.test{}", path)),
+            Body::Empty
+        );
+
+        respond(config, request).await.unwrap()
+    }
+
+    async fn resolve(message: &Message) -> Message {
+        setup().await;
+        resolve_with(*CONFIG, message).await
+    }
+
+    async fn resolve_with(config: &Config, message: &Message) -> Message {
+        let response = post_with(config, message).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Message::from_bytes(response.body().as_ref()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolves_from_upstream() {
+        let response = resolve(&query("example.com.", RecordType::A)).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_nxdomain_for_unknown_names() {
+        let response = resolve(&query("unknown.example.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_nodata_for_missing_types() {
+        let response = resolve(&query("example.com.", RecordType::AAAA)).await;
+
+        assert_eq!(response.response_code(), NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn answers_servfail_for_failed_lookups() {
+        let response = resolve(&query("broken.example.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), ServFail);
+    }
+
+    #[tokio::test]
+    async fn resolves_get_requests() {
+        let message = query("example.com.", RecordType::A).to_bytes().unwrap();
+        let response = get(&format!("dns={}", base64_url::encode(&message))).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/dns-message");
+
+        let response = Message::from_bytes(response.body().as_ref()).unwrap();
+        assert_eq!(response.answers().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_block_explanations_past_the_additional_limit() {
+        let response = resolve(&query("ads.example.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), NXDomain);
+        assert_eq!(response.additionals().len(), 1);
+        assert_eq!(response.additionals()[0].record_type(), RecordType::TXT);
+    }
+
+    #[tokio::test]
+    async fn blocks_denied_names_and_their_subdomains() {
+        for name in ["ads.example.", "stats.g.doubleclick.net.", "Stats.DoubleClick.net."] {
+            let response = resolve(&query(name, RecordType::A)).await;
+
+            assert_eq!(response.response_code(), NXDomain, "{}", name);
+            assert!(response.answers().is_empty(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_allow_listed_subdomains_of_denied_names() {
+        let response = resolve(&query("ok.ads.example.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ignores_other_get_parameters() {
+        let message = base64_url::encode(&query("example.com.", RecordType::A).to_bytes().unwrap());
+        let other = base64_url::encode(&query("ads.example.", RecordType::A).to_bytes().unwrap());
+
+        for query_string in [
+            format!("random_padding=XXXXXXXXXXXX&dns={}", message),
+            format!("dns={}&ct=application/dns-message&random_padding=", message),
+            format!("ct&padding=%00%00&dns={}&dns={}", message, other)
+        ] {
+            let response = get(&query_string).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", query_string);
+
+            let response = Message::from_bytes(response.body().as_ref()).unwrap();
+            assert_eq!(response.queries()[0].name().to_ascii(), "example.com.", "{}", query_string);
+            assert_eq!(response.answers().len(), 2, "{}", query_string);
+        }
+    }
+
+    #[tokio::test]
+    async fn compresses_names_in_responses() {
+        let response = post(&query("example.com.", RecordType::A)).await;
+        let compressed = response.body().as_ref().to_vec();
+
+        let mut uncompressed = Vec::new();
+        let mut encoder = BinEncoder::new(&mut uncompressed);
+        encoder.set_canonical_names(true);
+        Message::from_bytes(&compressed).unwrap().emit(&mut encoder).unwrap();
+
+        // Each of the two answers refers back to the question name with a
+        // 2 byte pointer instead of repeating its 13 bytes
+        let name_length = Name::from_ascii("example.com.").unwrap().to_bytes().unwrap().len();
+        assert_eq!(uncompressed.len() - compressed.len(), 2 * (name_length - 2));
+    }
+
+    #[tokio::test]
+    async fn blocks_checking_disabled_queries() {
+        let mut message = query("ads.example.", RecordType::A);
+        message.set_checking_disabled(true);
+
+        let response = resolve(&message).await;
+
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+    }
+
+    #[test]
+    fn sets_dnssec_flags_per_config() {
+        let mut message = query("example.com.", RecordType::A);
+        message.set_authentic_data(true).set_checking_disabled(true);
+
+        for (strip_ad, propagate_cd) in [(true, true), (true, false), (false, true), (false, false)] {
+            let mut response = message.clone();
+            set_dnssec_flags(&mut response, strip_ad, propagate_cd);
+
+            assert_eq!(response.authentic_data(), !strip_ad);
+            assert_eq!(response.checking_disabled(), propagate_cd);
+        }
+    }
+
+    #[tokio::test]
+    async fn propagates_cd_by_default() {
+        let mut message = query("example.com.", RecordType::A);
+        message.set_checking_disabled(true).set_authentic_data(true);
+
+        let response = resolve(&message).await;
+
+        assert!(response.checking_disabled());
+        assert!(!response.authentic_data());
+
+        let response = resolve(&query("example.com.", RecordType::A)).await;
+
+        assert!(!response.checking_disabled());
+    }
+
+    #[tokio::test]
+    async fn answers_formerr_for_messages_without_a_question() {
+        let mut message = Message::new();
+        message.set_id(4321).set_recursion_desired(true);
+
+        let response = post(&message).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/dns-message");
+
+        let response = Message::from_bytes(response.body().as_ref()).unwrap();
+
+        assert_eq!(response.id(), 4321);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), FormErr);
+    }
+
+    #[tokio::test]
+    async fn truncates_responses_over_the_dns_message_limit() {
+        let response = resolve(&query("huge.example.", RecordType::A)).await;
+
+        assert!(response.truncated());
+        assert_eq!(response.response_code(), NoError);
+        assert!(response.answers().is_empty());
+    }
+
+    #[test]
+    fn fits_responses_to_the_http_limit() {
+        let request = query("example.com.", RecordType::A);
+
+        let mut response = request.clone();
+        response.add_answer(Record::from_rdata(Name::from_ascii("example.com.").unwrap(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))));
+
+        let mut fitting = response.clone();
+        fit_response_size(&mut fitting, &request, 6 * 1024 * 1024, OversizedResponseHandling::Truncate);
+        assert_eq!(fitting, response);
+
+        let mut truncated = response.clone();
+        fit_response_size(&mut truncated, &request, RESPONSE_ENVELOPE_OVERHEAD, OversizedResponseHandling::Truncate);
+        assert!(truncated.truncated());
+        assert!(truncated.answers().is_empty());
+
+        let mut failed = response.clone();
+        fit_response_size(&mut failed, &request, RESPONSE_ENVELOPE_OVERHEAD, OversizedResponseHandling::ServFail);
+        assert!(!failed.truncated());
+        assert_eq!(failed.response_code(), ServFail);
+        assert!(failed.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_overridden_names_with_pinned_records() {
+        let response = resolve(&query("pinned.example.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), Some(&RData::A("10.1.2.3".parse().unwrap())));
+
+        // Overrides shadow the deny list too
+        let response = resolve(&query("pinned.ads.example.", RecordType::A)).await;
+
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), Some(&RData::A("10.1.2.4".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn reuses_one_upstream_resolver_across_lookups() {
+        setup().await;
+
+        let resolver: *const dyn UpstreamResolver = &**RESOLVER.as_ref().unwrap();
+
+        resolve_upstream("example.com".to_string(), RecordType::A).await.unwrap();
+        resolve_upstream("pinned.example".to_string(), RecordType::A).await.unwrap();
+
+        assert!(std::ptr::addr_eq(resolver, &**RESOLVER.as_ref().unwrap() as *const dyn UpstreamResolver));
+    }
+
+    #[tokio::test]
+    async fn decodes_post_bodies_per_config() {
+        let message = query("example.com.", RecordType::A).to_bytes().unwrap();
+        let encoded = || Body::Text(base64::encode(&message));
+
+        // API Gateway passing the body on without decoding it
+        for encoding in ["auto", "base64"] {
+            let response = post_body(&config(&[("POST_BODY_ENCODING", encoding)]), encoded()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", encoding);
+
+            let response = Message::from_bytes(response.body().as_ref()).unwrap();
+            assert_eq!(response.answers().len(), 2, "{}", encoding);
+        }
+
+        let raw = config(&[("POST_BODY_ENCODING", "raw")]);
+        assert_eq!(post_body(&raw, encoded()).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(post_body(&raw, Body::from(message.clone())).await.status(), StatusCode::OK);
+
+        let base64 = config(&[("POST_BODY_ENCODING", "base64")]);
+        assert_eq!(post_body(&base64, Body::from(message.clone())).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn raises_answer_ttls_to_the_minimum_for_clients() {
+        let message = query("short-ttl.example.", RecordType::A);
+
+        let response = resolve(&message).await;
+        assert!(response.answers()[0].ttl() <= 5);
+
+        // The floor only reaches clients when asked for
+        let response = resolve_with(&config(&[("MIN_UPSTREAM_TTL", "60")]), &message).await;
+        assert!(response.answers()[0].ttl() <= 5);
+
+        let response = resolve_with(&config(&[("MIN_UPSTREAM_TTL", "60"), ("MIN_UPSTREAM_TTL_FOR_CLIENTS", "true")]), &message).await;
+        assert_eq!(response.answers()[0].ttl(), 60);
+    }
+
+    #[tokio::test]
+    async fn blocks_whole_tlds_unless_allow_listed() {
+        let config = config(&[("BLOCKED_TLDS", "zip")]);
+
+        let response = resolve_with(&config, &query("files.zip.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+
+        let response = resolve_with(&config, &query("ok.zip.", RecordType::A)).await;
+        assert_eq!(response.answers().len(), 1);
+
+        let response = resolve(&query("files.zip.", RecordType::A)).await;
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_servfail_during_maintenance() {
+        let config = config(&[("MAINTENANCE_MODE", "true"), ("MAINTENANCE_TTL", "30")]);
+
+        let response = post_with(&config, &edns_query("example.com.", RecordType::A)).await;
+        assert_eq!(response.headers()["Cache-Control"], "max-age=30");
+
+        let response = Message::from_bytes(response.body().as_ref()).unwrap();
+        assert_eq!(response.response_code(), ServFail);
+        assert!(response.answers().is_empty());
+        assert_eq!(extended_error(&response), Some(EDE_NETWORK_ERROR));
+    }
+
+    #[tokio::test]
+    async fn answers_ip_literal_queries_per_config() {
+        let message = query("192.0.2.1.", RecordType::A);
+
+        let response = resolve(&message).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+
+        let response = resolve_with(&config(&[("IP_LITERAL_QUERIES", "refused")]), &message).await;
+        assert_eq!(response.response_code(), Refused);
+
+        let response = resolve_with(&config(&[("IP_LITERAL_QUERIES", "forward")]), &message).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    fn stub_resolver(name: &str, answers: &str) -> StubResolver {
+        let path = env::temp_dir().join(format!("responder-tests-{}-{}", name, std::process::id()));
+        fs::write(&path, answers).unwrap();
+
+        StubResolver::load(path.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_plaintext_resolver() {
+        let resolver = stub_resolver("encrypted", "flaky.example SERVFAIL\n");
+        let fallback = stub_resolver("plaintext", "flaky.example A 192.0.2.70\nmissing.example A 192.0.2.71\n");
+        let circuit_breaker = CircuitBreaker::new(Duration::from_secs(60));
+
+        let upstreams = Upstreams { by_type: None, resolver: &resolver, fallback: Some(&fallback), circuit_breaker: Some(&circuit_breaker) };
+
+        // Upstream saying the name doesn't exist isn't a failure
+        assert!(upstreams.lookup("missing.example.", RecordType::A).await.is_err());
+        assert!(!circuit_breaker.is_open());
+
+        assert_eq!(upstreams.lookup("flaky.example.", RecordType::A).await.unwrap().record_iter().count(), 1);
+        assert!(circuit_breaker.is_open());
+
+        // Straight to the fallback until the cooldown passes
+        assert_eq!(upstreams.lookup("missing.example.", RecordType::A).await.unwrap().record_iter().count(), 1);
+
+        let upstreams = Upstreams { fallback: None, ..upstreams };
+        assert!(upstreams.lookup("flaky.example.", RecordType::A).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_notimp_for_other_opcodes() {
+        let mut message = query("example.com.", RecordType::A);
+        message.set_op_code(OpCode::Update);
+
+        let response = resolve(&message).await;
+
+        assert_eq!(response.op_code(), OpCode::Update);
+        assert_eq!(response.response_code(), NotImp);
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_get_messages_from_the_configured_parameter() {
+        let config = config(&[("DNS_QUERY_PARAM", "ct_dns")]);
+        let message = base64_url::encode(&query("example.com.", RecordType::A).to_bytes().unwrap());
+
+        let response = get_with(&config, &format!("ct_dns={}", message)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Message::from_bytes(response.body().as_ref()).unwrap().answers().len(), 2);
+
+        let response = get_with(&config, &format!("dns={}", message)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn blocks_everything_but_the_allow_list_in_default_deny_mode() {
+        let config = config(&[("DEFAULT_DENY", "true")]);
+
+        let response = resolve_with(&config, &query("example.com.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+
+        let response = resolve_with(&config, &query("ok.ads.example.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_503_beyond_the_in_flight_limit() {
+        let config = config(&[("MAX_INFLIGHT", "1")]);
+        let message = query("example.com.", RecordType::A);
+
+        // Standing in for a request still being answered
+        let permit = config.in_flight.as_ref().unwrap().try_acquire().unwrap();
+
+        let response = post_with(&config, &message).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["Retry-After"], RETRY_AFTER_SECONDS.to_string());
+
+        drop(permit);
+
+        assert_eq!(post_with(&config, &message).await.status(), StatusCode::OK);
+        assert_eq!(post_with(&config, &message).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn answers_the_endpoint_root_with_the_landing_response() {
+        setup().await;
+
+        let response = get_path(*CONFIG, "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Robots-Tag"], "noindex");
+        assert_eq!(response.body().as_ref(), LANDING_PAGE.as_bytes());
+
+        let response = get_path(&config(&[("ROOT_RESPONSE", "not-found")]), "/").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["X-Robots-Tag"], "noindex");
+    }
+
+    #[tokio::test]
+    async fn answers_duplicate_questions_once() {
+        let mut message = query("example.com.", RecordType::A);
+        message.add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+
+        for config in [config(&[]), config(&[("ANSWER_ALL_QUESTIONS", "true")])] {
+            let response = resolve_with(&config, &message).await;
+
+            assert_eq!(response.queries().len(), 1);
+            assert_eq!(response.response_code(), NoError);
+            assert_eq!(response.answers().len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_down_ttls_of_cached_answers() {
+        let message = query("countdown.example.", RecordType::A);
+
+        let response = resolve(&message).await;
+        assert_eq!(response.answers()[0].ttl(), 4);
+
+        // Halfway through the TTL, answered from the cache
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+
+        let response = resolve(&message).await;
+        assert!((1..=2).contains(&response.answers()[0].ttl()), "{}", response.answers()[0].ttl());
+    }
+
+    #[tokio::test]
+    async fn answers_nodata_for_aaaa_of_names_with_a_records_per_config() {
+        let message = query("dual.example.", RecordType::AAAA);
+
+        let response = resolve(&message).await;
+        assert_eq!(response.answers().len(), 1);
+
+        let response = resolve_with(&config(&[("SYNTH_NODATA_AAAA", "true")]), &message).await;
+        assert_eq!(response.response_code(), NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn answers_blocked_names_like_missing_ones_in_stealth_mode() {
+        let config = config(&[("STEALTH_BLOCKING", "true")]);
+
+        // Blocked and existing upstream: NODATA
+        let response = resolve_with(&config, &edns_query("exists.ads.example.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+        assert!(response.additionals().is_empty());
+        assert_eq!(extended_error(&response), None);
+
+        let response = resolve_with(&config, &edns_query("ads.example.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert_eq!(extended_error(&response), None);
+    }
+
+    #[tokio::test]
+    async fn answers_servfail_when_out_of_time() {
+        setup().await;
+
+        // The lists are never marked loaded in these tests, so the handler
+        // waits on them until its time runs out
+        let config = config(&[("LIST_LOADING", "wait"), ("HANDLER_TIMEOUT_MARGIN_MS", "100")]);
+
+        let mut context = lambda_http::Context::default();
+        context.deadline = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64 + 300;
+
+        let request = with_contexts(
+            http::Request::builder()
+                .method(Method::POST)
+                .uri("https://dns.test/dns-query"),
+            Body::from(query("example.com.", RecordType::A).to_bytes().unwrap())
+        ).with_lambda_context(context);
+
+        let started = Instant::now();
+        let response = respond(&config, request).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Message::from_bytes(response.body().as_ref()).unwrap().response_code(), ServFail);
+    }
+
+    #[tokio::test]
+    async fn answers_on_the_configured_path_only() {
+        let config = config(&[("DOH_PATH", "tenant-a/dns-query")]);
+        let message = base64_url::encode(&query("example.com.", RecordType::A).to_bytes().unwrap());
+
+        let response = get_path(&config, &format!("/tenant-a/dns-query?dns={}", message)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Message::from_bytes(response.body().as_ref()).unwrap().answers().len(), 2);
+
+        let response = get_path(&config, &format!("/dns-query?dns={}", message)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn limits_any_responses() {
+        let message = query("huge.example.", RecordType::ANY);
+
+        let response = post_with(&config(&[("ANY_RESPONSE_SIZE_LIMIT", "512")]), &message).await;
+        assert!(response.body().len() <= 512);
+
+        let response = Message::from_bytes(response.body().as_ref()).unwrap();
+        assert_eq!(response.response_code(), NoError);
+        assert!(!response.truncated());
+        assert!(!response.answers().is_empty());
+
+        let response = resolve_with(&config(&[("ALLOW_ANY", "false")]), &message).await;
+        assert_eq!(response.response_code(), Refused);
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn returns_problem_details_from_the_debug_lookup() {
+        let config = config(&[("ENABLE_DEBUG_ENDPOINTS", "true")]);
+
+        let response = get_path(&config, "/debug/lookup").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["Content-Type"], "application/problem+json");
+
+        let problem: serde_json::Value = serde_json::from_slice(response.body().as_ref()).unwrap();
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["title"], "Bad Request");
+        assert_eq!(problem["detail"], "Missing 'name' query string parameter");
+
+        let response = get_path(&config, "/debug/lookup?name=ads.example").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+
+        let lookup: serde_json::Value = serde_json::from_slice(response.body().as_ref()).unwrap();
+        assert_eq!(lookup["decision"]["blocked"], true);
+
+        setup().await;
+        assert_eq!(get_path(*CONFIG, "/debug/lookup?name=ads.example").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn answers_formerr_without_edns_when_required() {
+        let config = config(&[("REQUIRE_EDNS", "true")]);
+
+        let response = resolve_with(&config, &query("example.com.", RecordType::A)).await;
+        assert_eq!(response.response_code(), FormErr);
+        assert!(response.answers().is_empty());
+
+        let response = resolve_with(&config, &edns_query("example.com.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 2);
+    }
+
+    // On the wire, with the client's spelling and the root label whether or
+    // not it was written
+    #[tokio::test]
+    async fn echoes_question_names_exactly() {
+        for name in ["ExAmple.COM.", "example.com", "ADS.example.", "Unknown.Example.", "Pinned.Example", "192.0.2.1."] {
+            let message = query(name, RecordType::A);
+            let response = resolve(&message).await;
+
+            assert_eq!(response.queries()[0].to_bytes().unwrap(), message.queries()[0].to_bytes().unwrap(), "{}", name);
+
+            for answer in response.answers() {
+                assert_eq!(answer.name().to_bytes().unwrap(), message.queries()[0].name().to_bytes().unwrap(), "{}", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_reverse_lookups_of_sinkhole_addresses_with_the_block_marker() {
+        let config = config(&[("BLOCK_ZONE", "block.test")]);
+
+        for name in ["0.0.0.0.in-addr.arpa.", "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa."] {
+            let response = resolve_with(&config, &query(name, RecordType::PTR)).await;
+
+            assert_eq!(response.response_code(), NoError, "{}", name);
+            assert_eq!(response.answers().len(), 1, "{}", name);
+            assert_eq!(response.answers()[0].data(), Some(&RData::PTR(Name::from_ascii("blocked.block.test.").unwrap())), "{}", name);
+        }
+
+        // Without a block zone they're looked up like any other name
+        let response = resolve(&query("0.0.0.0.in-addr.arpa.", RecordType::PTR)).await;
+        assert_eq!(response.response_code(), NXDomain);
+    }
+
+    #[tokio::test]
+    async fn answers_blocked_names_with_sinkhole_addresses_in_sinkhole_mode() {
+        let config = config(&[("BLOCK_MODE", "sinkhole")]);
+
+        let response = resolve_with(&config, &edns_query("ads.example.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), Some(&RData::A(Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(response.answers()[0].ttl(), config.negative_ttl);
+        assert_eq!(extended_error(&response), Some(EDE_FILTERED));
+
+        let response = resolve_with(&config, &query("ads.example.", RecordType::AAAA)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers()[0].data(), Some(&RData::AAAA(Ipv6Addr::UNSPECIFIED)));
+
+        let response = resolve_with(&config, &query("ads.example.", RecordType::TXT)).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+
+        // NXDOMAIN mode is the default
+        let response = resolve(&edns_query("ads.example.", RecordType::A)).await;
+        assert_eq!(response.response_code(), NXDomain);
+        assert!(response.answers().is_empty());
+        assert_eq!(extended_error(&response), Some(EDE_FILTERED));
+    }
+
+    // Newly registered domains only get a category from the list database,
+    // so the blocked answer is tested past the category lookup
+    #[test]
+    fn answers_newly_registered_domains_per_config() {
+        let request = edns_query("new.example.", RecordType::A);
+        let query = request.queries()[0].clone();
+        let mut response = request.clone();
+        response.set_message_type(MessageType::Response);
+
+        let answer = blocked_answer(&config(&[]), response.clone(), &request, &query, Some(NRD_CATEGORY));
+        assert!(matches!(answer.outcome, Outcome::Blocked));
+        assert_eq!(answer.response.response_code(), NoError);
+        assert_eq!(answer.response.answer_count(), 0);
+        assert_eq!(answer.response.name_servers()[0].record_type(), RecordType::SOA);
+        assert_eq!(extended_error(&answer.response), Some(EDE_FILTERED));
+
+        let answer = blocked_answer(&config(&[("NRD_HANDLING", "block")]), response.clone(), &request, &query, Some(NRD_CATEGORY));
+        assert_eq!(answer.response.response_code(), NXDomain);
+        assert_eq!(extended_error(&answer.response), Some(EDE_FILTERED));
+
+        let answer = blocked_answer(&config(&[]), response, &request, &query, None);
+        assert_eq!(answer.response.response_code(), NXDomain);
+    }
+
+    #[tokio::test]
+    async fn answers_formerr_for_reserved_bits_per_config() {
+        let mut bytes = query("example.com.", RecordType::A).to_bytes().unwrap();
+        // The Z bit sits between the RA and AD bits of the fourth header byte
+        bytes[3] |= 0b0100_0000;
+
+        let response = post_body(&config(&[("REJECT_RESERVED_BITS", "true")]), Body::from(bytes.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Message::from_bytes(response.body().as_ref()).unwrap().response_code(), FormErr);
+
+        let response = post_body(&config(&[]), Body::from(bytes)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(Message::from_bytes(response.body().as_ref()).unwrap().response_code(), NoError);
+    }
+
+    #[tokio::test]
+    async fn answers_reverse_lookups_in_sinkhole_networks_with_the_block_marker() {
+        let config = config(&[("BLOCK_ZONE", "block.test"), ("SINKHOLE_CIDRS", "192.0.2.0/24")]);
+
+        let response = resolve_with(&config, &query("5.2.0.192.in-addr.arpa.", RecordType::PTR)).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), Some(&RData::PTR(Name::from_ascii("blocked.block.test.").unwrap())));
+
+        // Neither addresses outside the network nor the network's own name
+        for name in ["5.3.0.192.in-addr.arpa.", "2.0.192.in-addr.arpa."] {
+            let response = resolve_with(&config, &query(name, RecordType::PTR)).await;
+            assert_eq!(response.response_code(), NXDomain, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_truncated_post_bodies_apart_from_invalid_ones() {
+        let bytes = query("example.com.", RecordType::A).to_bytes().unwrap();
+
+        let response = post_body(&config(&[]), Body::from(bytes[..bytes.len() - 3].to_vec())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body().as_ref(), b"Bad request: Truncated DNS message, the data ended before the message did\n");
+
+        // A header with one question, whose name starts with a reserved label type
+        let mut garbage = bytes[..12].to_vec();
+        garbage.extend([0b1000_0000, 0, 0, 1, 0, 1]);
+
+        let response = post_body(&config(&[]), Body::from(garbage)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body().as_ref(), b"Bad request: Invalid DNS message\n");
+    }
+
+    #[tokio::test]
+    async fn routes_lookups_to_per_type_upstreams() {
+        let resolver = stub_resolver("default", "routed.example A 192.0.2.80\nrouted.example TXT default\n");
+        let by_type: HashMap<RecordType, Box<dyn UpstreamResolver>> = HashMap::from([
+            (RecordType::TXT, Box::new(stub_resolver("txt", "routed.example TXT specialized\n")) as Box<dyn UpstreamResolver>)
+        ]);
+
+        let upstreams = Upstreams { by_type: Some(&by_type), resolver: &resolver, fallback: None, circuit_breaker: None };
+
+        let txt = upstreams.lookup("routed.example.", RecordType::TXT).await.unwrap();
+        assert_eq!(txt.record_iter().next().unwrap().data(), Some(&RData::TXT(TXT::new(vec!["specialized".to_string()]))));
+
+        let a = upstreams.lookup("routed.example.", RecordType::A).await.unwrap();
+        assert_eq!(a.record_iter().next().unwrap().data(), Some(&RData::A(Ipv4Addr::new(192, 0, 2, 80))));
+    }
+
+    #[tokio::test]
+    async fn keeps_question_order_in_multi_question_answers() {
+        let config = config(&[("ANSWER_ALL_QUESTIONS", "true")]);
+
+        let mut message = query("dual.example.", RecordType::AAAA);
+        message.add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+        message.add_query(Query::query(Name::from_ascii("pinned.example.").unwrap(), RecordType::A));
+
+        let response = resolve_with(&config, &message).await;
+        assert_eq!(response.response_code(), NoError);
+        assert_eq!(response.queries(), message.queries());
+
+        let owners: Vec<String> = response.answers().iter().map(|record| record.name().to_string()).collect();
+        assert_eq!(owners, ["dual.example.", "example.com.", "example.com.", "pinned.example."]);
+        assert_eq!(response.answers()[0].data(), Some(&RData::AAAA("2001:db8::50".parse().unwrap())));
+        assert_eq!(response.answers()[3].data(), Some(&RData::A(Ipv4Addr::new(10, 1, 2, 3))));
+
+        // One failing question fails the message, but the others stay answered
+        let mut message = query("example.com.", RecordType::A);
+        message.add_query(Query::query(Name::from_ascii("broken.example.").unwrap(), RecordType::A));
+
+        let response = resolve_with(&config, &message).await;
+        assert_eq!(response.response_code(), ServFail);
+        assert_eq!(response.queries(), message.queries());
+        assert_eq!(response.answers().len(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    net::{
+        Ipv4Addr,
+        Ipv6Addr
+    },
+    path::Path,
+    str::FromStr
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use trust_dns_proto::rr::{
+    rdata::TXT,
+    Name,
+    RData,
+    Record,
+    RecordType
+};
+
+const DEFAULT_OVERRIDE_TTL: u32 = 300;
+
+// Static records that shadow upstream answers for specific names, loaded from
+// a file with one record per line:
+//
+//     <name> <type> <value> [ttl]
+//
+// e.g. `api.partner.example A 10.1.2.3 60`. Supported types are A, AAAA,
+// CNAME, and TXT. Blank lines and lines starting with '#' are ignored. As in
+// a zone, a name with a CNAME can't have any other records.
+//
+// The file is either packaged with the function or, with an
+// `s3://<bucket>/<key>` location, fetched from S3 at init.
+#[derive(Default)]
+pub struct Overrides {
+    records: HashMap<String, Vec<(u32, RData)>>
+}
+
+impl Overrides {
+    pub fn load<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>, {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into())
+        };
+
+        Self::parse(&contents)
+    }
+
+    // Loads from a path in the function package or an `s3://<bucket>/<key>`
+    // object. Unlike a missing file, a missing object is an error, since the
+    // location was set explicitly.
+    pub async fn load_from(location: &str) -> Result<Self> {
+        match s3_location(location)? {
+            Some((bucket, key)) => {
+                let aws_config = aws_config::load_from_env().await;
+                let object = aws_sdk_s3::Client::new(&aws_config)
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to fetch overrides from '{}'", location))?;
+
+                let contents = object.body.collect().await?.into_bytes();
+
+                Self::parse(std::str::from_utf8(&contents).with_context(|| format!("Overrides at '{}' aren't UTF-8", location))?)
+            },
+            None => Self::load(location)
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut records: HashMap<String, Vec<(u32, RData)>> = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, ttl, rdata) = parse_override(line)
+                .map_err(|err| anyhow!("Invalid override on line {}: {}", index + 1, err))?;
+
+            records.entry(name).or_default().push((ttl, rdata));
+        }
+
+        for (name, name_records) in &records {
+            if name_records.len() > 1 && name_records.iter().any(|(_, rdata)| rdata.to_record_type() == RecordType::CNAME) {
+                return Err(anyhow!("Invalid overrides for '{}': a CNAME can't share its name with other records", name));
+            }
+        }
+
+        Ok(Self { records })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    // Returns the pinned records answering the query if the name is
+    // overridden. An overridden name with no records of the queried type
+    // yields an empty answer rather than falling through to upstream.
+    pub fn lookup(&self, name: &Name, query_type: RecordType) -> Option<Vec<Record>> {
+        let records = self.records.get(&normalize(&name.to_utf8()))?;
+
+        Some(records
+            .iter()
+            .filter(|(_, rdata)| {
+                let record_type = rdata.to_record_type();
+                record_type == query_type || query_type == RecordType::ANY || record_type == RecordType::CNAME
+            })
+            .map(|(ttl, rdata)| Record::from_rdata(name.clone(), *ttl, rdata.clone()))
+            .collect())
+    }
+}
+
+// Splits an `s3://<bucket>/<key>` location, or returns None for a local path
+pub fn s3_location(location: &str) -> Result<Option<(&str, &str)>> {
+    let location = match location.strip_prefix("s3://") {
+        Some(location) => location,
+        None => return Ok(None)
+    };
+
+    match location.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Some((bucket, key))),
+        _ => Err(anyhow!("Invalid S3 location 's3://{}', expected s3://<bucket>/<key>", location))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn parse_override(line: &str) -> Result<(String, u32, RData)> {
+    let mut fields = line.split_whitespace();
+
+    let name = fields.next().ok_or_else(|| anyhow!("missing name"))?;
+    let record_type = fields.next().ok_or_else(|| anyhow!("missing record type"))?;
+    let value = fields.next().ok_or_else(|| anyhow!("missing value"))?;
+    let ttl = match fields.next() {
+        Some(ttl) => ttl.parse()?,
+        None => DEFAULT_OVERRIDE_TTL
+    };
+
+    let rdata = match record_type.to_uppercase().as_str() {
+        "A" => RData::A(Ipv4Addr::from_str(value)?),
+        "AAAA" => RData::AAAA(Ipv6Addr::from_str(value)?),
+        "CNAME" => RData::CNAME(Name::from_str(value)?),
+        "TXT" => RData::TXT(TXT::new(vec![value.to_string()])),
+        other => return Err(anyhow!("unsupported record type '{}'", other))
+    };
+
+    Ok((normalize(name), ttl, rdata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn parses_records() {
+        let overrides = Overrides::parse("# pinned\n\napi.partner.example A 10.1.2.3 60\nAPI.partner.example. aaaa 2001:db8::1\nnote.example TXT hello\n").unwrap();
+
+        assert_eq!(overrides.len(), 2);
+
+        let records = overrides.lookup(&name("api.partner.example."), RecordType::A).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ttl(), 60);
+        assert_eq!(records[0].data(), Some(&RData::A(Ipv4Addr::new(10, 1, 2, 3))));
+
+        let records = overrides.lookup(&name("api.partner.example."), RecordType::AAAA).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ttl(), DEFAULT_OVERRIDE_TTL);
+
+        assert_eq!(overrides.lookup(&name("api.partner.example."), RecordType::ANY).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn overridden_names_without_the_type_get_no_records() {
+        let overrides = Overrides::parse("api.partner.example A 10.1.2.3").unwrap();
+
+        assert_eq!(overrides.lookup(&name("api.partner.example."), RecordType::MX), Some(vec![]));
+        assert_eq!(overrides.lookup(&name("other.example."), RecordType::A), None);
+    }
+
+    #[test]
+    fn cnames_answer_every_type() {
+        let overrides = Overrides::parse("www.partner.example CNAME api.partner.example.").unwrap();
+
+        let records = overrides.lookup(&name("www.partner.example."), RecordType::A).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data(), Some(&RData::CNAME(name("api.partner.example."))));
+    }
+
+    #[test]
+    fn rejects_cnames_sharing_a_name() {
+        assert!(Overrides::parse("www.partner.example CNAME api.partner.example.\nwww.partner.example A 10.1.2.3").is_err());
+        assert!(Overrides::parse("www.partner.example CNAME a.example.\nwww.partner.example CNAME b.example.").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(Overrides::parse("api.partner.example").is_err());
+        assert!(Overrides::parse("api.partner.example A").is_err());
+        assert!(Overrides::parse("api.partner.example A not-an-address").is_err());
+        assert!(Overrides::parse("api.partner.example MX mail.example.").is_err());
+        assert!(Overrides::parse("api.partner.example A 10.1.2.3 soon").is_err());
+    }
+
+    #[test]
+    fn splits_s3_locations() {
+        assert_eq!(s3_location("s3://bucket/dns/overrides").unwrap(), Some(("bucket", "dns/overrides")));
+        assert_eq!(s3_location("./overrides").unwrap(), None);
+        assert!(s3_location("s3://bucket").is_err());
+        assert!(s3_location("s3://bucket/").is_err());
+        assert!(s3_location("s3:///overrides").is_err());
+    }
+
+    #[tokio::test]
+    async fn loads_local_paths_without_s3() {
+        let path = std::env::temp_dir().join(format!("overrides-tests-{}", std::process::id()));
+        fs::write(&path, "api.partner.example A 10.1.2.3\n").unwrap();
+
+        let overrides = Overrides::load_from(path.to_str().unwrap()).await.unwrap();
+
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(Overrides::load_from("./missing-overrides").await.unwrap().len(), 0);
+    }
+}
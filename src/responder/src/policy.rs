@@ -0,0 +1,201 @@
+use serde::Serialize;
+
+// Which rules matched a name, gathered before deciding whether it's blocked
+pub struct PolicyMatches<'a> {
+    pub view: Option<&'a str>,
+    pub essential: bool,
+    pub view_allowed: bool,
+    pub view_denied: bool,
+    pub default_deny: bool,
+    pub denied: bool,
+    pub allowed: bool,
+    pub blocked_tld: bool
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleTrace {
+    pub rule: &'static str,
+    pub matched: bool
+}
+
+// The blocking decision for a name, with every rule that was considered in
+// order of precedence and the one that decided it
+#[derive(Debug, Serialize)]
+pub struct PolicyDecision {
+    pub view: Option<String>,
+    pub blocked: bool,
+    pub winning_rule: Option<&'static str>,
+    pub reason: &'static str,
+    pub trace: Vec<RuleTrace>
+}
+
+// What a rule does to a name it matches
+enum Effect {
+    Allows,
+    Blocks,
+    BlocksUnlessAllowListed,
+    None
+}
+
+pub fn decide(matches: &PolicyMatches) -> PolicyDecision {
+    let in_view = matches.view.is_some();
+
+    let rules: [(&'static str, bool, Effect, &'static str); 7] = [
+        ("essential-allow", matches.essential, Effect::Allows, "On the built-in essential allow list, which overrides everything else"),
+        ("view-allow", in_view && matches.view_allowed, Effect::Allows, "Allow-listed by the client's view, which overrides the lists"),
+        ("view-deny", in_view && matches.view_denied, Effect::Blocks, "Denied by the client's view"),
+        ("default-deny", matches.default_deny, Effect::BlocksUnlessAllowListed, match matches.allowed {
+            true => "Allow-listed while in default-deny mode",
+            false => "Not allow-listed while in default-deny mode"
+        }),
+        ("deny-list", matches.denied, Effect::BlocksUnlessAllowListed, match matches.allowed {
+            true => "On the deny list, but allow-listed",
+            false => "On the deny list"
+        }),
+        ("blocked-tld", matches.blocked_tld, Effect::BlocksUnlessAllowListed, match matches.allowed {
+            true => "In a blocked TLD, but allow-listed",
+            false => "In a blocked TLD"
+        }),
+        // Only overrides the rules above, never decides on its own
+        ("allow-list", matches.allowed, Effect::None, "")
+    ];
+
+    let mut decision = PolicyDecision {
+        view: matches.view.map(str::to_string),
+        blocked: false,
+        winning_rule: None,
+        reason: "No rule matched",
+        trace: Vec::new()
+    };
+
+    for (rule, matched, effect, reason) in rules {
+        decision.trace.push(RuleTrace { rule, matched });
+
+        if !matched || decision.winning_rule.is_some() {
+            continue;
+        }
+
+        let (blocked, winning_rule) = match effect {
+            Effect::Allows => (false, rule),
+            Effect::Blocks => (true, rule),
+            // The allow list is reported as deciding when it overrides a rule
+            Effect::BlocksUnlessAllowListed if matches.allowed => (false, "allow-list"),
+            Effect::BlocksUnlessAllowListed => (true, rule),
+            Effect::None => continue
+        };
+
+        decision.blocked = blocked;
+        decision.winning_rule = Some(winning_rule);
+        decision.reason = reason;
+    }
+
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_matches() -> PolicyMatches<'static> {
+        PolicyMatches {
+            view: None,
+            essential: false,
+            view_allowed: false,
+            view_denied: false,
+            default_deny: false,
+            denied: false,
+            allowed: false,
+            blocked_tld: false
+        }
+    }
+
+    fn trace(decision: &PolicyDecision) -> Vec<(&'static str, bool)> {
+        decision.trace.iter().map(|rule| (rule.rule, rule.matched)).collect()
+    }
+
+    #[test]
+    fn allow_list_overrides_deny_list() {
+        let decision = decide(&PolicyMatches { denied: true, allowed: true, ..no_matches() });
+
+        assert!(!decision.blocked);
+        assert_eq!(decision.winning_rule, Some("allow-list"));
+        assert_eq!(decision.reason, "On the deny list, but allow-listed");
+        assert_eq!(trace(&decision), vec![
+            ("essential-allow", false),
+            ("view-allow", false),
+            ("view-deny", false),
+            ("default-deny", false),
+            ("deny-list", true),
+            ("blocked-tld", false),
+            ("allow-list", true)
+        ]);
+
+        let decision = decide(&PolicyMatches { denied: true, ..no_matches() });
+
+        assert!(decision.blocked);
+        assert_eq!(decision.winning_rule, Some("deny-list"));
+        assert_eq!(decision.reason, "On the deny list");
+    }
+
+    #[test]
+    fn view_deny_overrides_the_allow_list() {
+        let decision = decide(&PolicyMatches { view: Some("kids"), view_denied: true, allowed: true, ..no_matches() });
+
+        assert!(decision.blocked);
+        assert_eq!(decision.view.as_deref(), Some("kids"));
+        assert_eq!(decision.winning_rule, Some("view-deny"));
+        assert_eq!(decision.reason, "Denied by the client's view");
+        assert_eq!(trace(&decision), vec![
+            ("essential-allow", false),
+            ("view-allow", false),
+            ("view-deny", true),
+            ("default-deny", false),
+            ("deny-list", false),
+            ("blocked-tld", false),
+            ("allow-list", true)
+        ]);
+    }
+
+    #[test]
+    fn view_rules_only_match_in_a_view() {
+        let decision = decide(&PolicyMatches { view_denied: true, ..no_matches() });
+
+        assert!(!decision.blocked);
+        assert_eq!(decision.winning_rule, None);
+        assert_eq!(decision.reason, "No rule matched");
+        assert!(trace(&decision).iter().all(|(_, matched)| !matched));
+    }
+
+    #[test]
+    fn blocks_tlds_unless_allow_listed() {
+        let decision = decide(&PolicyMatches { blocked_tld: true, ..no_matches() });
+
+        assert!(decision.blocked);
+        assert_eq!(decision.winning_rule, Some("blocked-tld"));
+        assert_eq!(decision.reason, "In a blocked TLD");
+        assert_eq!(trace(&decision), vec![
+            ("essential-allow", false),
+            ("view-allow", false),
+            ("view-deny", false),
+            ("default-deny", false),
+            ("deny-list", false),
+            ("blocked-tld", true),
+            ("allow-list", false)
+        ]);
+
+        let decision = decide(&PolicyMatches { blocked_tld: true, allowed: true, ..no_matches() });
+
+        assert!(!decision.blocked);
+        assert_eq!(decision.winning_rule, Some("allow-list"));
+        assert_eq!(decision.reason, "In a blocked TLD, but allow-listed");
+    }
+
+    #[test]
+    fn essential_names_are_never_blocked() {
+        let decision = decide(&PolicyMatches { essential: true, denied: true, blocked_tld: true, ..no_matches() });
+
+        assert!(!decision.blocked);
+        assert_eq!(decision.winning_rule, Some("essential-allow"));
+        assert_eq!(trace(&decision)[0], ("essential-allow", true));
+    }
+}
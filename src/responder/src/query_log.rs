@@ -0,0 +1,362 @@
+use std::{
+    future::Future,
+    mem,
+    str::FromStr,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use aws_sdk_firehose::{
+    model::Record as FirehoseRecord,
+    types::Blob
+};
+
+use aws_sdk_s3::types::ByteStream;
+
+use chrono::{
+    SecondsFormat,
+    Utc
+};
+
+use serde::Serialize;
+
+use sha2::{
+    Digest,
+    Sha256
+};
+
+use tokio::sync::OnceCell;
+
+use trust_dns_proto::rr::RecordType;
+
+// Firehose rejects batches of more than 500 records
+const FIREHOSE_MAX_BATCH: usize = 500;
+
+// Batches kept for retrying while the sink is failing
+const MAX_BUFFERED_BATCHES: usize = 10;
+
+// Where query records are written, configured with QUERY_LOG_SINK as either
+// `s3://<bucket>[/<prefix>]` or `firehose://<delivery stream>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    S3 { bucket: String, prefix: String },
+    Firehose { stream: String }
+}
+
+impl FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once("://") {
+            Some(("s3", location)) if !location.is_empty() => {
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+                let prefix = match prefix.trim_matches('/') {
+                    "" => String::new(),
+                    prefix => format!("{}/", prefix)
+                };
+
+                Ok(Sink::S3 { bucket: bucket.to_string(), prefix })
+            },
+            Some(("firehose", stream)) if !stream.is_empty() => Ok(Sink::Firehose { stream: stream.to_string() }),
+            _ => Err(anyhow!("Unknown query log sink '{}', expected 's3://<bucket>[/<prefix>]' or 'firehose://<stream>'", value))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QueryLogSettings {
+    pub sink: Sink,
+    // Log a salted SHA-256 of each name instead of the name itself
+    pub hash_names: bool,
+    pub hash_salt: String,
+    pub log_client_ip: bool,
+    // Flush once this many records are buffered...
+    pub batch_size: usize,
+    // ...or the oldest buffered record is this old
+    pub max_batch_age: Duration
+}
+
+#[derive(Debug, Serialize)]
+struct QueryRecord {
+    timestamp: String,
+    name: String,
+    #[serde(rename = "type")]
+    query_type: String,
+    blocked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_ip: Option<String>
+}
+
+struct Buffer {
+    records: Vec<QueryRecord>,
+    oldest: Option<Instant>
+}
+
+enum SinkClient {
+    S3(aws_sdk_s3::Client),
+    Firehose(aws_sdk_firehose::Client)
+}
+
+// Buffers query records in memory and writes them to the sink in batches.
+//
+// The instance can be frozen at any point between invocations, during which
+// no background task runs, so flushing is checked at the end of each
+// invocation instead. Records buffered by an instance that never gets invoked
+// again are lost.
+pub struct QueryLog {
+    settings: QueryLogSettings,
+    buffer: Mutex<Buffer>,
+    client: OnceCell<SinkClient>
+}
+
+impl QueryLog {
+    pub fn new(settings: QueryLogSettings) -> Self {
+        Self {
+            settings,
+            buffer: Mutex::new(Buffer { records: Vec::new(), oldest: None }),
+            client: OnceCell::new()
+        }
+    }
+
+    pub fn record(&self, name: &str, query_type: RecordType, blocked: bool, client_ip: &str) {
+        let name = match self.settings.hash_names {
+            true => hash_name(&self.settings.hash_salt, name),
+            false => name.to_string()
+        };
+
+        let record = QueryRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            name,
+            query_type: query_type.to_string(),
+            blocked,
+            client_ip: self.settings.log_client_ip.then(|| client_ip.to_string())
+        };
+
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.oldest.get_or_insert_with(Instant::now);
+        buffer.records.push(record);
+    }
+
+    // Writes out the buffered records if the batch is full or old enough
+    pub async fn flush_if_due(&self) -> Result<()> {
+        self.flush_if_due_with(|lines| self.write(lines)).await
+    }
+
+    // Records that fail to write go back to the front of the buffer to be
+    // retried on the next flush, so a write that partly succeeded may repeat
+    // records. While the sink stays down the oldest records are dropped once
+    // MAX_BUFFERED_BATCHES batches are waiting.
+    async fn flush_if_due_with<W, F>(&self, write: W) -> Result<()>
+    where
+        W: FnOnce(Vec<Vec<u8>>) -> F,
+        F: Future<Output = Result<()>>, {
+        let (records, oldest) = {
+            let mut buffer = self.buffer.lock().unwrap();
+
+            let due = buffer.records.len() >= self.settings.batch_size
+                || buffer.oldest.map(|oldest| oldest.elapsed() >= self.settings.max_batch_age).unwrap_or(false);
+
+            if !due {
+                return Ok(());
+            }
+
+            (mem::take(&mut buffer.records), buffer.oldest.take())
+        };
+
+        let count = records.len();
+
+        let result = match to_json_lines(&records) {
+            Ok(lines) => write(lines).await,
+            Err(err) => Err(err)
+        };
+
+        if let Err(err) = result {
+            self.requeue(records, oldest);
+
+            return Err(err).with_context(|| format!("Failed to write {} query log records", count));
+        }
+
+        println!("Wrote {} query log records", count);
+
+        Ok(())
+    }
+
+    fn requeue(&self, mut records: Vec<QueryRecord>, oldest: Option<Instant>) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        records.append(&mut buffer.records);
+
+        let limit = self.settings.batch_size.max(1) * MAX_BUFFERED_BATCHES;
+        if records.len() > limit {
+            let dropped = records.len() - limit;
+            println!("Query log sink is failing, dropping the {} oldest query log records", dropped);
+            records.drain(..dropped);
+        }
+
+        buffer.records = records;
+        buffer.oldest = oldest.or(buffer.oldest);
+    }
+
+    async fn write(&self, lines: Vec<Vec<u8>>) -> Result<()> {
+        let client = self.client.get_or_init(|| async {
+            let aws_config = aws_config::load_from_env().await;
+
+            match self.settings.sink {
+                Sink::S3 { .. } => SinkClient::S3(aws_sdk_s3::Client::new(&aws_config)),
+                Sink::Firehose { .. } => SinkClient::Firehose(aws_sdk_firehose::Client::new(&aws_config))
+            }
+        }).await;
+
+        match (&self.settings.sink, client) {
+            (Sink::S3 { bucket, prefix }, SinkClient::S3(client)) => {
+                let now = Utc::now();
+                let key = format!("{}{}-{:08x}.jsonl", prefix, now.format("%Y/%m/%d/%H%M%S%.3f"), rand::random::<u32>());
+
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .content_type("application/x-ndjson")
+                    .body(ByteStream::from(lines.concat()))
+                    .send()
+                    .await?;
+            },
+            (Sink::Firehose { stream }, SinkClient::Firehose(client)) => {
+                for chunk in lines.chunks(FIREHOSE_MAX_BATCH) {
+                    let records = chunk
+                        .iter()
+                        .map(|line| FirehoseRecord::builder().data(Blob::new(line.clone())).build())
+                        .collect();
+
+                    let output = client
+                        .put_record_batch()
+                        .delivery_stream_name(stream)
+                        .set_records(Some(records))
+                        .send()
+                        .await?;
+
+                    if let Some(failed) = output.failed_put_count().filter(|failed| *failed > 0) {
+                        return Err(anyhow!("Firehose rejected {} of {} records", failed, chunk.len()));
+                    }
+                }
+            },
+            _ => unreachable!("Query log client does not match its sink")
+        };
+
+        Ok(())
+    }
+}
+
+fn hash_name(salt: &str, name: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(name.to_lowercase().as_bytes());
+
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// One newline-terminated JSON object per record
+fn to_json_lines(records: &[QueryRecord]) -> Result<Vec<Vec<u8>>> {
+    records
+        .iter()
+        .map(|record| {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            Ok(line)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use super::*;
+
+    fn query_log(batch_size: usize) -> QueryLog {
+        QueryLog::new(QueryLogSettings {
+            sink: Sink::Firehose { stream: "test".to_string() },
+            hash_names: false,
+            hash_salt: String::new(),
+            log_client_ip: false,
+            batch_size,
+            max_batch_age: Duration::from_secs(3600)
+        })
+    }
+
+    fn names(lines: &[Vec<u8>]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap()["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_writes_keep_their_records() {
+        let query_log = query_log(2);
+        query_log.record("a.example", RecordType::A, false, "192.0.2.1");
+        query_log.record("b.example", RecordType::A, true, "192.0.2.1");
+
+        let failed = query_log.flush_if_due_with(|_| async { Err(anyhow!("sink unavailable")) }).await;
+        assert!(failed.is_err());
+
+        query_log.record("c.example", RecordType::AAAA, false, "192.0.2.1");
+
+        let written = StdMutex::new(Vec::new());
+        query_log.flush_if_due_with(|lines| async {
+            *written.lock().unwrap() = lines;
+            Ok(())
+        }).await.unwrap();
+
+        assert_eq!(names(&written.lock().unwrap()), ["a.example", "b.example", "c.example"]);
+        assert!(query_log.buffer.lock().unwrap().records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_full_batch() {
+        let query_log = query_log(2);
+        query_log.record("a.example", RecordType::A, false, "192.0.2.1");
+
+        query_log.flush_if_due_with(|_| async { panic!("flushed early") }).await.unwrap();
+
+        assert_eq!(query_log.buffer.lock().unwrap().records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_records_while_failing() {
+        let query_log = query_log(1);
+
+        for index in 0..=MAX_BUFFERED_BATCHES {
+            query_log.record(&format!("{}.example", index), RecordType::A, false, "192.0.2.1");
+            let _ = query_log.flush_if_due_with(|_| async { Err(anyhow!("sink unavailable")) }).await;
+        }
+
+        let buffer = query_log.buffer.lock().unwrap();
+        assert_eq!(buffer.records.len(), MAX_BUFFERED_BATCHES);
+        assert_eq!(buffer.records[0].name, "1.example");
+    }
+
+    #[test]
+    fn parses_sinks() {
+        assert_eq!("s3://bucket".parse::<Sink>().unwrap(), Sink::S3 { bucket: "bucket".to_string(), prefix: String::new() });
+        assert_eq!("s3://bucket/logs/".parse::<Sink>().unwrap(), Sink::S3 { bucket: "bucket".to_string(), prefix: "logs/".to_string() });
+        assert_eq!("firehose://stream".parse::<Sink>().unwrap(), Sink::Firehose { stream: "stream".to_string() });
+        assert!("s3://".parse::<Sink>().is_err());
+        assert!("kinesis://stream".parse::<Sink>().is_err());
+    }
+
+    #[test]
+    fn hashes_names_case_insensitively() {
+        assert_eq!(hash_name("salt", "Example.COM"), hash_name("salt", "example.com"));
+        assert_ne!(hash_name("salt", "example.com"), hash_name("other", "example.com"));
+    }
+}
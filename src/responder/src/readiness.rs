@@ -0,0 +1,83 @@
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering
+};
+
+use tokio::sync::Notify;
+
+// Set once the deny and allow lists have finished loading in the background,
+// so requests arriving during a slow cold start can wait on it
+pub struct Readiness {
+    ready: AtomicBool,
+    notify: Notify
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self { ready: AtomicBool::new(false), notify: Notify::new() }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    pub async fn wait(&self) {
+        loop {
+            // Registered before checking the flag, so a `mark_ready` in
+            // between isn't missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_ready() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::Duration
+    };
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_until_ready() {
+        let readiness = Arc::new(Readiness::new());
+        assert!(!readiness.is_ready());
+
+        let waiter = tokio::spawn({
+            let readiness = readiness.clone();
+            async move { readiness.wait().await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        readiness.mark_ready();
+
+        timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert!(readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn returns_at_once_when_ready() {
+        let readiness = Readiness::new();
+        readiness.mark_ready();
+
+        timeout(Duration::from_secs(1), readiness.wait()).await.unwrap();
+    }
+}
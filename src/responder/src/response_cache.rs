@@ -0,0 +1,173 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+use lru_cache::LruCache;
+
+use trust_dns_proto::rr::{
+    Record,
+    RecordType
+};
+
+use trust_dns_resolver::lookup::Lookup;
+
+struct Entry {
+    lookup: Lookup,
+    inserted: Instant,
+    expires: Instant
+}
+
+// Upstream answers kept in memory by (name, type), so a warm instance answers
+// popular names without a resolver call. Answers expire with the lowest TTL
+// among their records, and are served with their TTLs counted down. Holds at
+// most `capacity` answers, evicting the least recently used when full. Only
+// answers with records are cached.
+pub struct ResponseCache {
+    entries: Mutex<LruCache<(String, RecordType), Entry>>
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    pub fn get(&self, name: &str, query_type: RecordType) -> Option<Lookup> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (name.to_lowercase(), query_type);
+
+        let entry = entries.get_mut(&key)?;
+        let now = Instant::now();
+
+        if entry.expires <= now {
+            entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = u32::try_from(now.duration_since(entry.inserted).as_secs()).unwrap_or(u32::MAX);
+        let records: Vec<Record> = entry.lookup
+            .record_iter()
+            .cloned()
+            .map(|mut record| {
+                record.set_ttl(record.ttl().saturating_sub(elapsed));
+                record
+            })
+            .collect();
+
+        Some(Lookup::new_with_deadline(entry.lookup.query().clone(), Arc::from(records), entry.expires))
+    }
+
+    pub fn insert(&self, name: &str, query_type: RecordType, lookup: &Lookup) {
+        let min_ttl = match lookup.record_iter().map(Record::ttl).min() {
+            Some(min_ttl) if min_ttl > 0 => min_ttl,
+            _ => return
+        };
+
+        let inserted = Instant::now();
+        let expires = lookup.valid_until().min(inserted + Duration::from_secs(min_ttl.into()));
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((name.to_lowercase(), query_type), Entry { lookup: lookup.clone(), inserted, expires });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        thread::sleep
+    };
+
+    use trust_dns_proto::{
+        op::Query,
+        rr::{
+            Name,
+            RData
+        }
+    };
+
+    use super::*;
+
+    fn lookup(name: &str, ttl: u32) -> Lookup {
+        let name = Name::from_ascii(name).unwrap();
+        let record = Record::from_rdata(name.clone(), ttl, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+
+        Lookup::new_with_max_ttl(Query::query(name, RecordType::A), Arc::from(vec![record]))
+    }
+
+    fn empty_lookup(name: &str) -> Lookup {
+        Lookup::new_with_max_ttl(Query::query(Name::from_ascii(name).unwrap(), RecordType::A), Arc::from(vec![]))
+    }
+
+    #[test]
+    fn caches_answers_by_name_and_type() {
+        let cache = ResponseCache::new(10);
+
+        cache.insert("Popular.Example", RecordType::A, &lookup("popular.example", 300));
+
+        let cached = cache.get("popular.example", RecordType::A).unwrap();
+        assert_eq!(cached.record_iter().count(), 1);
+        assert_eq!(cached.record_iter().next().unwrap().ttl(), 300);
+
+        assert!(cache.get("popular.example", RecordType::AAAA).is_none());
+        assert!(cache.get("other.example", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn skips_answers_without_records() {
+        let cache = ResponseCache::new(10);
+
+        cache.insert("empty.example", RecordType::A, &empty_lookup("empty.example"));
+        cache.insert("zero.example", RecordType::A, &lookup("zero.example", 0));
+
+        assert!(cache.get("empty.example", RecordType::A).is_none());
+        assert!(cache.get("zero.example", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn expires_answers_with_their_ttl() {
+        let cache = ResponseCache::new(10);
+
+        cache.insert("short.example", RecordType::A, &lookup("short.example", 1));
+        assert!(cache.get("short.example", RecordType::A).is_some());
+
+        sleep(Duration::from_millis(1100));
+
+        assert!(cache.get("short.example", RecordType::A).is_none());
+        assert_eq!(cache.entries.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn counts_down_served_ttls() {
+        let cache = ResponseCache::new(10);
+
+        cache.insert("popular.example", RecordType::A, &lookup("popular.example", 300));
+        sleep(Duration::from_millis(1100));
+
+        assert_eq!(cache.get("popular.example", RecordType::A).unwrap().record_iter().next().unwrap().ttl(), 299);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = ResponseCache::new(2);
+
+        cache.insert("a.example", RecordType::A, &lookup("a.example", 300));
+        cache.insert("b.example", RecordType::A, &lookup("b.example", 300));
+        // Using a.example leaves b.example the least recently used
+        assert!(cache.get("a.example", RecordType::A).is_some());
+
+        cache.insert("c.example", RecordType::A, &lookup("c.example", 300));
+
+        assert!(cache.get("a.example", RecordType::A).is_some());
+        assert!(cache.get("b.example", RecordType::A).is_none());
+        assert!(cache.get("c.example", RecordType::A).is_some());
+    }
+}
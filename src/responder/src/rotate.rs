@@ -0,0 +1,98 @@
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering
+};
+
+use trust_dns_proto::rr::{
+    Record,
+    RecordType
+};
+
+// Advanced on every rotated response, so successive answers for the same name
+// start at successive records
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
+// Rotates each run of A or AAAA records sharing a name and type, leaving
+// everything else (e.g. the CNAME chain leading to them) where it is
+pub fn rotate_answers(records: &mut [Record]) {
+    let offset = ROTATION.fetch_add(1, Ordering::Relaxed);
+    let mut start = 0;
+
+    while start < records.len() {
+        let mut end = start + 1;
+
+        while end < records.len()
+            && records[end].record_type() == records[start].record_type()
+            && records[end].name() == records[start].name() {
+            end += 1;
+        }
+
+        if matches!(records[start].record_type(), RecordType::A | RecordType::AAAA) {
+            let run = &mut records[start..end];
+            let len = run.len();
+            run.rotate_left(offset % len);
+        }
+
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr
+    };
+
+    use trust_dns_proto::rr::{
+        Name,
+        RData
+    };
+
+    use super::*;
+
+    fn a(owner: &str, last: u8) -> Record {
+        Record::from_rdata(Name::from_str(owner).unwrap(), 300, RData::A(Ipv4Addr::new(192, 0, 2, last)))
+    }
+
+    fn addresses(records: &[Record]) -> Vec<u8> {
+        records
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(address)) => Some(address.octets()[3]),
+                _ => None
+            })
+            .collect()
+    }
+
+    // One test, as the rotation is shared and advanced by every call
+    #[test]
+    fn rotates_address_runs() {
+        let cname = Record::from_rdata(Name::from_str("www.example.").unwrap(), 300, RData::CNAME(Name::from_str("cdn.example.").unwrap()));
+        let original = vec![cname.clone(), a("cdn.example.", 1), a("cdn.example.", 2), a("cdn.example.", 3), a("other.example.", 4)];
+
+        let mut leading = Vec::new();
+
+        for _ in 0..3 {
+            let mut records = original.clone();
+            rotate_answers(&mut records);
+
+            // The CNAME stays first and the other name's run stays last
+            assert_eq!(records[0], cname);
+            assert_eq!(addresses(&records[4..]), vec![4]);
+
+            let run = addresses(&records[1..4]);
+            let start = run[0] as usize - 1;
+            assert_eq!(run, [1, 2, 3].iter().cycle().skip(start).take(3).copied().collect::<Vec<u8>>());
+
+            leading.push(run[0]);
+        }
+
+        // Successive responses start at successive records
+        leading.sort_unstable();
+        assert_eq!(leading, vec![1, 2, 3]);
+
+        let mut empty: Vec<Record> = Vec::new();
+        rotate_answers(&mut empty);
+    }
+}
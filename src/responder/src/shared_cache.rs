@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+use anyhow::{
+    Context,
+    Result
+};
+
+use aws_sdk_dynamodb::{
+    model::AttributeValue,
+    types::Blob
+};
+
+use futures_util::future::BoxFuture;
+
+use tokio::sync::OnceCell;
+
+use trust_dns_proto::{
+    op::{
+        Message,
+        Query
+    },
+    rr::{
+        Name,
+        Record,
+        RecordType
+    }
+};
+
+use trust_dns_resolver::lookup::Lookup;
+
+// Attribute names in the table. Its partition key must be a string named
+// `key`, and its TTL attribute set to `expires_at` so DynamoDB deletes
+// answers once they expire.
+const KEY_ATTRIBUTE: &str = "key";
+const RECORDS_ATTRIBUTE: &str = "records";
+const EXPIRES_ATTRIBUTE: &str = "expires_at";
+
+// Where shared answers are stored, keyed by name and type. Production uses a
+// DynamoDB table, tests an in-memory one.
+pub trait SharedCacheTable: Send + Sync {
+    fn get(&self, key: &str) -> BoxFuture<'_, Result<Option<SharedItem>>>;
+    fn put(&self, key: &str, item: SharedItem) -> BoxFuture<'_, Result<()>>;
+}
+
+// An answer's encoded records and when it expires, in seconds since the epoch
+#[derive(Clone)]
+pub struct SharedItem {
+    pub records: Vec<u8>,
+    pub expires_at: u64
+}
+
+// The SHARED_CACHE_TABLE DynamoDB table
+pub struct DynamoDbTable {
+    table: String,
+    client: OnceCell<aws_sdk_dynamodb::Client>
+}
+
+impl DynamoDbTable {
+    pub fn new(table: String) -> Self {
+        Self { table, client: OnceCell::new() }
+    }
+
+    async fn client(&self) -> &aws_sdk_dynamodb::Client {
+        self.client.get_or_init(|| async {
+            let aws_config = aws_config::load_from_env().await;
+            aws_sdk_dynamodb::Client::new(&aws_config)
+        }).await
+    }
+}
+
+impl SharedCacheTable for DynamoDbTable {
+    fn get(&self, key: &str) -> BoxFuture<'_, Result<Option<SharedItem>>> {
+        let key = key.to_string();
+
+        Box::pin(async move {
+            let output = self.client()
+                .await
+                .get_item()
+                .table_name(&self.table)
+                .key(KEY_ATTRIBUTE, AttributeValue::S(key))
+                .send()
+                .await
+                .with_context(|| format!("Failed to get item from DynamoDB table '{}'", self.table))?;
+
+            let item = match output.item() {
+                Some(item) => item,
+                None => return Ok(None)
+            };
+
+            let expires_at = match item.get(EXPIRES_ATTRIBUTE) {
+                Some(AttributeValue::N(expires_at)) => expires_at.parse::<u64>().with_context(|| "Invalid shared cache expiry")?,
+                _ => return Ok(None)
+            };
+
+            match item.get(RECORDS_ATTRIBUTE) {
+                Some(AttributeValue::B(records)) => Ok(Some(SharedItem { records: records.as_ref().to_vec(), expires_at })),
+                _ => Ok(None)
+            }
+        })
+    }
+
+    fn put(&self, key: &str, item: SharedItem) -> BoxFuture<'_, Result<()>> {
+        let key = key.to_string();
+
+        Box::pin(async move {
+            self.client()
+                .await
+                .put_item()
+                .table_name(&self.table)
+                .item(KEY_ATTRIBUTE, AttributeValue::S(key))
+                .item(RECORDS_ATTRIBUTE, AttributeValue::B(Blob::new(item.records)))
+                .item(EXPIRES_ATTRIBUTE, AttributeValue::N(item.expires_at.to_string()))
+                .send()
+                .await
+                .with_context(|| format!("Failed to put item into DynamoDB table '{}'", self.table))?;
+
+            Ok(())
+        })
+    }
+}
+
+// Upstream answers shared by every instance through a table, so a name
+// resolved by one instance is answered by the rest without going upstream.
+// Answers found in the table, or resolved and written to it, are also kept in
+// memory until they expire, so hot names don't cost a table read per query.
+// Only answers with records are shared.
+pub struct SharedCache {
+    table: Box<dyn SharedCacheTable>,
+    memory: Mutex<HashMap<(String, RecordType), Lookup>>,
+    capacity: usize
+}
+
+impl SharedCache {
+    pub fn new(table: Box<dyn SharedCacheTable>, capacity: usize) -> Self {
+        Self { table, memory: Mutex::new(HashMap::new()), capacity }
+    }
+
+    // Table errors are logged and treated as misses, so the cache can't fail
+    // a query the upstream could answer
+    pub async fn get(&self, name: &str, query_type: RecordType) -> Option<Lookup> {
+        if let Some(lookup) = self.get_memory(name, query_type) {
+            return Some(lookup);
+        }
+
+        match self.get_table(name, query_type).await {
+            Ok(Some(lookup)) => {
+                println!("Answering '{}' from the shared cache", name);
+                self.insert_memory(name, query_type, &lookup);
+                Some(lookup)
+            },
+            Ok(None) => None,
+            Err(err) => {
+                println!("Failed to read shared cache: {:#}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, name: &str, query_type: RecordType, lookup: &Lookup) {
+        if lookup.record_iter().next().is_none() {
+            return;
+        }
+
+        self.insert_memory(name, query_type, lookup);
+
+        if let Err(err) = self.put_table(name, query_type, lookup).await {
+            println!("Failed to write shared cache: {:#}", err);
+        }
+    }
+
+    fn get_memory(&self, name: &str, query_type: RecordType) -> Option<Lookup> {
+        let mut memory = self.memory.lock().unwrap();
+        let key = (name.to_string(), query_type);
+
+        match memory.get(&key) {
+            Some(lookup) if lookup.valid_until() > Instant::now() => Some(lookup.clone()),
+            Some(_) => {
+                memory.remove(&key);
+                None
+            },
+            None => None
+        }
+    }
+
+    // When full, expired answers are dropped first, then an arbitrary one
+    fn insert_memory(&self, name: &str, query_type: RecordType, lookup: &Lookup) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        let key = (name.to_string(), query_type);
+
+        if memory.len() >= self.capacity && !memory.contains_key(&key) {
+            let now = Instant::now();
+            memory.retain(|_, lookup| lookup.valid_until() > now);
+
+            if memory.len() >= self.capacity {
+                let evicted = match memory.keys().next() {
+                    Some(evicted) => evicted.clone(),
+                    None => return
+                };
+                memory.remove(&evicted);
+            }
+        }
+
+        memory.insert(key, lookup.clone());
+    }
+
+    async fn get_table(&self, name: &str, query_type: RecordType) -> Result<Option<Lookup>> {
+        let SharedItem { records, expires_at } = match self.table.get(&item_key(name, query_type)).await? {
+            Some(item) => item,
+            None => return Ok(None)
+        };
+
+        let records = Message::from_vec(&records).with_context(|| "Invalid shared cache records")?.take_answers();
+
+        // DynamoDB deletes expired items lazily, sometimes days later
+        let remaining = match expires_at.checked_sub(epoch_seconds()) {
+            Some(remaining) if remaining > 0 => remaining,
+            _ => return Ok(None)
+        };
+
+        let remaining_ttl = u32::try_from(remaining).unwrap_or(u32::MAX);
+        let records: Vec<Record> = records
+            .into_iter()
+            .map(|mut record| {
+                record.set_ttl(record.ttl().min(remaining_ttl));
+                record
+            })
+            .collect();
+
+        let query = Query::query(Name::from_ascii(name).with_context(|| "Invalid shared cache name")?, query_type);
+
+        Ok(Some(Lookup::new_with_deadline(query, Arc::from(records), Instant::now() + Duration::from_secs(remaining))))
+    }
+
+    async fn put_table(&self, name: &str, query_type: RecordType, lookup: &Lookup) -> Result<()> {
+        let remaining = lookup.valid_until().saturating_duration_since(Instant::now()).as_secs();
+        if remaining == 0 {
+            return Ok(());
+        }
+
+        let mut message = Message::new();
+        message.add_answers(lookup.record_iter().cloned());
+        let records = message.to_vec().with_context(|| "Failed to encode shared cache records")?;
+
+        self.table.put(&item_key(name, query_type), SharedItem { records, expires_at: epoch_seconds() + remaining }).await
+    }
+}
+
+fn item_key(name: &str, query_type: RecordType) -> String {
+    format!("{}|{}", name, query_type)
+}
+
+fn epoch_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{
+            AtomicUsize,
+            Ordering
+        },
+        thread::sleep
+    };
+
+    use trust_dns_proto::rr::RData;
+
+    use super::*;
+
+    // A table shared by every cache built from its clones, like instances
+    // sharing the DynamoDB table
+    #[derive(Clone, Default)]
+    struct FakeTable {
+        items: Arc<Mutex<HashMap<String, SharedItem>>>,
+        reads: Arc<AtomicUsize>
+    }
+
+    impl SharedCacheTable for FakeTable {
+        fn get(&self, key: &str) -> BoxFuture<'_, Result<Option<SharedItem>>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let item = self.items.lock().unwrap().get(key).cloned();
+
+            Box::pin(async move { Ok(item) })
+        }
+
+        fn put(&self, key: &str, item: SharedItem) -> BoxFuture<'_, Result<()>> {
+            self.items.lock().unwrap().insert(key.to_string(), item);
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn lookup(name: &str, valid_for: Duration) -> Lookup {
+        let name = Name::from_ascii(name).unwrap();
+        let record = Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+
+        Lookup::new_with_deadline(Query::query(name, RecordType::A), Arc::from(vec![record]), Instant::now() + valid_for)
+    }
+
+    #[test]
+    fn keeps_answers_in_memory_until_they_expire() {
+        let cache = SharedCache::new(Box::<FakeTable>::default(), 10);
+
+        cache.insert_memory("example.com", RecordType::A, &lookup("example.com.", Duration::from_secs(60)));
+        cache.insert_memory("short.example", RecordType::A, &lookup("short.example.", Duration::from_millis(20)));
+
+        assert!(cache.get_memory("example.com", RecordType::A).is_some());
+        assert!(cache.get_memory("example.com", RecordType::AAAA).is_none());
+        assert!(cache.get_memory("short.example", RecordType::A).is_some());
+
+        sleep(Duration::from_millis(30));
+
+        assert!(cache.get_memory("short.example", RecordType::A).is_none());
+        assert_eq!(cache.memory.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn evicts_expired_answers_first() {
+        let cache = SharedCache::new(Box::<FakeTable>::default(), 2);
+
+        cache.insert_memory("a.example", RecordType::A, &lookup("a.example.", Duration::from_secs(60)));
+        cache.insert_memory("b.example", RecordType::A, &lookup("b.example.", Duration::from_millis(20)));
+
+        sleep(Duration::from_millis(30));
+        cache.insert_memory("c.example", RecordType::A, &lookup("c.example.", Duration::from_secs(60)));
+
+        assert!(cache.get_memory("a.example", RecordType::A).is_some());
+        assert!(cache.get_memory("c.example", RecordType::A).is_some());
+
+        cache.insert_memory("d.example", RecordType::A, &lookup("d.example.", Duration::from_secs(60)));
+
+        assert_eq!(cache.memory.lock().unwrap().len(), 2);
+        assert!(cache.get_memory("d.example", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn keeps_nothing_in_memory_without_capacity() {
+        let cache = SharedCache::new(Box::<FakeTable>::default(), 0);
+
+        cache.insert_memory("example.com", RecordType::A, &lookup("example.com.", Duration::from_secs(60)));
+
+        assert!(cache.get_memory("example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn keys_items_by_name_and_type() {
+        assert_eq!(item_key("example.com", RecordType::AAAA), "example.com|AAAA");
+    }
+
+    #[tokio::test]
+    async fn shares_answers_between_instances() {
+        let table = FakeTable::default();
+        let first = SharedCache::new(Box::new(table.clone()), 10);
+        let second = SharedCache::new(Box::new(table.clone()), 10);
+
+        assert!(first.get("example.com", RecordType::A).await.is_none());
+
+        first.insert("example.com", RecordType::A, &lookup("example.com.", Duration::from_secs(60))).await;
+        assert!(table.items.lock().unwrap().contains_key("example.com|A"));
+
+        let shared = second.get("example.com", RecordType::A).await.unwrap();
+        let record = shared.record_iter().next().unwrap();
+
+        assert_eq!(record.data(), Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1))));
+        // Capped at the time left before the shared answer expires
+        assert!(record.ttl() <= 60);
+
+        // Then answered from the second instance's memory
+        let reads = table.reads.load(Ordering::SeqCst);
+        assert!(second.get("example.com", RecordType::A).await.is_some());
+        assert_eq!(table.reads.load(Ordering::SeqCst), reads);
+    }
+
+    #[tokio::test]
+    async fn ignores_expired_and_empty_answers() {
+        let table = FakeTable::default();
+        let cache = SharedCache::new(Box::new(table.clone()), 10);
+
+        let mut message = Message::new();
+        message.add_answers(lookup("stale.example.", Duration::from_secs(60)).record_iter().cloned());
+        table.items.lock().unwrap().insert("stale.example|A".to_string(), SharedItem { records: message.to_vec().unwrap(), expires_at: epoch_seconds() - 1 });
+
+        assert!(cache.get("stale.example", RecordType::A).await.is_none());
+
+        let empty = Lookup::new_with_max_ttl(Query::query(Name::from_ascii("empty.example.").unwrap(), RecordType::A), Arc::from(vec![]));
+        cache.insert("empty.example", RecordType::A, &empty).await;
+
+        assert!(!table.items.lock().unwrap().contains_key("empty.example|A"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::Mutex
+};
+
+use futures_util::future::{
+    BoxFuture,
+    FutureExt,
+    Shared
+};
+
+// Coalesces concurrent calls for the same key, so only the first caller does
+// the work while the others await its result
+pub struct SingleFlight<K, V>
+where V: Clone, {
+    in_flight: Mutex<HashMap<K, Shared<BoxFuture<'static, V>>>>
+}
+
+impl<K, V> SingleFlight<K, V>
+where K: Eq + Hash + Clone, V: Clone + Send + Sync + 'static, {
+    pub fn new() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> V
+    where F: FnOnce() -> Fut, Fut: Future<Output = V> + Send + 'static, {
+        let future = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            in_flight
+                .entry(key.clone())
+                .or_insert_with(|| work().boxed().shared())
+                .clone()
+        };
+
+        let result = future.clone().await;
+
+        // Whichever caller finishes first clears the entry, unless a newer
+        // flight for the key has already replaced it
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(&key).map(|current| current.ptr_eq(&future)).unwrap_or(false) {
+            in_flight.remove(&key);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering
+            },
+            Arc
+        },
+        time::Duration
+    };
+
+    use futures_util::future::join_all;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_concurrent_calls() {
+        let flights = SingleFlight::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let results = join_all((0..10).map(|_| {
+            let calls = calls.clone();
+
+            flights.run("example.com", move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                42
+            })
+        })).await;
+
+        assert_eq!(results, vec![42; 10]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_different_keys_separately() {
+        let flights = SingleFlight::new();
+
+        let (a, b) = tokio::join!(
+            flights.run("a.example", || async { "a" }),
+            flights.run("b.example", || async { "b" })
+        );
+
+        assert_eq!((a, b), ("a", "b"));
+    }
+
+    #[tokio::test]
+    async fn runs_again_once_finished() {
+        let flights = SingleFlight::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..2 {
+            let calls = calls.clone();
+
+            flights.run("example.com", move || async move { calls.fetch_add(1, Ordering::SeqCst) }).await;
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
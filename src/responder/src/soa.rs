@@ -0,0 +1,58 @@
+use trust_dns_proto::rr::{
+    rdata::SOA,
+    Name,
+    RData,
+    Record
+};
+
+// Builds an SOA record for the authority section of negative answers the
+// responder makes up itself, where there is no upstream SOA to pass along.
+// Per RFC 2308 the negative caching TTL is the lesser of the record TTL and
+// the SOA minimum, so both are set to `ttl`. The primary nameserver defaults
+// to the owner name and the responsible mailbox to the root.
+pub fn synthesized_soa(name: &Name, mname: Option<&Name>, rname: Option<&Name>, ttl: u32) -> Record {
+    let soa = SOA::new(
+        mname.unwrap_or(name).clone(),
+        rname.cloned().unwrap_or_else(Name::root),
+        1, 3600, 600, 86400, ttl
+    );
+
+    Record::from_rdata(name.clone(), ttl, RData::SOA(soa))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn soa(record: &Record) -> &SOA {
+        match record.data() {
+            Some(RData::SOA(soa)) => soa,
+            _ => panic!("Expected an SOA record")
+        }
+    }
+
+    #[test]
+    fn uses_ttl_for_negative_caching() {
+        let name = Name::from_str("example.com.").unwrap();
+        let record = synthesized_soa(&name, None, None, 60);
+
+        assert_eq!(record.name(), &name);
+        assert_eq!(record.ttl(), 60);
+        assert_eq!(soa(&record).minimum(), 60);
+        assert_eq!(soa(&record).mname(), &name);
+        assert_eq!(soa(&record).rname(), &Name::root());
+    }
+
+    #[test]
+    fn uses_configured_names() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mname = Name::from_str("ns.resolver.example.").unwrap();
+        let rname = Name::from_str("hostmaster.resolver.example.").unwrap();
+        let record = synthesized_soa(&name, Some(&mname), Some(&rname), 30);
+
+        assert_eq!(soa(&record).mname(), &mname);
+        assert_eq!(soa(&record).rname(), &rname);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex
+};
+
+use trust_dns_proto::rr::{
+    Record,
+    RecordType
+};
+
+// Last known good answers, to fall back on when no upstream is reachable
+// (RFC 8767). Holds at most `capacity` names, evicting an arbitrary one when
+// full.
+pub struct StaleCache {
+    entries: Mutex<HashMap<(String, RecordType), Vec<Record>>>,
+    capacity: usize
+}
+
+impl StaleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: Mutex::new(HashMap::new()), capacity }
+    }
+
+    pub fn insert(&self, name: &str, query_type: RecordType, records: &[Record]) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (name.to_lowercase(), query_type);
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let evicted = match entries.keys().next() {
+                Some(evicted) => evicted.clone(),
+                None => return
+            };
+            entries.remove(&evicted);
+        }
+
+        entries.insert(key, records.to_vec());
+    }
+
+    // Returns the records with their TTLs set to `ttl`, as stale answers must
+    // not be cached for long by clients
+    pub fn get(&self, name: &str, query_type: RecordType, ttl: u32) -> Option<Vec<Record>> {
+        let entries = self.entries.lock().unwrap();
+
+        entries.get(&(name.to_lowercase(), query_type)).map(|records| {
+            records
+                .iter()
+                .cloned()
+                .map(|mut record| {
+                    record.set_ttl(ttl);
+                    record
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr
+    };
+
+    use trust_dns_proto::rr::{
+        Name,
+        RData
+    };
+
+    use super::*;
+
+    fn a(owner: &str) -> Record {
+        Record::from_rdata(Name::from_str(owner).unwrap(), 3600, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+    }
+
+    #[test]
+    fn returns_records_with_the_stale_ttl() {
+        let cache = StaleCache::new(10);
+        cache.insert("Example.com", RecordType::A, &[a("example.com.")]);
+
+        let records = cache.get("example.com", RecordType::A, 30).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ttl(), 30);
+        assert!(cache.get("example.com", RecordType::AAAA, 30).is_none());
+        assert!(cache.get("other.example", RecordType::A, 30).is_none());
+    }
+
+    #[test]
+    fn replaces_records() {
+        let cache = StaleCache::new(10);
+        cache.insert("example.com", RecordType::A, &[a("example.com."), a("example.com.")]);
+        cache.insert("example.com", RecordType::A, &[a("example.com.")]);
+
+        assert_eq!(cache.get("example.com", RecordType::A, 30).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn holds_at_most_capacity_names() {
+        let cache = StaleCache::new(2);
+        cache.insert("a.example", RecordType::A, &[a("a.example.")]);
+        cache.insert("b.example", RecordType::A, &[a("b.example.")]);
+        cache.insert("c.example", RecordType::A, &[a("c.example.")]);
+
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(cache.get("c.example", RecordType::A, 30).is_some());
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{
+        AtomicU64,
+        Ordering
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+// Upper bounds of the latency histogram buckets, in milliseconds
+const LATENCY_BUCKETS_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Blocked,
+    Resolved,
+    Error
+}
+
+// Lightweight per-instance counters, logged as a compact heartbeat line every
+// `log_every_requests` requests or `log_every_seconds` seconds, whichever
+// comes first
+pub struct Stats {
+    total: AtomicU64,
+    blocked: AtomicU64,
+    resolved: AtomicU64,
+    errors: AtomicU64,
+    // Blocked responses to clients over the block rate limit
+    block_rate_exceeded: AtomicU64,
+    // Requests answered within each of LATENCY_BUCKETS_MS, not cumulative
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    latency_count: AtomicU64,
+    latency_sum_us: AtomicU64,
+    last_logged_at: AtomicU64,
+    log_every_requests: u64,
+    log_every_seconds: u64
+}
+
+impl Stats {
+    pub fn new(log_every_requests: u64, log_every_seconds: u64) -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            resolved: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            block_rate_exceeded: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+            latency_count: AtomicU64::new(0),
+            latency_sum_us: AtomicU64::new(0),
+            last_logged_at: AtomicU64::new(now()),
+            log_every_requests,
+            log_every_seconds
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Blocked => &self.blocked,
+            Outcome::Resolved => &self.resolved,
+            Outcome::Error => &self.errors
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        self.maybe_log();
+    }
+
+    pub fn record_block_rate_exceeded(&self) {
+        self.block_rate_exceeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let latency_ms = latency.as_millis() as u64;
+
+        if let Some(bucket) = LATENCY_BUCKETS_MS.iter().position(|bound| latency_ms <= *bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // The counters in the Prometheus text exposition format. Like the
+    // heartbeat, they only cover this instance since it started.
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+
+        // Writing to a String can't fail
+        let _ = writeln!(text, "# HELP dnssls_requests_total DNS requests received.");
+        let _ = writeln!(text, "# TYPE dnssls_requests_total counter");
+        let _ = writeln!(text, "dnssls_requests_total {}", self.total.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP dnssls_responses_total DNS responses sent, by outcome.");
+        let _ = writeln!(text, "# TYPE dnssls_responses_total counter");
+        for (outcome, counter) in [("blocked", &self.blocked), ("resolved", &self.resolved), ("error", &self.errors)] {
+            let _ = writeln!(text, "dnssls_responses_total{{outcome=\"{}\"}} {}", outcome, counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(text, "# HELP dnssls_block_rate_exceeded_total Blocked responses to clients over the block rate limit.");
+        let _ = writeln!(text, "# TYPE dnssls_block_rate_exceeded_total counter");
+        let _ = writeln!(text, "dnssls_block_rate_exceeded_total {}", self.block_rate_exceeded.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP dnssls_request_duration_seconds Time taken to answer DNS requests.");
+        let _ = writeln!(text, "# TYPE dnssls_request_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, counter) in LATENCY_BUCKETS_MS.iter().zip(&self.latency_buckets) {
+            cumulative += counter.load(Ordering::Relaxed);
+            let _ = writeln!(text, "dnssls_request_duration_seconds_bucket{{le=\"{}\"}} {}", *bound as f64 / 1000.0, cumulative);
+        }
+
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let _ = writeln!(text, "dnssls_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(text, "dnssls_request_duration_seconds_sum {}", self.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(text, "dnssls_request_duration_seconds_count {}", count);
+
+        text
+    }
+
+    fn maybe_log(&self) {
+        let total = self.total.load(Ordering::Relaxed);
+        let now = now();
+        let last_logged_at = self.last_logged_at.load(Ordering::Relaxed);
+
+        let due = (self.log_every_requests > 0 && total.is_multiple_of(self.log_every_requests))
+            || (self.log_every_seconds > 0 && now.saturating_sub(last_logged_at) >= self.log_every_seconds);
+
+        // Only one concurrent request gets to log for a given interval
+        if due && self.last_logged_at.compare_exchange(last_logged_at, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            println!(
+                "Stats: total={} blocked={} resolved={} errors={}",
+                total,
+                self.blocked.load(Ordering::Relaxed),
+                self.resolved.load(Ordering::Relaxed),
+                self.errors.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_by_outcome() {
+        let stats = Stats::new(0, 0);
+
+        for outcome in [Outcome::Blocked, Outcome::Resolved, Outcome::Resolved, Outcome::Error] {
+            stats.record_request();
+            stats.record(outcome);
+        }
+
+        assert_eq!(stats.total.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.blocked.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.resolved.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn counts_block_rate_exceeded_responses() {
+        let stats = Stats::new(0, 0);
+
+        stats.record_block_rate_exceeded();
+        stats.record_block_rate_exceeded();
+
+        assert!(stats.prometheus().lines().any(|line| line == "dnssls_block_rate_exceeded_total 2"));
+    }
+
+    #[test]
+    fn logs_once_per_interval() {
+        let stats = Stats::new(2, 0);
+        let last_logged_at = stats.last_logged_at.load(Ordering::Relaxed);
+
+        stats.record_request();
+        stats.record(Outcome::Resolved);
+        assert_eq!(stats.last_logged_at.load(Ordering::Relaxed), last_logged_at);
+
+        // Backdated, so logging moves it on
+        stats.last_logged_at.store(0, Ordering::Relaxed);
+        stats.record_request();
+        stats.record(Outcome::Resolved);
+        assert_ne!(stats.last_logged_at.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn exposes_prometheus_metrics() {
+        let stats = Stats::new(0, 0);
+
+        stats.record_request();
+        stats.record(Outcome::Blocked);
+        stats.record_latency(Duration::from_millis(3));
+        stats.record_latency(Duration::from_millis(40));
+        stats.record_latency(Duration::from_secs(10));
+
+        let text = stats.prometheus();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"dnssls_requests_total 1"));
+        assert!(lines.contains(&"dnssls_responses_total{outcome=\"blocked\"} 1"));
+        assert!(lines.contains(&"dnssls_responses_total{outcome=\"resolved\"} 0"));
+        // Buckets are cumulative, with the slowest request only under +Inf
+        assert!(lines.contains(&"dnssls_request_duration_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_bucket{le=\"0.025\"} 1"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_bucket{le=\"0.05\"} 2"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_bucket{le=\"5\"} 2"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_sum 10.043"));
+        assert!(lines.contains(&"dnssls_request_duration_seconds_count 3"));
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashSet,
+    fs,
+    sync::Arc
+};
+
+use anyhow::{
+    Context,
+    Result
+};
+
+use futures_util::future::BoxFuture;
+
+use trust_dns_proto::{
+    op::{
+        query::Query,
+        response_code::ResponseCode
+    },
+    rr::{
+        Name,
+        RecordType
+    }
+};
+
+use trust_dns_resolver::{
+    error::{
+        ResolveError,
+        ResolveErrorKind
+    },
+    lookup::Lookup
+};
+
+use crate::{
+    overrides::Overrides,
+    upstream::UpstreamResolver
+};
+
+// Canned upstream answers for TEST_MODE, so the handler can be driven end to
+// end without a network. Answers are loaded from a file in the overrides
+// format, plus `<name> SERVFAIL` lines for names whose lookups fail:
+//
+//     example.com A 93.184.216.34
+//     broken.example SERVFAIL
+//
+// Names with records of other types get NODATA, and unknown names NXDOMAIN.
+pub struct StubResolver {
+    answers: Overrides,
+    failing: HashSet<String>
+}
+
+impl StubResolver {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("Failed to read stub answers from '{}'", path))?;
+
+        let mut answers = String::new();
+        let mut failing = HashSet::new();
+
+        for line in contents.lines() {
+            match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [name, record_type] if record_type.eq_ignore_ascii_case("SERVFAIL") => {
+                    failing.insert(normalize(name));
+                },
+                _ => {
+                    answers.push_str(line);
+                    answers.push('\n');
+                }
+            };
+        }
+
+        let answers = Overrides::parse(&answers).with_context(|| format!("Invalid stub answers in '{}'", path))?;
+
+        Ok(Self { answers, failing })
+    }
+
+    fn answer(&self, name: &str, query_type: RecordType) -> Result<Lookup, ResolveError> {
+        let name = Name::from_utf8(name)?;
+        let query = Query::query(name.clone(), query_type);
+
+        if self.failing.contains(&normalize(&name.to_utf8())) {
+            return Err(ResolveErrorKind::Message("Stub lookup failure").into());
+        }
+
+        match self.answers.lookup(&name, query_type) {
+            Some(records) => Ok(Lookup::new_with_max_ttl(query, Arc::from(records))),
+            None => Err(ResolveErrorKind::NoRecordsFound {
+                query: Box::new(query),
+                soa: None,
+                negative_ttl: None,
+                response_code: ResponseCode::NXDomain,
+                trusted: true
+            }.into())
+        }
+    }
+}
+
+impl UpstreamResolver for StubResolver {
+    fn lookup(&self, name: &str, query_type: RecordType) -> BoxFuture<'_, Result<Lookup, ResolveError>> {
+        let answer = self.answer(name, query_type);
+
+        Box::pin(async move { answer })
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn stub() -> StubResolver {
+        let path = env::temp_dir().join(format!("stub-resolver-tests-{}", std::process::id()));
+        fs::write(&path, "example.com A 93.184.216.34\nexample.com A 93.184.216.35\nBroken.Example. servfail\n").unwrap();
+
+        StubResolver::load(path.to_str().unwrap()).unwrap()
+    }
+
+    fn response_code(err: &ResolveError) -> Option<ResponseCode> {
+        match err.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => Some(*response_code),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn answers_with_records() {
+        let lookup = stub().answer("example.com.", RecordType::A).unwrap();
+
+        assert_eq!(lookup.record_iter().count(), 2);
+    }
+
+    #[test]
+    fn answers_nodata_for_other_types() {
+        let lookup = stub().answer("example.com.", RecordType::AAAA).unwrap();
+
+        assert_eq!(lookup.record_iter().count(), 0);
+    }
+
+    #[test]
+    fn answers_nxdomain_for_unknown_names() {
+        let err = stub().answer("unknown.example.", RecordType::A).unwrap_err();
+
+        assert_eq!(response_code(&err), Some(ResponseCode::NXDomain));
+    }
+
+    #[test]
+    fn fails_lookups_for_failing_names() {
+        let err = stub().answer("broken.example.", RecordType::A).unwrap_err();
+
+        assert_eq!(response_code(&err), None);
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        assert!(StubResolver::load("/nonexistent/stub-answers").is_err());
+    }
+}
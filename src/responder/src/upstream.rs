@@ -0,0 +1,353 @@
+use std::{
+    net::{
+        IpAddr,
+        SocketAddr
+    },
+    str::FromStr,
+    time::Duration
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use futures_util::future::BoxFuture;
+
+use trust_dns_proto::{
+    rr::{
+        Name,
+        RecordType
+    },
+    xfer::DnsRequestOptions
+};
+
+use trust_dns_resolver::{
+    config::{
+        NameServerConfig,
+        Protocol,
+        ResolverConfig,
+        ResolverOpts
+    },
+    error::ResolveError,
+    lookup::Lookup,
+    system_conf::read_system_conf,
+    TokioAsyncResolver
+};
+
+// Where queries that aren't answered locally are sent. Production uses
+// trust-dns resolvers, tests a stub with canned answers.
+pub trait UpstreamResolver: Send + Sync {
+    fn lookup(&self, name: &str, query_type: RecordType) -> BoxFuture<'_, Result<Lookup, ResolveError>>;
+}
+
+impl UpstreamResolver for TokioAsyncResolver {
+    fn lookup(&self, name: &str, query_type: RecordType) -> BoxFuture<'_, Result<Lookup, ResolveError>> {
+        let name = name.to_string();
+
+        Box::pin(async move { TokioAsyncResolver::lookup(self, name.as_str(), query_type, DnsRequestOptions::default()).await })
+    }
+}
+
+// Address families used to reach upstream nameservers, configured with
+// UPSTREAM_IP_VERSION
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpVersion {
+    V4,
+    V6,
+    Both
+}
+
+impl IpVersion {
+    fn allows(&self, address: &SocketAddr) -> bool {
+        match self {
+            IpVersion::V4 => address.is_ipv4(),
+            IpVersion::V6 => address.is_ipv6(),
+            IpVersion::Both => true
+        }
+    }
+}
+
+impl FromStr for IpVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "v4" => Ok(IpVersion::V4),
+            "v6" => Ok(IpVersion::V6),
+            "both" => Ok(IpVersion::Both),
+            _ => Err(anyhow!("Unknown upstream IP version '{}', expected 'v4', 'v6', or 'both'", value))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UpstreamSettings {
+    // Defaults to the nameservers from the system config when unset
+    pub name_servers: Option<Vec<NameServerConfig>>,
+    pub ip_version: IpVersion,
+    pub min_ttl: Option<u32>,
+    // Domains to qualify names with fewer than `ndots` dots against. Empty by
+    // default, as DoH clients send fully-qualified names.
+    pub search_domains: Vec<Name>,
+    pub ndots: Option<usize>,
+    pub timeout: Option<Duration>,
+    // How many nameservers are queried in parallel
+    pub concurrent_requests: Option<usize>
+}
+
+// Parses a comma-separated list of upstream nameservers, each of the form
+// `<protocol>://<ip>[:<port>][#<tls name>]`, e.g.:
+//
+//     udp://8.8.8.8,tls://1.1.1.1#cloudflare-dns.com,https://9.9.9.9#dns.quad9.net
+//
+// Encrypted protocols (tls, https) require the TLS name of the server.
+pub fn parse_name_servers(spec: &str) -> Result<Vec<NameServerConfig>> {
+    let mut name_servers = Vec::new();
+
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (protocol, rest) = entry
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Upstream '{}' is missing a protocol, e.g. 'udp://'", entry))?;
+
+        let (address, tls_dns_name) = match rest.split_once('#') {
+            Some((address, tls_dns_name)) => (address, Some(tls_dns_name.to_string())),
+            None => (rest, None)
+        };
+
+        let (protocols, default_port) = match protocol {
+            // Plain DNS falls back to TCP for truncated responses
+            "udp" => (vec![Protocol::Udp, Protocol::Tcp], 53),
+            "tcp" => (vec![Protocol::Tcp], 53),
+            "tls" => (vec![Protocol::Tls], 853),
+            "https" => (vec![Protocol::Https], 443),
+            _ => return Err(anyhow!("Upstream '{}' has unknown protocol '{}', expected udp, tcp, tls, or https", entry, protocol))
+        };
+
+        if matches!(protocols[0], Protocol::Tls | Protocol::Https) && tls_dns_name.is_none() {
+            return Err(anyhow!("Encrypted upstream '{}' requires a TLS name, e.g. '#dns.example'", entry));
+        }
+
+        let socket_addr = match address.parse::<SocketAddr>() {
+            Ok(socket_addr) => socket_addr,
+            Err(_) => SocketAddr::new(
+                address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()
+                    .with_context(|| format!("Upstream '{}' has an invalid address", entry))?,
+                default_port
+            )
+        };
+
+        for protocol in protocols {
+            name_servers.push(NameServerConfig {
+                socket_addr,
+                protocol,
+                tls_dns_name: tls_dns_name.clone(),
+                trust_nx_responses: true,
+                tls_config: None,
+                bind_addr: None
+            });
+        }
+    }
+
+    if name_servers.is_empty() {
+        return Err(anyhow!("No upstream nameservers configured"));
+    }
+
+    Ok(name_servers)
+}
+
+// Parses a comma-separated list of search domains, e.g. `corp.example,lan`
+pub fn parse_search_domains(spec: &str) -> Result<Vec<Name>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty())
+        .map(|domain| {
+            let mut name = Name::from_ascii(domain)
+                .with_context(|| format!("Invalid search domain '{}'", domain))?;
+            name.set_fqdn(true);
+
+            Ok(name)
+        })
+        .collect()
+}
+
+pub fn is_encrypted(name_server: &NameServerConfig) -> bool {
+    matches!(name_server.protocol, Protocol::Tls | Protocol::Https)
+}
+
+// Restricts the nameservers to the allowed address families
+fn select_name_servers(name_servers: &[NameServerConfig], ip_version: IpVersion) -> Result<Vec<NameServerConfig>> {
+    let name_servers: Vec<NameServerConfig> = name_servers
+        .iter()
+        .filter(|name_server| ip_version.allows(&name_server.socket_addr))
+        .cloned()
+        .collect();
+
+    if name_servers.is_empty() {
+        return Err(anyhow!("No upstream nameservers available for IP version {:?}", ip_version));
+    }
+
+    Ok(name_servers)
+}
+
+// Resolvers are meant to be built once per instance and shared: each keeps
+// its connections to the nameservers open across queries, so warm instances
+// reuse established TLS sessions and multiplex DoH queries over a single
+// HTTP/2 connection per nameserver.
+pub fn build_resolver(settings: &UpstreamSettings) -> Result<TokioAsyncResolver> {
+    let (config, mut options): (ResolverConfig, ResolverOpts) = read_system_conf()
+        .with_context(|| "Failed to read system resolver config")?;
+
+    let name_servers = select_name_servers(
+        settings.name_servers.as_deref().unwrap_or(config.name_servers()),
+        settings.ip_version
+    )?;
+
+    let config = ResolverConfig::from_parts(config.domain().cloned(), settings.search_domains.clone(), name_servers);
+
+    options.positive_min_ttl = settings.min_ttl.map(|ttl| Duration::from_secs(ttl.into()));
+
+    if let Some(ndots) = settings.ndots {
+        options.ndots = ndots;
+    }
+
+    if let Some(timeout) = settings.timeout {
+        options.timeout = timeout;
+    }
+
+    if let Some(concurrent_requests) = settings.concurrent_requests {
+        options.num_concurrent_reqs = concurrent_requests;
+    }
+
+    Ok(TokioAsyncResolver::tokio(config, options)?)
+}
+
+// One resolver per nameserver address, with plain DNS's UDP and TCP kept
+// together, so each upstream's failures can be tracked separately
+pub fn build_resolvers_per_name_server(settings: &UpstreamSettings) -> Result<Vec<TokioAsyncResolver>> {
+    let name_servers = match &settings.name_servers {
+        Some(name_servers) => name_servers.clone(),
+        None => read_system_conf().with_context(|| "Failed to read system resolver config")?.0.name_servers().to_vec()
+    };
+
+    let mut groups: Vec<Vec<NameServerConfig>> = Vec::new();
+
+    for name_server in select_name_servers(&name_servers, settings.ip_version)? {
+        match groups.iter_mut().find(|group| group[0].socket_addr == name_server.socket_addr) {
+            Some(group) => group.push(name_server),
+            None => groups.push(vec![name_server])
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|group| build_resolver(&UpstreamSettings { name_servers: Some(group), ..settings.clone() }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name_server(address: &str) -> NameServerConfig {
+        NameServerConfig {
+            socket_addr: address.parse().unwrap(),
+            protocol: Protocol::Udp,
+            tls_dns_name: None,
+            trust_nx_responses: true,
+            tls_config: None,
+            bind_addr: None
+        }
+    }
+
+    #[test]
+    fn parses_ip_versions() {
+        assert_eq!("v4".parse::<IpVersion>().unwrap(), IpVersion::V4);
+        assert_eq!("v6".parse::<IpVersion>().unwrap(), IpVersion::V6);
+        assert_eq!("both".parse::<IpVersion>().unwrap(), IpVersion::Both);
+        assert!("ipv4".parse::<IpVersion>().is_err());
+    }
+
+    #[test]
+    fn selects_name_servers_by_ip_version() {
+        let name_servers = vec![name_server("192.0.2.1:53"), name_server("[2001:db8::1]:53")];
+
+        let addresses = |ip_version| -> Vec<SocketAddr> {
+            select_name_servers(&name_servers, ip_version).unwrap().iter().map(|name_server| name_server.socket_addr).collect()
+        };
+
+        assert_eq!(addresses(IpVersion::V4), vec!["192.0.2.1:53".parse().unwrap()]);
+        assert_eq!(addresses(IpVersion::V6), vec!["[2001:db8::1]:53".parse().unwrap()]);
+        assert_eq!(addresses(IpVersion::Both).len(), 2);
+    }
+
+    #[test]
+    fn requires_a_name_server_of_the_ip_version() {
+        assert!(select_name_servers(&[name_server("192.0.2.1:53")], IpVersion::V6).is_err());
+    }
+
+    #[test]
+    fn parses_name_servers() {
+        let name_servers = parse_name_servers("udp://8.8.8.8, tls://1.1.1.1#cloudflare-dns.com,https://[2620:fe::fe]:8443#dns.quad9.net,tcp://192.0.2.1:5353").unwrap();
+
+        let parsed: Vec<(Protocol, SocketAddr, Option<&str>)> = name_servers
+            .iter()
+            .map(|name_server| (name_server.protocol, name_server.socket_addr, name_server.tls_dns_name.as_deref()))
+            .collect();
+
+        assert_eq!(parsed, vec![
+            // Plain DNS falls back to TCP
+            (Protocol::Udp, "8.8.8.8:53".parse().unwrap(), None),
+            (Protocol::Tcp, "8.8.8.8:53".parse().unwrap(), None),
+            (Protocol::Tls, "1.1.1.1:853".parse().unwrap(), Some("cloudflare-dns.com")),
+            (Protocol::Https, "[2620:fe::fe]:8443".parse().unwrap(), Some("dns.quad9.net")),
+            (Protocol::Tcp, "192.0.2.1:5353".parse().unwrap(), None)
+        ]);
+
+        assert!(!is_encrypted(&name_servers[0]));
+        assert!(is_encrypted(&name_servers[2]));
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_addresses_without_ports() {
+        let name_servers = parse_name_servers("udp://[2001:db8::1]").unwrap();
+
+        assert_eq!(name_servers[0].socket_addr, "[2001:db8::1]:53".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_name_servers() {
+        assert!(parse_name_servers("").is_err());
+        assert!(parse_name_servers("8.8.8.8").is_err());
+        assert!(parse_name_servers("quic://8.8.8.8").is_err());
+        assert!(parse_name_servers("tls://1.1.1.1").is_err());
+        assert!(parse_name_servers("udp://dns.example").is_err());
+    }
+
+    #[test]
+    fn parses_search_domains() {
+        assert_eq!(parse_search_domains(" corp.example, lan ,").unwrap(), vec![
+            Name::from_ascii("corp.example.").unwrap(),
+            Name::from_ascii("lan.").unwrap()
+        ]);
+        assert!(parse_search_domains("").unwrap().is_empty());
+        assert!(parse_search_domains("bad..example").is_err());
+    }
+
+    #[tokio::test]
+    async fn builds_a_resolver_per_name_server_address() {
+        let settings = UpstreamSettings {
+            name_servers: Some(parse_name_servers("udp://8.8.8.8,tls://1.1.1.1#cloudflare-dns.com,udp://[2001:db8::1]").unwrap()),
+            ip_version: IpVersion::V4,
+            min_ttl: None,
+            search_domains: Vec::new(),
+            ndots: None,
+            timeout: None,
+            concurrent_requests: None
+        };
+
+        // UDP and TCP to 8.8.8.8 share a resolver, and the IPv6 one is left out
+        assert_eq!(build_resolvers_per_name_server(&settings).unwrap().len(), 2);
+    }
+}
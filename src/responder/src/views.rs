@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    net::IpAddr,
+    path::Path
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+    Result
+};
+
+use ipnet::IpNet;
+
+use crate::{
+    domain_set::DomainSet,
+    overrides::Overrides
+};
+
+// A policy profile applied to a set of clients. Each profile is a directory
+// of optional files in the same formats as their top-level counterparts:
+//
+//     deny       extra names blocked for these clients
+//     allow      names never blocked for these clients
+//     overrides  static records, taking precedence over the global overrides
+pub struct View {
+    pub name: String,
+    pub deny: DomainSet,
+    pub allow: DomainSet,
+    pub overrides: Overrides
+}
+
+impl View {
+    fn load(profiles_dir: &Path, name: &str) -> Result<Self> {
+        let dir = profiles_dir.join(name);
+
+        if !dir.is_dir() {
+            return Err(anyhow!("View profile directory '{}' does not exist", dir.display()));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            deny: DomainSet::load(dir.join("deny")),
+            allow: DomainSet::load(dir.join("allow")),
+            overrides: Overrides::load(dir.join("overrides"))
+                .with_context(|| format!("Failed to load overrides for view '{}'", name))?
+        })
+    }
+}
+
+// Maps client networks to view profiles, loaded from a file with one mapping
+// per line:
+//
+//     <cidr> <profile>
+//
+// e.g. `192.168.1.64/26 kids`. When networks overlap, the most specific one
+// wins. Clients outside every network get the global policy only.
+#[derive(Default)]
+pub struct Views {
+    networks: Vec<(IpNet, usize)>,
+    profiles: Vec<View>
+}
+
+impl Views {
+    pub fn load<P, Q>(path: P, profiles_dir: Q) -> Result<Self>
+    where P: AsRef<Path>, Q: AsRef<Path>, {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into())
+        };
+
+        let mut views = Self::default();
+        let mut profile_indexes: HashMap<String, usize> = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (network, profile) = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [network, profile] => (network, profile),
+                _ => return Err(anyhow!("Invalid view on line {}: expected '<cidr> <profile>'", index + 1))
+            };
+
+            let network: IpNet = network
+                .parse()
+                .with_context(|| format!("Invalid view on line {}: bad network '{}'", index + 1, network))?;
+
+            let profile_index = match profile_indexes.get(profile) {
+                Some(profile_index) => *profile_index,
+                None => {
+                    views.profiles.push(View::load(profiles_dir.as_ref(), profile)?);
+                    profile_indexes.insert(profile.to_string(), views.profiles.len() - 1);
+                    views.profiles.len() - 1
+                }
+            };
+
+            views.networks.push((network.trunc(), profile_index));
+        }
+
+        // Most specific networks first, so the first match is the best one
+        views.networks.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix_len()));
+
+        Ok(views)
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    pub fn select(&self, client_ip: &str) -> Option<&View> {
+        let client_ip: IpAddr = client_ip.parse().ok()?;
+
+        self.networks
+            .iter()
+            .find(|(network, _)| network.contains(&client_ip))
+            .map(|(_, profile_index)| &self.profiles[*profile_index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    // Writes the views file and profiles to a directory of their own
+    fn load(name: &str, views: &str, profiles: &[(&str, &str, &str)]) -> Result<Views> {
+        let dir = env::temp_dir().join(format!("views-tests-{}-{}", std::process::id(), name));
+        let profiles_dir = dir.join("profiles");
+
+        for (profile, file, contents) in profiles {
+            fs::create_dir_all(profiles_dir.join(profile)).unwrap();
+            fs::write(profiles_dir.join(profile).join(file), contents).unwrap();
+        }
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("views"), views).unwrap();
+
+        Views::load(dir.join("views"), profiles_dir)
+    }
+
+    #[test]
+    fn selects_the_most_specific_network() {
+        let views = load("specific", "# home\n192.168.1.0/24 adults\n192.168.1.64/26 kids\n2001:db8::/32 kids\n", &[
+            ("adults", "allow", "ads.example\n"),
+            ("kids", "deny", "games.example\n")
+        ]).unwrap();
+
+        assert_eq!(views.len(), 2);
+        assert_eq!(views.select("192.168.1.10").map(|view| view.name.as_str()), Some("adults"));
+        assert_eq!(views.select("192.168.1.70").map(|view| view.name.as_str()), Some("kids"));
+        assert_eq!(views.select("2001:db8::1").map(|view| view.name.as_str()), Some("kids"));
+        assert!(views.select("10.0.0.1").is_none());
+        assert!(views.select("not-an-ip").is_none());
+
+        let kids = views.select("192.168.1.70").unwrap();
+
+        assert!(kids.deny.contains("games.example"));
+        assert!(!kids.allow.contains("ads.example"));
+        assert!(views.select("192.168.1.10").unwrap().allow.contains("ads.example"));
+    }
+
+    #[test]
+    fn treats_a_missing_file_as_no_views() {
+        let views = Views::load("/nonexistent/views", "/nonexistent/profiles").unwrap();
+
+        assert_eq!(views.len(), 0);
+        assert!(views.select("192.168.1.10").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_views() {
+        assert!(load("fields", "192.168.1.0/24\n", &[]).is_err());
+        assert!(load("network", "192.168.1.0/33 kids\n", &[("kids", "deny", "")]).is_err());
+        assert!(load("profile", "192.168.1.0/24 missing\n", &[]).is_err());
+        assert!(load("overrides", "192.168.1.0/24 kids\n", &[("kids", "overrides", "games.example MX mail.example.\n")]).is_err());
+    }
+}